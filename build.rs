@@ -9,7 +9,6 @@ fn main() -> eyre::Result<()> {
 
     let protos = glob("protos/**/*.proto")
         .wrap_err("Failed to read glob pattern")?
-        .into_iter()
         .map(|file| {
             // rerun the build if any of the protos files change
             let file = file.unwrap();
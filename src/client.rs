@@ -0,0 +1,307 @@
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
+use eyre::WrapErr;
+use tokio::io::AsyncWriteExt;
+use tonic::transport::Channel;
+
+use crate::{
+    domain::{DigestInfo, ResourceName},
+    infrastructure::grpc_upstream::chunked_write_requests,
+    protos::{
+        build::bazel::remote::execution::v2::{
+            action_cache_client::ActionCacheClient,
+            content_addressable_storage_client::ContentAddressableStorageClient, Digest,
+            FindMissingBlobsRequest, GetActionResultRequest, GetTreeRequest,
+        },
+        google::bytestream::{byte_stream_client::ByteStreamClient, ReadRequest},
+    },
+    tracing::TracingConfig,
+    worker::{self, WorkerConfig},
+};
+
+#[derive(Parser, Debug, Clone)]
+#[clap(rename_all = "kebab-case", next_help_heading = "CLIENT CONFIGS")]
+pub struct ClientConfig {
+    /// Address of the cache to send requests to
+    #[clap(long, env = "BACHE_ENDPOINT", default_value = "http://localhost:50051")]
+    pub endpoint: String,
+
+    /// Instance name to send requests for
+    #[clap(long, env = "BACHE_INSTANCE_NAME", default_value = "")]
+    pub instance_name: String,
+}
+
+impl ClientConfig {
+    async fn connect(&self) -> eyre::Result<Channel> {
+        Channel::from_shared(self.endpoint.clone())
+            .wrap_err("Invalid endpoint")?
+            .connect()
+            .await
+            .wrap_err_with(|| format!("Failed to connect to {}", self.endpoint))
+    }
+
+    fn blob_resource_name(&self, digest: &DigestInfo) -> String {
+        ResourceName::blob(self.instance_name.as_str().into(), digest).to_string()
+    }
+
+    fn upload_resource_name(&self, digest: &DigestInfo) -> String {
+        ResourceName::upload(self.instance_name.as_str().into(), digest).to_string()
+    }
+}
+
+/// Commands talking to a running cache. Digests are written as `{hash}/{size}`
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Read, write or inspect blobs of the CAS
+    #[clap(subcommand)]
+    Blob(BlobCommand),
+
+    /// Inspect the ActionCache
+    #[clap(subcommand)]
+    Ac(ActionCacheCommand),
+
+    /// Print which of the given blobs are missing from the CAS
+    FindMissing {
+        #[clap(flatten)]
+        client_config: ClientConfig,
+
+        #[clap(required = true)]
+        digests: Vec<DigestInfo>,
+    },
+
+    /// Print every directory of the tree rooted at the given `Directory` digest
+    Tree {
+        #[clap(flatten)]
+        client_config: ClientConfig,
+
+        root_digest: DigestInfo,
+    },
+
+    /// Run the actions a server with remote execution enabled hands out, until interrupted
+    Worker {
+        #[clap(flatten)]
+        worker_config: WorkerConfig,
+
+        #[clap(flatten)]
+        tracing_config: TracingConfig,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum BlobCommand {
+    /// Download a blob, to stdout unless an output file is given
+    Get {
+        #[clap(flatten)]
+        client_config: ClientConfig,
+
+        digest: DigestInfo,
+
+        /// File to write the blob to
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Upload a file, printing its digest
+    Put {
+        #[clap(flatten)]
+        client_config: ClientConfig,
+
+        path: PathBuf,
+    },
+
+    /// Print whether a blob is present in the CAS
+    Stat {
+        #[clap(flatten)]
+        client_config: ClientConfig,
+
+        digest: DigestInfo,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ActionCacheCommand {
+    /// Print the `ActionResult` stored for an action digest
+    Get {
+        #[clap(flatten)]
+        client_config: ClientConfig,
+
+        action_digest: DigestInfo,
+    },
+}
+
+async fn blob_get(
+    client_config: &ClientConfig,
+    digest: DigestInfo,
+    output: Option<PathBuf>,
+) -> eyre::Result<()> {
+    let mut client = ByteStreamClient::new(client_config.connect().await?);
+
+    let mut responses = client
+        .read(ReadRequest {
+            resource_name: client_config.blob_resource_name(&digest),
+            read_offset: 0,
+            read_limit: 0,
+        })
+        .await?
+        .into_inner();
+
+    let mut writer: Box<dyn tokio::io::AsyncWrite + Unpin> = match output {
+        Some(path) => Box::new(
+            tokio::fs::File::create(&path)
+                .await
+                .wrap_err_with(|| format!("Failed to create {}", path.display()))?,
+        ),
+        None => Box::new(tokio::io::stdout()),
+    };
+
+    while let Some(response) = responses.message().await? {
+        writer.write_all(&response.data).await?;
+    }
+    writer.flush().await?;
+
+    Ok(())
+}
+
+async fn blob_put(client_config: &ClientConfig, path: PathBuf) -> eyre::Result<()> {
+    let data = Bytes::from(
+        tokio::fs::read(&path)
+            .await
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?,
+    );
+    let digest = DigestInfo::compute(&data);
+    let resource_name = client_config.upload_resource_name(&digest);
+
+    let requests = chunked_write_requests(resource_name, &data);
+
+    let mut client = ByteStreamClient::new(client_config.connect().await?);
+    client.write(tokio_stream::iter(requests)).await?;
+
+    println!("{digest}");
+
+    Ok(())
+}
+
+async fn find_missing(
+    client_config: &ClientConfig,
+    digests: Vec<DigestInfo>,
+) -> eyre::Result<Vec<DigestInfo>> {
+    let mut client = ContentAddressableStorageClient::new(client_config.connect().await?);
+
+    let missing_blob_digests = client
+        .find_missing_blobs(FindMissingBlobsRequest {
+            instance_name: client_config.instance_name.clone(),
+            blob_digests: digests.into_iter().map(Digest::from).collect(),
+        })
+        .await?
+        .into_inner()
+        .missing_blob_digests;
+
+    missing_blob_digests
+        .into_iter()
+        .map(|digest| DigestInfo::try_from(digest).wrap_err("Server sent an invalid digest"))
+        .collect()
+}
+
+async fn blob_stat(client_config: &ClientConfig, digest: DigestInfo) -> eyre::Result<()> {
+    let missing = find_missing(client_config, vec![digest.clone()]).await?;
+
+    if missing.is_empty() {
+        println!("{digest}: present");
+    } else {
+        println!("{digest}: missing");
+    }
+
+    Ok(())
+}
+
+async fn action_cache_get(
+    client_config: &ClientConfig,
+    action_digest: DigestInfo,
+) -> eyre::Result<()> {
+    let mut client = ActionCacheClient::new(client_config.connect().await?);
+
+    let action_result = client
+        .get_action_result(GetActionResultRequest {
+            instance_name: client_config.instance_name.clone(),
+            action_digest: Some(action_digest.into()),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    println!("{action_result:#?}");
+
+    Ok(())
+}
+
+async fn tree(client_config: &ClientConfig, root_digest: DigestInfo) -> eyre::Result<()> {
+    let mut client = ContentAddressableStorageClient::new(client_config.connect().await?);
+
+    let mut responses = client
+        .get_tree(GetTreeRequest {
+            instance_name: client_config.instance_name.clone(),
+            root_digest: Some(root_digest.into()),
+            page_size: 0,
+            page_token: String::new(),
+        })
+        .await?
+        .into_inner();
+
+    while let Some(response) = responses.message().await? {
+        for directory in response.directories {
+            println!("{directory:#?}");
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run(command: Command) -> eyre::Result<()> {
+    // the worker is long running, and logs the way the server does
+    let command = match command {
+        Command::Worker {
+            worker_config,
+            tracing_config,
+        } => return worker::run(worker_config, &tracing_config).await,
+        command => command,
+    };
+
+    color_eyre::install()?;
+
+    match command {
+        Command::Blob(BlobCommand::Get {
+            client_config,
+            digest,
+            output,
+        }) => blob_get(&client_config, digest, output).await,
+        Command::Blob(BlobCommand::Put {
+            client_config,
+            path,
+        }) => blob_put(&client_config, path).await,
+        Command::Blob(BlobCommand::Stat {
+            client_config,
+            digest,
+        }) => blob_stat(&client_config, digest).await,
+        Command::Ac(ActionCacheCommand::Get {
+            client_config,
+            action_digest,
+        }) => action_cache_get(&client_config, action_digest).await,
+        Command::FindMissing {
+            client_config,
+            digests,
+        } => {
+            for digest in find_missing(&client_config, digests).await? {
+                println!("{digest}");
+            }
+
+            Ok(())
+        }
+        Command::Tree {
+            client_config,
+            root_digest,
+        } => tree(&client_config, root_digest).await,
+        Command::Worker { .. } => unreachable!("the worker is run above"),
+    }
+}
@@ -1,6 +1,15 @@
+use std::path::PathBuf;
+
 use clap::Parser;
 
-use crate::tracing::TracingConfig;
+use crate::{
+    client::Command,
+    domain::InstanceRoute,
+    infrastructure::{quota::InstanceQuotaSpec, replicated::Acknowledgement},
+    rate_limit::RateLimitConfig,
+    services::capabilities::SymlinkPolicy,
+    tracing::TracingConfig,
+};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(rename_all = "kebab-case", next_help_heading = "SERVER CONFIGS")]
@@ -20,14 +29,322 @@ pub struct ServerConfig {
     /// Disable health checks. Used only for testing
     #[clap(long, env = "BACHE_DISABLE_HEALTH_CHECKS")]
     pub disable_health_checks: bool,
+
+    /// Most batches of 1000 digests of a single `FindMissingBlobs` request looked up at once
+    #[clap(
+        long,
+        env = "BACHE_FIND_MISSING_BLOBS_CONCURRENCY",
+        default_value_t = 16
+    )]
+    pub find_missing_blobs_concurrency: usize,
+
+    /// Seconds the requests in flight, such as ByteStream uploads, are given to finish once the
+    /// server is asked to shut down. New requests are turned away in the meantime
+    #[clap(
+        long,
+        env = "BACHE_SHUTDOWN_GRACE_PERIOD_SECONDS",
+        default_value_t = 30
+    )]
+    pub shutdown_grace_period_seconds: u64,
+
+    /// Credential required to use the admin API, sent as a bearer token. The admin API is
+    /// disabled when unset
+    #[clap(long, env = "BACHE_ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Credential the workers running actions authenticate with, sent as a bearer token. Remote
+    /// execution is disabled when unset
+    #[clap(long, env = "BACHE_WORKER_TOKEN")]
+    pub worker_token: Option<String>,
+
+    /// Seconds a worker keeps an action without extending its lease on it, past which the action
+    /// is queued again
+    #[clap(long, env = "BACHE_WORKER_LEASE_SECONDS", default_value_t = 30)]
+    pub worker_lease_seconds: u64,
+
+    /// Times an action is handed out to workers before giving up on it, when they keep going
+    /// away while running it
+    #[clap(long, env = "BACHE_EXECUTION_MAX_ATTEMPTS", default_value_t = 3)]
+    pub execution_max_attempts: u32,
+
+    /// Seconds an action waits for a worker to take it before failing with UNAVAILABLE, e.g. when
+    /// no worker has its platform
+    #[clap(
+        long,
+        env = "BACHE_EXECUTION_QUEUE_TIMEOUT_SECONDS",
+        default_value_t = 600
+    )]
+    pub execution_queue_timeout_seconds: u64,
+
+    /// Most bytes of blobs a single `BatchUpdateBlobs` or `BatchReadBlobs` request may write or
+    /// read, larger ones are turned away. No limit when 0
+    #[clap(
+        long,
+        env = "BACHE_MAX_BATCH_TOTAL_SIZE_BYTES",
+        default_value_t = 4 * 1024 * 1024
+    )]
+    pub max_batch_total_size_bytes: u64,
+
+    /// Most bytes of a blob `SpliceBlob` may assemble in memory, larger ones, or ones larger than
+    /// the quota of their instance, are turned away with INVALID_ARGUMENT
+    #[clap(
+        long,
+        env = "BACHE_MAX_SPLICE_BLOB_SIZE_BYTES",
+        default_value_t = 1024 * 1024 * 1024
+    )]
+    pub max_splice_blob_size_bytes: u64,
+
+    /// Seconds a ByteStream upload may go without being written to before the bytes written so
+    /// far are dropped, clients then having to start it over
+    #[clap(long, env = "BACHE_UPLOAD_IDLE_TIMEOUT_SECONDS", default_value_t = 600)]
+    pub upload_idle_timeout_seconds: u64,
+
+    /// Most bytes the partial ByteStream uploads may hold together in memory, the uploads going
+    /// past it being spilled to files of `BACHE_UPLOAD_SPILL_DIR`
+    #[clap(
+        long,
+        env = "BACHE_UPLOAD_MAX_BUFFERED_BYTES",
+        default_value_t = 1024 * 1024 * 1024
+    )]
+    pub upload_max_buffered_bytes: usize,
+
+    /// Directory the partial ByteStream uploads that no longer fit in memory are spilled to,
+    /// created when missing. Defaults to a directory of the system's temporary one
+    #[clap(long, env = "BACHE_UPLOAD_SPILL_DIR")]
+    pub upload_spill_dir: Option<PathBuf>,
+
+    /// Whether action results may hold symlinks to absolute paths (`allowed`) or not
+    /// (`disallowed`), as advertised to the clients
+    #[clap(
+        long,
+        arg_enum,
+        env = "BACHE_SYMLINK_ABSOLUTE_PATH_STRATEGY",
+        default_value = "disallowed"
+    )]
+    pub symlink_absolute_path_strategy: SymlinkPolicy,
+
+    /// Comma separated list of the credentials allowed to update the ActionCache, sent as bearer
+    /// tokens. Other clients may only read from it. Every client may update it when empty
+    #[clap(
+        long,
+        env = "BACHE_ACTION_CACHE_UPDATE_TOKENS",
+        use_value_delimiter = true
+    )]
+    pub action_cache_update_tokens: Vec<String>,
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(rename_all = "kebab-case", next_help_heading = "STORE CONFIGS")]
+pub struct StoreConfig {
+    /// Comma separated list of instance names to serve. Bazel uses the empty instance name unless
+    /// told otherwise
+    #[clap(
+        long,
+        env = "BACHE_INSTANCE_NAMES",
+        use_value_delimiter = true,
+        default_value = ""
+    )]
+    pub instance_names: Vec<String>,
+
+    /// Comma separated list of routes for the instance names not served, as
+    /// `pattern=instance_name` where `*` in the pattern matches anything, e.g. `ci/*=ci`. Routes
+    /// are tried in order, the first match wins
+    #[clap(long, env = "BACHE_INSTANCE_ROUTES", use_value_delimiter = true)]
+    pub instance_routes: Vec<InstanceRoute>,
+
+    /// Instance serving the requests for instance names neither served nor routed. Without it,
+    /// those requests are answered with NOT_FOUND
+    #[clap(long, env = "BACHE_DEFAULT_INSTANCE_NAME")]
+    pub default_instance_name: Option<String>,
+
+    /// Comma separated list of per instance quotas, as `instance_name=max_bytes:max_entries`
+    /// where either limit may be left empty. The CAS and the ActionCache of the instance are each
+    /// held to the quota, evicting their own least recently used entries once over it
+    #[clap(long, env = "BACHE_INSTANCE_QUOTAS", use_value_delimiter = true)]
+    pub instance_quotas: Vec<InstanceQuotaSpec>,
+
+    /// Maximum size in bytes of the in-memory CAS of each instance
+    #[clap(long, env = "BACHE_CAS_MEMORY_MAX_BYTES", default_value_t = 1024 * 1024 * 1024)]
+    pub cas_memory_max_bytes: u64,
+
+    /// Maximum size in bytes of the in-memory ActionCache of each instance
+    #[clap(
+        long,
+        env = "BACHE_ACTION_CACHE_MEMORY_MAX_BYTES",
+        default_value_t = 64 * 1024 * 1024
+    )]
+    pub action_cache_memory_max_bytes: u64,
+
+    /// Percentage of each in-memory store kept apart for entries written with a high cache
+    /// priority, which are only evicted to make room for each other. Cache priorities are
+    /// ignored when 0
+    #[clap(long, env = "BACHE_MEMORY_RETAINED_PERCENT", default_value_t = 20)]
+    pub memory_retained_percent: u64,
+
+    /// Average size in bytes of the content-defined chunks the blobs of each instance's in-memory
+    /// CAS are split in, so that blobs differing only slightly share most of their memory. Chunks
+    /// are kept in a memory store of their own, taking 90% of `cas_memory_max_bytes`, while the
+    /// CAS memory store only keeps the list of chunks of every blob in the other 10%. Blobs are
+    /// kept whole when unset. Also
+    /// the average size of the chunks `SplitBlob` splits blobs in, 512KiB when unset
+    #[clap(long, env = "BACHE_DEDUP_AVERAGE_CHUNK_BYTES")]
+    pub dedup_average_chunk_bytes: Option<u32>,
+
+    /// Database file of the embedded key-value store keeping the small entries of every
+    /// instance, created when missing. Every entry is kept in memory when unset
+    #[clap(long, env = "BACHE_EMBEDDED_STORE_PATH")]
+    pub embedded_store_path: Option<PathBuf>,
+
+    /// Largest entry in bytes kept in the embedded key-value store, larger ones are kept in
+    /// memory
+    #[clap(
+        long,
+        env = "BACHE_EMBEDDED_STORE_MAX_ENTRY_BYTES",
+        default_value_t = 4096
+    )]
+    pub embedded_store_max_entry_bytes: usize,
+
+    /// Most bytes of entries the CAS and the ActionCache of every instance each keep in the
+    /// embedded key-value store, the entries written the longest ago being evicted once over it
+    #[clap(
+        long,
+        env = "BACHE_EMBEDDED_STORE_MAX_BYTES",
+        default_value_t = 1024 * 1024 * 1024
+    )]
+    pub embedded_store_max_bytes: u64,
+
+    /// Redis server the entries of every instance are kept in, behind the in-memory stores, e.g.
+    /// `redis://redis:6379/0`. Several nodes may share it. Entries are only kept in memory when
+    /// unset
+    #[clap(long, env = "BACHE_REDIS_URL")]
+    pub redis_url: Option<String>,
+
+    /// Seconds entries are kept in Redis after being written or last found. Entries are kept
+    /// until Redis evicts them when unset
+    #[clap(long, env = "BACHE_REDIS_TTL_SECONDS")]
+    pub redis_ttl_seconds: Option<u64>,
+
+    /// Largest value in bytes written to a single Redis key, larger entries are split over
+    /// several keys
+    #[clap(
+        long,
+        env = "BACHE_REDIS_CHUNK_BYTES",
+        default_value_t = 4 * 1024 * 1024
+    )]
+    pub redis_chunk_bytes: usize,
+
+    /// Number of blobs the bloom filter of each instance's CAS is sized for. Past that, more
+    /// lookups get through to the store
+    #[clap(
+        long,
+        env = "BACHE_BLOOM_FILTER_EXPECTED_ENTRIES",
+        default_value_t = 1_000_000
+    )]
+    pub bloom_filter_expected_entries: u64,
+
+    /// Share of lookups for missing blobs the bloom filter lets through to the store
+    #[clap(
+        long,
+        env = "BACHE_BLOOM_FILTER_FALSE_POSITIVE_RATE",
+        default_value_t = 0.01
+    )]
+    pub bloom_filter_false_positive_rate: f64,
+
+    /// How long in milliseconds a blob found to be missing is reported as missing without asking
+    /// the store again
+    #[clap(long, env = "BACHE_NEGATIVE_CACHE_TTL_MS", default_value_t = 2000)]
+    pub negative_cache_ttl_ms: u64,
+
+    /// Directory the in-memory stores are snapshotted to on shutdown, and restored from in the
+    /// background on startup. Snapshots are disabled when unset
+    #[clap(long, env = "BACHE_MEMORY_SNAPSHOT_DIR")]
+    pub memory_snapshot_dir: Option<PathBuf>,
+
+    /// Seconds between snapshots of the in-memory stores, on top of the one taken on shutdown
+    #[clap(long, env = "BACHE_MEMORY_SNAPSHOT_INTERVAL_SECONDS")]
+    pub memory_snapshot_interval_seconds: Option<u64>,
+
+    /// Comma separated list of the other bache nodes to replicate entries to, e.g.
+    /// `http://bache-2:50051`. Replication is disabled when empty
+    #[clap(long, env = "BACHE_REPLICATION_PEERS", use_value_delimiter = true)]
+    pub replication_peers: Vec<String>,
+
+    /// Number of nodes holding a copy of every entry, this one included
+    #[clap(long, env = "BACHE_REPLICATION_FACTOR", default_value_t = 2)]
+    pub replication_factor: usize,
+
+    /// Whether writes wait for every replica to hold the entry (`sync`) or only for the local copy
+    /// (`async`)
+    #[clap(
+        long,
+        arg_enum,
+        env = "BACHE_REPLICATION_ACKNOWLEDGEMENT",
+        default_value = "async"
+    )]
+    pub replication_acknowledgement: Acknowledgement,
+
+    /// Credential the nodes of the cluster authenticate each other with, sent as a bearer token
+    /// on every call to a replication peer or shard. It lets them update the ActionCache, and
+    /// their calls are not rate limited. Calls between nodes are anonymous when unset
+    #[clap(long, env = "BACHE_PEER_TOKEN")]
+    pub peer_token: Option<String>,
+
+    /// Deadline in seconds of every call to a replication peer
+    #[clap(long, env = "BACHE_REPLICATION_TIMEOUT_SECONDS", default_value_t = 30)]
+    pub replication_timeout_seconds: u64,
+
+    /// Comma separated list of every node of the cluster to shard entries across, this one
+    /// included. Every node must be given the same list. Sharding is disabled when empty
+    #[clap(long, env = "BACHE_SHARD_NODES", use_value_delimiter = true)]
+    pub shard_nodes: Vec<String>,
+
+    /// Which of the `--shard-nodes` is this node, written exactly as it appears in that list
+    #[clap(long, env = "BACHE_SHARD_SELF")]
+    pub shard_self: Option<String>,
+
+    /// Deadline in seconds of every call to another shard
+    #[clap(long, env = "BACHE_SHARD_TIMEOUT_SECONDS", default_value_t = 60)]
+    pub shard_timeout_seconds: u64,
+
+    /// REAPI cache to put this one in front of, e.g. `https://cache.example.com`. Misses are read
+    /// from it and writes go through to it. Disabled when unset
+    #[clap(long, env = "BACHE_UPSTREAM_ENDPOINT")]
+    pub upstream_endpoint: Option<String>,
+
+    /// Instance name to use on the upstream cache. Every instance keeps its own name when unset
+    #[clap(long, env = "BACHE_UPSTREAM_INSTANCE_NAME")]
+    pub upstream_instance_name: Option<String>,
+
+    /// Credential sent to the upstream cache as a bearer token, e.g. one allowed to update its
+    /// ActionCache. Calls to it are anonymous when unset
+    #[clap(long, env = "BACHE_UPSTREAM_TOKEN")]
+    pub upstream_token: Option<String>,
+
+    /// Number of connections opened to the upstream cache
+    #[clap(long, env = "BACHE_UPSTREAM_CONNECTIONS", default_value_t = 4)]
+    pub upstream_connections: usize,
+
+    /// Deadline in seconds of every call to the upstream cache
+    #[clap(long, env = "BACHE_UPSTREAM_TIMEOUT_SECONDS", default_value_t = 60)]
+    pub upstream_timeout_seconds: u64,
 }
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about)]
 pub struct Args {
+    /// Runs the server when no command is given
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     #[clap(flatten)]
     pub server_config: ServerConfig,
 
+    #[clap(flatten)]
+    pub store_config: StoreConfig,
+
+    #[clap(flatten)]
+    pub rate_limit_config: RateLimitConfig,
+
     #[clap(flatten)]
     pub tracing_config: TracingConfig,
 }
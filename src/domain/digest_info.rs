@@ -1,6 +1,7 @@
-use std::{fmt, hash::Hash};
+use std::{fmt, hash::Hash, str::FromStr};
 
 use hex::{FromHex, ToHex};
+use sha2::{Digest as _, Sha256};
 
 use crate::{errors::Error, protos::build::bazel::remote::execution::v2::Digest};
 
@@ -45,11 +46,41 @@ impl DigestInfo {
         })
     }
 
+    /// Computes the SHA-256 digest of `data`
+    pub fn compute(data: &[u8]) -> Self {
+        Self {
+            size_bytes: data.len() as i64,
+            packed_hash: Sha256::digest(data).into(),
+        }
+    }
+
     pub fn hash(&self) -> DigestHash {
         DigestHash(self.packed_hash.encode_hex::<String>())
     }
 }
 
+impl fmt::Display for DigestInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.hash(), self.size_bytes)
+    }
+}
+
+/// Parses digests in the `{hash}/{size}` format used by Bazel's logs and command line flags
+impl FromStr for DigestInfo {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (hash, size) = value.split_once('/').ok_or_else(|| {
+            Error::InvalidDigestParts(format!("`{value}` is not of the form `{{hash}}/{{size}}`"))
+        })?;
+        let size: i64 = size
+            .parse()
+            .map_err(|_| Error::InvalidDigestParts(format!("`{size}` is not a valid size")))?;
+
+        Self::try_new(hash, size)
+    }
+}
+
 impl TryFrom<Digest> for DigestInfo {
     type Error = Error;
     fn try_from(digest: Digest) -> Result<Self, Self::Error> {
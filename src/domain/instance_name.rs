@@ -1,4 +1,17 @@
-use std::fmt;
+use std::{fmt, str::FromStr};
+
+use crate::errors::Error;
+
+/// Path segments the REAPI reserves for resource names, which instance names may not contain
+const RESERVED_SEGMENTS: [&str; 7] = [
+    "blobs",
+    "uploads",
+    "actions",
+    "actionResults",
+    "operations",
+    "capabilities",
+    "compressed-blobs",
+];
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct InstanceName(String);
@@ -13,6 +26,29 @@ impl InstanceName {
     pub fn new(instance_name: String) -> Self {
         Self(instance_name)
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Checks that the instance name could be part of a resource name, i.e. that it has no empty
+    /// or reserved path segments
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        let is_valid = self
+            .0
+            .split('/')
+            .all(|segment| !segment.is_empty() && !RESERVED_SEGMENTS.contains(&segment));
+
+        if is_valid {
+            Ok(())
+        } else {
+            Err(Error::InvalidInstanceName(self.clone()))
+        }
+    }
 }
 
 impl From<String> for InstanceName {
@@ -26,3 +62,99 @@ impl From<&str> for InstanceName {
         Self(value.to_string())
     }
 }
+
+/// Pattern matched against the instance names of requests, where `*` matches any run of
+/// characters, `/` included, and `?` any single character. A pattern without wildcards only
+/// matches that exact instance name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstancePattern(String);
+
+impl InstancePattern {
+    pub fn matches(&self, instance_name: &InstanceName) -> bool {
+        let pattern: Vec<char> = self.0.chars().collect();
+        let name: Vec<char> = instance_name.as_str().chars().collect();
+
+        // `matched[j]` tells whether the pattern read so far matches the first `j` characters
+        let mut matched = vec![false; name.len() + 1];
+        matched[0] = true;
+
+        for wildcard in pattern {
+            let previous = matched.clone();
+
+            matched[0] = previous[0] && wildcard == '*';
+            for j in 1..=name.len() {
+                matched[j] = match wildcard {
+                    '*' => previous[j] || matched[j - 1],
+                    '?' => previous[j - 1],
+                    character => previous[j - 1] && name[j - 1] == character,
+                };
+            }
+        }
+
+        matched[name.len()]
+    }
+}
+
+impl fmt::Display for InstancePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Route sending the requests for every instance name matching `pattern` to the stores of the
+/// `instance_name` served, as given on the command line, `{pattern}={instance_name}`, e.g.
+/// `ci/*=ci` or `bazel-5=main` to alias an instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceRoute {
+    pub pattern: InstancePattern,
+    pub instance_name: InstanceName,
+}
+
+impl FromStr for InstanceRoute {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (pattern, instance_name) = value
+            .rsplit_once('=')
+            .ok_or_else(|| format!("`{value}` is not of the form `pattern=instance_name`"))?;
+
+        Ok(Self {
+            pattern: InstancePattern(pattern.to_string()),
+            instance_name: instance_name.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_match_instance_names() {
+        let cases = [
+            ("main", "main", true),
+            ("main", "main2", false),
+            ("main", "", false),
+            ("", "", true),
+            ("ci/*", "ci/linux", true),
+            ("ci/*", "ci/linux/x86", true),
+            ("ci/*", "ci/", true),
+            ("ci/*", "ci", false),
+            ("*", "", true),
+            ("*/arm", "ci/linux/arm", true),
+            ("bazel-?", "bazel-5", true),
+            ("bazel-?", "bazel-", false),
+            ("bazel-?", "bazel-10", false),
+        ];
+
+        for (pattern, instance_name, expected) in cases {
+            let route: InstanceRoute = format!("{pattern}=main").parse().unwrap();
+
+            assert_eq!(
+                route.pattern.matches(&instance_name.into()),
+                expected,
+                "`{pattern}` against `{instance_name}`"
+            );
+        }
+    }
+}
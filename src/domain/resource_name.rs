@@ -1,85 +1,289 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
 use uuid::Uuid;
 
-use super::InstanceName;
+use super::{DigestInfo, InstanceName};
 use crate::errors::Error;
 
+/// Compressor the REAPI uses to name uncompressed blobs
+const IDENTITY_COMPRESSOR: &str = "identity";
+
+/// ByteStream resource name of a blob.
+///
+/// Bazel will send resource names in the patterns:
+/// * `{instance_name}/blobs/{hash}/{size}{/optional_metadata}`
+/// * `{instance_name}/uploads/{uuid}/blobs/{hash}/{size}{/optional_metadata}`
+/// * `{instance_name}/compressed-blobs/{compressor}/{hash}/{size}{/optional_metadata}`
+/// * `{instance_name}/uploads/{uuid}/compressed-blobs/{compressor}/{hash}/{size}{/
+///   optional_metadata}`
+///
+/// where the instance name may span several path segments, or be empty along with the `/`
+/// following it
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ResourceName {
     pub instance_name: InstanceName,
     pub uuid: Option<Uuid>,
+    /// Compressor of a `compressed-blobs` resource, the blob being named by the digest of its
+    /// uncompressed bytes
+    pub compressor: Option<String>,
     pub hash: String,
     pub size: usize,
+    /// Path segments after the size, which servers are free to ignore
+    pub metadata: Option<String>,
 }
 
-impl TryFrom<&str> for ResourceName {
-    type Error = Error;
+impl ResourceName {
+    /// Name to read the blob of `digest` from
+    pub fn blob(instance_name: InstanceName, digest: &DigestInfo) -> Self {
+        Self {
+            instance_name,
+            uuid: None,
+            compressor: None,
+            hash: digest.hash().to_string(),
+            size: digest.size_bytes as usize,
+            metadata: None,
+        }
+    }
 
-    // Bazel will send resource names in the patterns:
-    // * `{instance_name}/uploads/{uuid}/blobs/{hash}/{size}`
-    // * `{instance_name}/blobs/{hash}/{size}`
-    fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let mut parts = value.splitn(6, '/');
+    /// Name to write the blob of `digest` to, under a new upload id
+    pub fn upload(instance_name: InstanceName, digest: &DigestInfo) -> Self {
+        Self {
+            uuid: Some(Uuid::new_v4()),
+            ..Self::blob(instance_name, digest)
+        }
+    }
 
-        let instance_name = parts
-            .next()
-            .ok_or_else(|| Error::InvalidResourceName(value.to_string()))?
-            .into();
+    /// Whether the bytes read or written are compressed, rather than the blob itself
+    pub fn is_compressed(&self) -> bool {
+        self.compressor
+            .as_deref()
+            .is_some_and(|compressor| compressor != IDENTITY_COMPRESSOR)
+    }
+}
 
-        let blobs_or_uploads = parts
-            .next()
-            .ok_or_else(|| Error::InvalidResourceName(value.to_string()))?;
+impl FromStr for ResourceName {
+    type Err = Error;
 
-        let mut uuid: Option<Uuid> = None;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidResourceName(value.to_string());
+        let segments: Vec<&str> = value.split('/').collect();
 
-        if blobs_or_uploads == "uploads" {
-            let raw_uuid = parts
-                .next()
-                .ok_or_else(|| Error::InvalidResourceName(value.to_string()))?;
+        // instance names may not contain these segments, so the first one ends the instance name
+        let marker = segments
+            .iter()
+            .position(|segment| matches!(*segment, "blobs" | "uploads" | "compressed-blobs"))
+            .ok_or_else(invalid)?;
 
-            uuid = Some(
-                Uuid::from_str(raw_uuid)
-                    .map_err(|_| Error::InvalidResourceName(value.to_string()))?,
-            );
+        let instance_name = InstanceName::from(segments[..marker].join("/"));
+        // a leading `/` would make for an empty, rather than absent, instance name
+        if marker > 0 && (instance_name.as_str().is_empty() || instance_name.validate().is_err()) {
+            return Err(invalid());
+        }
 
-            // this next section should be `blobs`
-            let blob_part = parts
-                .next()
-                .ok_or_else(|| Error::InvalidResourceName(value.to_string()))?;
-            if blob_part != "blobs" {
-                return Err(Error::InvalidResourceName(value.to_string()));
-            }
-        } else if blobs_or_uploads != "blobs" {
-            return Err(Error::InvalidResourceName(value.to_string()));
+        let mut rest = segments[marker..].iter().copied();
+
+        let mut uuid = None;
+        let mut kind = rest.next().ok_or_else(invalid)?;
+        if kind == "uploads" {
+            let raw_uuid = rest.next().ok_or_else(invalid)?;
+            uuid = Some(Uuid::from_str(raw_uuid).map_err(|_| invalid())?);
+
+            kind = rest.next().ok_or_else(invalid)?;
         }
 
-        let hash = parts
+        let compressor = match kind {
+            "blobs" => None,
+            "compressed-blobs" => Some(
+                rest.next()
+                    .filter(|compressor| !compressor.is_empty())
+                    .ok_or_else(invalid)?
+                    .to_string(),
+            ),
+            _ => return Err(invalid()),
+        };
+
+        let hash = rest
             .next()
-            .ok_or_else(|| Error::InvalidResourceName(value.to_string()))?
+            .filter(|hash| !hash.is_empty())
+            .ok_or_else(invalid)?
             .to_string();
 
-        let raw_size = parts
+        let size = rest
             .next()
-            .ok_or_else(|| Error::InvalidResourceName(value.to_string()))?;
-
-        let size: usize = raw_size
+            .ok_or_else(invalid)?
             .parse()
-            .map_err(|_| Error::InvalidResourceName(value.to_string()))?;
+            .map_err(|_| invalid())?;
+
+        let metadata: Vec<&str> = rest.collect();
+        let metadata = if metadata.is_empty() {
+            None
+        } else {
+            Some(metadata.join("/"))
+        };
 
         Ok(Self {
             instance_name,
             uuid,
+            compressor,
             hash,
             size,
+            metadata,
         })
     }
 }
 
+impl TryFrom<&str> for ResourceName {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
 impl TryFrom<String> for ResourceName {
     type Error = Error;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        let resource_name = value.as_str();
-        resource_name.try_into()
+        value.parse()
+    }
+}
+
+impl fmt::Display for ResourceName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.instance_name.as_str().is_empty() {
+            write!(f, "{}/", self.instance_name)?;
+        }
+
+        if let Some(uuid) = &self.uuid {
+            write!(f, "uploads/{uuid}/")?;
+        }
+
+        match &self.compressor {
+            Some(compressor) => write!(f, "compressed-blobs/{compressor}/")?,
+            None => write!(f, "blobs/")?,
+        }
+
+        write!(f, "{}/{}", self.hash, self.size)?;
+
+        if let Some(metadata) = &self.metadata {
+            write!(f, "/{metadata}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4";
+    const UUID: &str = "4a5c9bd2-2f7d-4d8e-9d1e-1a2b3c4d5e6f";
+
+    fn parse(value: &str) -> ResourceName {
+        value
+            .parse()
+            .unwrap_or_else(|err| panic!("`{value}` did not parse: {err}"))
+    }
+
+    #[test]
+    fn round_trips() {
+        let instance_names = ["", "main", "team/project", "a/b/c"];
+        let uploads = [None, Some(UUID)];
+        let compressors = [None, Some("zstd"), Some(IDENTITY_COMPRESSOR)];
+        let metadata = [None, Some("meta"), Some("some/more/meta")];
+
+        for instance_name in instance_names {
+            for upload in uploads {
+                for compressor in compressors {
+                    for metadata in metadata {
+                        let mut value = String::new();
+                        if !instance_name.is_empty() {
+                            value.push_str(&format!("{instance_name}/"));
+                        }
+                        if let Some(uuid) = upload {
+                            value.push_str(&format!("uploads/{uuid}/"));
+                        }
+                        match compressor {
+                            Some(compressor) => {
+                                value.push_str(&format!("compressed-blobs/{compressor}/"))
+                            }
+                            None => value.push_str("blobs/"),
+                        }
+                        value.push_str(&format!("{HASH}/3"));
+                        if let Some(metadata) = metadata {
+                            value.push_str(&format!("/{metadata}"));
+                        }
+
+                        let resource_name = parse(&value);
+
+                        assert_eq!(resource_name.instance_name.as_str(), instance_name);
+                        assert_eq!(
+                            resource_name.uuid.map(|uuid| uuid.to_string()).as_deref(),
+                            upload
+                        );
+                        assert_eq!(resource_name.compressor.as_deref(), compressor);
+                        assert_eq!(resource_name.hash, HASH);
+                        assert_eq!(resource_name.size, 3);
+                        assert_eq!(resource_name.metadata.as_deref(), metadata);
+                        assert_eq!(resource_name.to_string(), value);
+                        assert_eq!(parse(&resource_name.to_string()), resource_name);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn constructors_round_trip() {
+        let digest = DigestInfo::compute(b"hi\n");
+
+        for instance_name in ["", "main", "team/project"] {
+            let blob = ResourceName::blob(instance_name.into(), &digest);
+            let upload = ResourceName::upload(instance_name.into(), &digest);
+
+            assert_eq!(parse(&blob.to_string()), blob);
+            assert_eq!(parse(&upload.to_string()), upload);
+            assert!(upload.uuid.is_some());
+            assert_eq!(DigestInfo::try_new(&blob.hash, blob.size).unwrap(), digest);
+        }
+    }
+
+    #[test]
+    fn compression() {
+        assert!(!parse(&format!("blobs/{HASH}/3")).is_compressed());
+        assert!(!parse(&format!("compressed-blobs/identity/{HASH}/3")).is_compressed());
+        assert!(parse(&format!("compressed-blobs/zstd/{HASH}/3")).is_compressed());
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let invalid = [
+            "".to_string(),
+            "main".to_string(),
+            format!("main/{HASH}/3"),
+            format!("main/blobs/{HASH}"),
+            format!("main/blobs/{HASH}/size"),
+            "main/blobs//3".to_string(),
+            format!("main/blobs/{HASH}/-3"),
+            format!("main/uploads/not-a-uuid/blobs/{HASH}/3"),
+            format!("main/uploads/{UUID}/{HASH}/3"),
+            format!("main/uploads/{UUID}/uploads/{HASH}/3"),
+            format!("main/compressed-blobs/{HASH}/3"),
+            format!("main/compressed-blobs//{HASH}/3"),
+            format!("team//project/blobs/{HASH}/3"),
+            format!("/blobs/{HASH}/3"),
+            format!("main/actions/blobs/{HASH}/3"),
+        ];
+
+        for value in invalid {
+            assert!(
+                matches!(
+                    value.parse::<ResourceName>(),
+                    Err(Error::InvalidResourceName(_))
+                ),
+                "`{value}` should not parse"
+            );
+        }
     }
 }
@@ -0,0 +1,236 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::{HeaderMap, Request, Response};
+use http_body::Body as HttpBody;
+use hyper::Body;
+use tokio::sync::Notify;
+use tonic::{body::BoxBody, Status};
+use tonic_health::{server::HealthReporter, ServingStatus};
+use tower::{Layer, Service};
+
+/// Requests to these services are still answered while draining, so that orchestrators can see
+/// the server is going away
+const EXEMPT_PATH_PREFIXES: &[&str] = &["/grpc.health.v1.Health/"];
+
+#[derive(Default)]
+struct DrainState {
+    draining: AtomicBool,
+    /// Notified once draining starts
+    started: Notify,
+    in_flight: AtomicUsize,
+    /// Notified whenever the last in-flight request finishes
+    idle: Notify,
+}
+
+/// Shutdown of the server, letting the requests in flight finish before it stops.
+///
+/// Once draining starts, the health service reports the server as not serving and new requests
+/// are turned away with UNAVAILABLE, while the ones already in flight, such as long ByteStream
+/// uploads, get a grace period to finish.
+#[derive(Clone, Default)]
+pub struct Drain {
+    state: Arc<DrainState>,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn layer(&self) -> DrainLayer {
+        DrainLayer {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Waits for `signal`, then starts draining. Resolves once nothing is in flight anymore
+    pub async fn on_signal(
+        self,
+        signal: impl Future<Output = ()>,
+        mut health_reporter: HealthReporter,
+    ) {
+        signal.await;
+
+        tracing::info!(
+            in_flight = self.in_flight(),
+            "draining requests before shutting down"
+        );
+
+        self.state.draining.store(true, Ordering::SeqCst);
+        self.state.started.notify_waiters();
+        health_reporter
+            .set_service_status("", ServingStatus::NotServing)
+            .await;
+
+        self.until_idle().await;
+    }
+
+    /// Resolves once `grace_period` has passed since draining started, at which point the
+    /// requests still in flight should be dropped
+    pub async fn deadline(&self, grace_period: Duration) {
+        loop {
+            let started = self.state.started.notified();
+
+            if self.state.draining.load(Ordering::SeqCst) {
+                break;
+            }
+
+            started.await;
+        }
+
+        tokio::time::sleep(grace_period).await;
+    }
+
+    async fn until_idle(&self) {
+        loop {
+            // created before checking, so that a request finishing in between is not missed
+            let idle = self.state.idle.notified();
+
+            if self.in_flight() == 0 {
+                return;
+            }
+
+            idle.await;
+        }
+    }
+}
+
+/// Counts a request as in flight for as long as it lives
+struct InFlightGuard {
+    state: Arc<DrainState>,
+}
+
+impl InFlightGuard {
+    fn new(state: Arc<DrainState>) -> Self {
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        Self { state }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.state.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.state.idle.notify_waiters();
+        }
+    }
+}
+
+/// Response body keeping its request in flight until it is fully sent
+struct GuardedBody {
+    inner: BoxBody,
+    _guard: InFlightGuard,
+}
+
+impl HttpBody for GuardedBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Tower layer keeping track of the requests in flight, and turning new ones away once the
+/// server is draining, see [`Drain`]
+#[derive(Clone)]
+pub struct DrainLayer {
+    state: Arc<DrainState>,
+}
+
+impl<S> Layer<S> for DrainLayer {
+    type Service = Draining<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Draining {
+            inner,
+            state: self.state.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Draining<S> {
+    inner: S,
+    state: Arc<DrainState>,
+}
+
+impl<S> Service<Request<Body>> for Draining<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // the service that was polled ready is the one that has to be called
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let exempt = EXEMPT_PATH_PREFIXES
+            .iter()
+            .any(|prefix| request.uri().path().starts_with(prefix));
+
+        if exempt {
+            return Box::pin(inner.call(request));
+        }
+
+        if self.state.draining.load(Ordering::SeqCst) {
+            let response = Status::unavailable("Server is shutting down").to_http();
+
+            return Box::pin(async move { Ok(response) });
+        }
+
+        let guard = InFlightGuard::new(self.state.clone());
+
+        Box::pin(async move {
+            let response = inner.call(request).await?;
+
+            Ok(response.map(|inner| {
+                BoxBody::new(GuardedBody {
+                    inner,
+                    _guard: guard,
+                })
+            }))
+        })
+    }
+}
@@ -1,9 +1,14 @@
 use bytes::Bytes;
+use prost::Message;
 use thiserror::Error;
 use tokio::task::JoinError;
 use tonic::{Code, Status};
+use uuid::Uuid;
 
-use crate::domain::{DigestHash, InstanceName};
+use crate::{
+    domain::{DigestHash, DigestInfo, InstanceName},
+    protos::google::rpc,
+};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -13,20 +18,208 @@ pub enum Error {
     #[error(transparent)]
     Tokio(#[from] JoinError),
 
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// Boxed, `redb::Error` being several times larger than the other variants
+    #[error("Embedded store failed, {0}")]
+    Embedded(Box<redb::Error>),
+
+    #[error("Redis store failed, {0}")]
+    Redis(#[from] redis::RedisError),
+
+    #[error(transparent)]
+    InvalidProto(#[from] prost::DecodeError),
+
     #[error("Store for `instance_name` of {0} was not found")]
     StoreNotFound(InstanceName),
 
+    #[error("`{0}` is not a valid instance name")]
+    InvalidInstanceName(InstanceName),
+
     #[error("Digest with hash {0} was not found")]
     DigestInfoNotFound(DigestHash),
 
     #[error("Invalid digest part(s), {0}")]
     InvalidDigestParts(String),
 
+    #[error("Uploaded data does not match its digest {0}")]
+    DigestMismatch(DigestInfo),
+
     #[error("`{0}` is not a valid resource name")]
     InvalidResourceName(String),
 
     #[error("`{0}` could not be converted to a different int type")]
     ConversionIntError(String),
+
+    #[error("`{0}` is not a valid endpoint")]
+    InvalidEndpoint(String),
+
+    #[error("Invalid store configuration, {0}")]
+    InvalidStoreConfig(String),
+
+    #[error("Entry does not fit in the quota of instance `{0}`")]
+    QuotaExceeded(InstanceName),
+
+    #[error("Operation `{0}` was not found")]
+    OperationNotFound(String),
+
+    #[error("Operation `{0}` is not leased to this worker")]
+    LeaseLost(String),
+
+    #[error("{} blob(s) needed to run the action are missing from the CAS", .0.len())]
+    MissingBlobs(Vec<DigestInfo>),
+
+    #[error("Upload `{0}` was not found, it may have expired")]
+    UploadNotFound(Uuid),
+
+    #[error("`write_offset` of {0} does not match the {1} bytes written so far")]
+    WriteOffsetMismatch(i64, usize),
+
+    #[error("Write goes past the {0} bytes of the blob")]
+    WriteTooLarge(usize),
+
+    #[error(
+        "Partial uploads already hold the {0} bytes they may, finish or retry the upload later"
+    )]
+    UploadsExhausted(usize),
+
+    #[error("Operation is not supported, {0}")]
+    UnsupportedOperation(&'static str),
+
+    #[error("`{0}` is required")]
+    MissingField(&'static str),
+
+    #[error("Only SHA256 digests are supported")]
+    UnsupportedDigestFunction,
+
+    #[error("Batch of {0} bytes is over the limit of {1} bytes")]
+    BatchTooLarge(u64, u64),
+
+    #[error("Blob of {0} bytes is over the limit of {1} bytes")]
+    BlobTooLarge(i64, u64),
+
+    #[error("Remote cache responded with {0}")]
+    Remote(Box<Status>),
+}
+
+impl From<redb::Error> for Error {
+    fn from(err: redb::Error) -> Self {
+        Error::Embedded(Box::new(err))
+    }
+}
+
+/// `redb` has an error type per kind of operation, each of which converts into a `redb::Error`
+macro_rules! from_redb_errors {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for Error {
+                fn from(err: $error) -> Self {
+                    redb::Error::from(err).into()
+                }
+            }
+        )*
+    };
+}
+
+from_redb_errors!(
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+impl Error {
+    /// Converts the error status of a request made to a remote cache about `key`
+    pub fn from_remote_status(status: Status, key: &DigestInfo) -> Self {
+        match status.code() {
+            Code::NotFound => Error::DigestInfoNotFound(key.hash()),
+            _ => Error::Remote(Box::new(status)),
+        }
+    }
+
+    /// Copy of the error, for outcomes shared by several callers. Errors wrapping a source that
+    /// cannot be cloned become the internal error they would be sent to clients as
+    pub fn duplicate(&self) -> Self {
+        match self {
+            Error::InvalidHexString(err) => Error::InvalidHexString(*err),
+            Error::Io(err) => Error::Io(std::io::Error::new(err.kind(), err.to_string())),
+            err @ (Error::Tokio(_) | Error::Embedded(_) | Error::Redis(_)) => {
+                Error::Remote(Box::new(Status::internal(err.to_string())))
+            }
+            Error::InvalidProto(err) => Error::InvalidProto(err.clone()),
+            Error::StoreNotFound(instance_name) => Error::StoreNotFound(instance_name.clone()),
+            Error::InvalidInstanceName(instance_name) => {
+                Error::InvalidInstanceName(instance_name.clone())
+            }
+            Error::DigestInfoNotFound(hash) => Error::DigestInfoNotFound(hash.clone()),
+            Error::InvalidDigestParts(message) => Error::InvalidDigestParts(message.clone()),
+            Error::DigestMismatch(digest) => Error::DigestMismatch(digest.clone()),
+            Error::InvalidResourceName(name) => Error::InvalidResourceName(name.clone()),
+            Error::ConversionIntError(value) => Error::ConversionIntError(value.clone()),
+            Error::InvalidEndpoint(endpoint) => Error::InvalidEndpoint(endpoint.clone()),
+            Error::InvalidStoreConfig(message) => Error::InvalidStoreConfig(message.clone()),
+            Error::QuotaExceeded(instance_name) => Error::QuotaExceeded(instance_name.clone()),
+            Error::OperationNotFound(name) => Error::OperationNotFound(name.clone()),
+            Error::LeaseLost(name) => Error::LeaseLost(name.clone()),
+            Error::MissingBlobs(digests) => Error::MissingBlobs(digests.clone()),
+            Error::UploadNotFound(uuid) => Error::UploadNotFound(*uuid),
+            Error::WriteOffsetMismatch(offset, written) => {
+                Error::WriteOffsetMismatch(*offset, *written)
+            }
+            Error::WriteTooLarge(size) => Error::WriteTooLarge(*size),
+            Error::UploadsExhausted(max_bytes) => Error::UploadsExhausted(*max_bytes),
+            Error::UnsupportedOperation(operation) => Error::UnsupportedOperation(operation),
+            Error::MissingField(field) => Error::MissingField(field),
+            Error::UnsupportedDigestFunction => Error::UnsupportedDigestFunction,
+            Error::BatchTooLarge(size, max) => Error::BatchTooLarge(*size, *max),
+            Error::BlobTooLarge(size, max) => Error::BlobTooLarge(*size, *max),
+            Error::Remote(status) => Error::Remote(Box::new(Status::with_details(
+                status.code(),
+                status.message(),
+                status.details().to_vec().into(),
+            ))),
+        }
+    }
+}
+
+/// As the REAPI asks for, a violation of type MISSING for every missing blob
+fn missing_blobs_status(digests: &[DigestInfo], message: String) -> rpc::Status {
+    let violations = digests
+        .iter()
+        .map(|digest| rpc::precondition_failure::Violation {
+            r#type: "MISSING".to_string(),
+            subject: format!("blobs/{digest}"),
+            description: String::new(),
+        })
+        .collect();
+
+    rpc::Status {
+        code: Code::FailedPrecondition as i32,
+        message,
+        details: vec![prost_types::Any {
+            type_url: "type.googleapis.com/google.rpc.PreconditionFailure".to_string(),
+            value: rpc::PreconditionFailure { violations }.encode_to_vec(),
+        }],
+    }
+}
+
+/// Per-entry status of the batched CAS calls, and status of the actions workers fail to run
+impl From<Error> for rpc::Status {
+    fn from(err: Error) -> Self {
+        if let Error::MissingBlobs(digests) = &err {
+            return missing_blobs_status(digests, err.to_string());
+        }
+
+        let status = tonic::Status::from(err);
+
+        Self {
+            code: status.code() as i32,
+            message: status.message().to_string(),
+            details: Vec::new(),
+        }
+    }
 }
 
 impl From<Error> for tonic::Status {
@@ -65,11 +258,45 @@ impl From<Error> for tonic::Status {
                 "Tokio task failed to execute",
                 Bytes::from(join_error.to_string()),
             ),
-            err @ Error::StoreNotFound(_) => Status::internal(err.to_string()),
+            err @ Error::Io(_) => Status::internal(err.to_string()),
+            err @ Error::Embedded(_) => Status::internal(err.to_string()),
+            err @ Error::Redis(_) => Status::internal(err.to_string()),
+            Error::InvalidProto(decode_error) => Status::with_details(
+                Code::InvalidArgument,
+                "Failed to decode protobuf message",
+                Bytes::from(decode_error.to_string()),
+            ),
+            err @ Error::StoreNotFound(_) => Status::not_found(err.to_string()),
+            err @ Error::InvalidInstanceName(_) => Status::invalid_argument(err.to_string()),
             err @ Error::InvalidResourceName(_) => Status::invalid_argument(err.to_string()),
             err @ Error::DigestInfoNotFound(_) => Status::not_found(err.to_string()),
             err @ Error::ConversionIntError(_) => Status::invalid_argument(err.to_string()),
             err @ Error::InvalidDigestParts(_) => Status::invalid_argument(err.to_string()),
+            err @ Error::DigestMismatch(_) => Status::invalid_argument(err.to_string()),
+            err @ Error::InvalidEndpoint(_) => Status::internal(err.to_string()),
+            err @ Error::InvalidStoreConfig(_) => Status::internal(err.to_string()),
+            err @ Error::QuotaExceeded(_) => Status::resource_exhausted(err.to_string()),
+            err @ Error::OperationNotFound(_) => Status::not_found(err.to_string()),
+            err @ Error::LeaseLost(_) => Status::aborted(err.to_string()),
+            err @ Error::UploadNotFound(_) => Status::not_found(err.to_string()),
+            err @ Error::WriteOffsetMismatch(..) => Status::invalid_argument(err.to_string()),
+            err @ Error::WriteTooLarge(_) => Status::invalid_argument(err.to_string()),
+            err @ Error::UploadsExhausted(_) => Status::resource_exhausted(err.to_string()),
+            Error::MissingBlobs(ref digests) => {
+                let details = missing_blobs_status(digests, err.to_string());
+
+                Status::with_details(
+                    Code::FailedPrecondition,
+                    err.to_string(),
+                    Bytes::from(details.encode_to_vec()),
+                )
+            }
+            err @ Error::UnsupportedOperation(_) => Status::unimplemented(err.to_string()),
+            err @ Error::MissingField(_) => Status::invalid_argument(err.to_string()),
+            err @ Error::UnsupportedDigestFunction => Status::invalid_argument(err.to_string()),
+            err @ Error::BatchTooLarge(..) => Status::invalid_argument(err.to_string()),
+            err @ Error::BlobTooLarge(..) => Status::invalid_argument(err.to_string()),
+            Error::Remote(status) => *status,
         }
     }
 }
@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{
+    future::{BoxFuture, Shared, WeakShared},
+    FutureExt,
+};
+use tracing::instrument;
+
+use super::{Store, StoreKind, StoreStats};
+use crate::{domain::DigestInfo, errors::Error};
+
+/// Outcome of a call shared between every caller waiting on it. [`Error`] cannot be cloned, so
+/// the error is shared and each caller gets a [`Error::duplicate`] of it
+type SharedOutcome<T> = Result<T, Arc<Error>>;
+
+type CallFuture<T> = BoxFuture<'static, SharedOutcome<T>>;
+
+/// Calls in flight by key. Calls are only referenced weakly, so that a call every caller gave up
+/// on is dropped rather than kept running, and numbered, for a dropped call to only deregister
+/// itself and not a call started for the same key since
+struct Calls<K, T> {
+    next_id: u64,
+    calls: HashMap<K, (u64, WeakShared<CallFuture<T>>)>,
+}
+
+impl<K, T> Default for Calls<K, T> {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            calls: HashMap::new(),
+        }
+    }
+}
+
+type InFlight<K, T> = Arc<Mutex<Calls<K, T>>>;
+
+/// Removes a call from the calls in flight once it is done or dropped
+struct Deregister<K: Eq + Hash, T> {
+    in_flight: InFlight<K, T>,
+    key: K,
+    id: u64,
+}
+
+impl<K: Eq + Hash, T> Drop for Deregister<K, T> {
+    fn drop(&mut self) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if matches!(in_flight.calls.get(&self.key), Some((id, _)) if *id == self.id) {
+            in_flight.calls.remove(&self.key);
+        }
+    }
+}
+
+/// Runs `call` unless a call for `key` is already in flight, in which case its outcome is awaited
+/// instead. The call removes itself from `in_flight` once done, or once every caller awaiting it
+/// was dropped, so later callers start afresh
+async fn single_flight<K, T>(
+    in_flight: &InFlight<K, T>,
+    key: K,
+    call: impl FnOnce() -> BoxFuture<'static, Result<T, Error>>,
+) -> Result<T, Error>
+where
+    K: Clone + Eq + Hash + Send + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    let shared: Shared<CallFuture<T>> = {
+        let mut calls = in_flight.lock().unwrap();
+
+        match calls.calls.get(&key).and_then(|(_, call)| call.upgrade()) {
+            Some(shared) => shared,
+            None => {
+                let id = calls.next_id;
+                calls.next_id += 1;
+
+                let call = call();
+                let deregister = Deregister {
+                    in_flight: in_flight.clone(),
+                    key: key.clone(),
+                    id,
+                };
+                let shared = async move {
+                    let _deregister = deregister;
+
+                    call.await.map_err(Arc::new)
+                }
+                .boxed()
+                .shared();
+
+                // a call that could not be downgraded has completed already, without a need to be
+                // registered
+                if let Some(weak) = shared.downgrade() {
+                    calls.calls.insert(key, (id, weak));
+                }
+
+                shared
+            }
+        }
+    };
+
+    shared
+        .await
+        .map_err(|err| Arc::try_unwrap(err).unwrap_or_else(|err| err.duplicate()))
+}
+
+/// Store collapsing concurrent calls for the same digest into a single call to the store it
+/// wraps.
+///
+/// Reads of the same chunk of a blob share one read, and writes of the same blob share one
+/// write, every caller getting its outcome. Since a blob is only identified by its digest,
+/// writes are assumed to carry the same bytes, which only holds for content addressed stores.
+#[derive(Clone)]
+pub struct CoalescingStore {
+    inner: Arc<StoreKind>,
+    reads: InFlight<(DigestInfo, usize, usize), Bytes>,
+    writes: InFlight<DigestInfo, ()>,
+}
+
+impl Debug for CoalescingStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CoalescingStore")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CoalescingStore {
+    pub fn new(inner: Arc<StoreKind>) -> Self {
+        Self {
+            inner,
+            reads: Arc::default(),
+            writes: Arc::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for CoalescingStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        self.inner.contains_key(key).await
+    }
+
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        self.inner.contains_keys(keys).await
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        self.inner.touch(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        single_flight(&self.reads, (key.clone(), offset, limit), || {
+            let inner = self.inner.clone();
+            let key = key.clone();
+
+            async move { inner.get_chunk(&key, offset, limit).await }.boxed()
+        })
+        .await
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        single_flight(&self.writes, key.clone(), || {
+            let inner = self.inner.clone();
+            let key = key.clone();
+
+            async move { inner.put(key, bytes).await }.boxed()
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        self.inner.remove(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        self.inner.size_of(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        self.inner.clear().await
+    }
+
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        vec![self.inner.clone()]
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats::layered("coalescing", &self.tiers())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::memory::MemoryStore;
+
+    #[tokio::test]
+    async fn failures_keep_their_error() {
+        let store = CoalescingStore::new(Arc::new(StoreKind::from(MemoryStore::new(1024))));
+        let key = DigestInfo::compute(b"missing");
+
+        let result = store.get_chunk(&key, 0, 0).await;
+
+        assert!(matches!(result, Err(Error::DigestInfoNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn dropped_calls_are_deregistered() {
+        let in_flight: InFlight<u32, ()> = Arc::default();
+
+        let call = single_flight(&in_flight, 1, || futures::future::pending().boxed());
+        let timed_out = tokio::time::timeout(std::time::Duration::from_millis(10), call).await;
+
+        assert!(timed_out.is_err());
+        assert!(in_flight.lock().unwrap().calls.is_empty());
+    }
+}
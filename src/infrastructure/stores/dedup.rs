@@ -0,0 +1,470 @@
+use std::{collections::HashSet, fmt::Debug, io::ErrorKind, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use fastcdc::v2020::{FastCDC, AVERAGE_MAX, AVERAGE_MIN, MAXIMUM_MAX, MINIMUM_MIN};
+use tracing::instrument;
+
+use super::{Store, StoreKind, StoreStats};
+use crate::{domain::DigestInfo, errors::Error};
+
+/// First bytes of a manifest, ending with the version of the format
+const MANIFEST_MAGIC: &[u8; 8] = b"BACHEDM1";
+
+/// Bytes taken by every chunk in a manifest, its hash followed by its length
+const MANIFEST_ENTRY_LENGTH: usize = 32 + 8;
+
+/// Sizes of the chunks blobs are split in, see [`DedupStore`]
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkingOptions {
+    min_size: u32,
+    average_size: u32,
+    max_size: u32,
+}
+
+impl ChunkingOptions {
+    /// Chunks of about `average_size` bytes, and of a quarter to four times that
+    pub fn new(average_size: u32) -> Result<Self, Error> {
+        if !(AVERAGE_MIN..=AVERAGE_MAX).contains(&average_size) {
+            return Err(Error::InvalidStoreConfig(format!(
+                "average chunk size must be between {AVERAGE_MIN} and {AVERAGE_MAX} bytes"
+            )));
+        }
+
+        Ok(Self {
+            min_size: (average_size / 4).max(MINIMUM_MIN),
+            average_size,
+            max_size: average_size.saturating_mul(4).min(MAXIMUM_MAX),
+        })
+    }
+}
+
+impl Default for ChunkingOptions {
+    /// Chunks of about 512KiB
+    fn default() -> Self {
+        Self::new(512 * 1024).expect("default average chunk size is in range")
+    }
+}
+
+/// Chunks of a blob, in order, as the key and length of every one of them
+#[derive(Debug, Clone)]
+struct Manifest {
+    chunks: Vec<DigestInfo>,
+}
+
+impl Manifest {
+    fn encode(&self) -> Bytes {
+        let mut bytes = BytesMut::with_capacity(
+            MANIFEST_MAGIC.len() + self.chunks.len() * MANIFEST_ENTRY_LENGTH,
+        );
+
+        bytes.put_slice(MANIFEST_MAGIC);
+        for chunk in &self.chunks {
+            bytes.put_slice(&chunk.packed_hash);
+            bytes.put_u64_le(chunk.size_bytes as u64);
+        }
+
+        bytes.freeze()
+    }
+
+    fn decode(mut bytes: Bytes) -> Result<Self, Error> {
+        let invalid = || {
+            Error::from(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "entry is not a dedup manifest",
+            ))
+        };
+
+        if !bytes.starts_with(MANIFEST_MAGIC) {
+            return Err(invalid());
+        }
+        bytes.advance(MANIFEST_MAGIC.len());
+
+        if !bytes.len().is_multiple_of(MANIFEST_ENTRY_LENGTH) {
+            return Err(invalid());
+        }
+
+        let chunks = bytes
+            .chunks_exact(MANIFEST_ENTRY_LENGTH)
+            .map(|entry| {
+                let (packed_hash, length) = entry.split_at(32);
+                let length = u64::from_le_bytes(length.try_into().unwrap());
+
+                DigestInfo::new(packed_hash.try_into().unwrap(), length as i64)
+            })
+            .collect();
+
+        Ok(Self { chunks })
+    }
+
+    fn length(&self) -> usize {
+        self.chunks
+            .iter()
+            .map(|chunk| chunk.size_bytes as usize)
+            .sum()
+    }
+
+    /// Chunks holding the `limit` bytes from `offset`, along with the offset and limit to read
+    /// each of them with
+    fn ranges(&self, offset: usize, limit: usize) -> Vec<(&DigestInfo, usize, usize)> {
+        let end = offset.saturating_add(limit);
+
+        let mut ranges = Vec::new();
+        let mut chunk_start = 0;
+        for chunk in &self.chunks {
+            let chunk_end = chunk_start + chunk.size_bytes as usize;
+
+            if chunk_end > offset && chunk_start < end {
+                let start = offset.max(chunk_start) - chunk_start;
+                ranges.push((chunk, start, end.min(chunk_end) - chunk_start - start));
+            }
+            if chunk_end >= end {
+                break;
+            }
+
+            chunk_start = chunk_end;
+        }
+
+        ranges
+    }
+}
+
+/// Splits `bytes` in content-defined chunks, so that blobs differing by a few bytes share most of
+/// their chunks
+pub(crate) fn split(bytes: &Bytes, options: ChunkingOptions) -> Vec<(DigestInfo, Bytes)> {
+    if bytes.is_empty() {
+        return Vec::new();
+    }
+
+    FastCDC::new(
+        bytes,
+        options.min_size,
+        options.average_size,
+        options.max_size,
+    )
+    .map(|chunk| {
+        let chunk = bytes.slice(chunk.offset..chunk.offset + chunk.length);
+
+        (DigestInfo::compute(&chunk), chunk)
+    })
+    .collect()
+}
+
+/// Content addressed store splitting blobs in content-defined chunks (FastCDC), so that blobs
+/// differing only slightly between builds, such as jars or container layers, share most of their
+/// storage.
+///
+/// Every chunk is kept once in the `content` store, while the `index` store keeps a manifest per
+/// blob listing its chunks. Reads are reassembled from the chunks, offset reads only fetching the
+/// chunks they cover. A blob is only reported as present when all of its chunks are, as they may
+/// be evicted from the content store on their own.
+#[derive(Clone)]
+pub struct DedupStore {
+    index: Arc<StoreKind>,
+    content: Arc<StoreKind>,
+    options: ChunkingOptions,
+}
+
+impl Debug for DedupStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DedupStore")
+            .field("index", &self.index)
+            .field("content", &self.content)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl DedupStore {
+    pub fn new(index: Arc<StoreKind>, content: Arc<StoreKind>, options: ChunkingOptions) -> Self {
+        Self {
+            index,
+            content,
+            options,
+        }
+    }
+
+    async fn get_manifest(&self, key: &DigestInfo) -> Result<Manifest, Error> {
+        Manifest::decode(self.index.get(key).await?)
+    }
+}
+
+#[async_trait]
+impl Store for DedupStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        self.contains_keys(std::slice::from_ref(key)).await[0]
+    }
+
+    /// Looks up the manifests of every key, then every chunk of those in a single batch
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        let manifests: Vec<_> = self
+            .index
+            .get_many(keys)
+            .await
+            .into_iter()
+            .map(|bytes| bytes.and_then(Manifest::decode).ok())
+            .collect();
+
+        let chunks: Vec<_> = manifests
+            .iter()
+            .flatten()
+            .flat_map(|manifest| manifest.chunks.iter().cloned())
+            .collect();
+        let mut present = self.content.contains_keys(&chunks).await.into_iter();
+
+        manifests
+            .iter()
+            .map(|manifest| match manifest {
+                // every chunk is consumed, even past a missing one, to stay aligned with `chunks`
+                Some(manifest) => manifest
+                    .chunks
+                    .iter()
+                    .fold(true, |all, _| present.next().unwrap_or(false) && all),
+                None => false,
+            })
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        if !self.index.touch(key).await {
+            return false;
+        }
+
+        let manifest = match self.get_manifest(key).await {
+            Ok(manifest) => manifest,
+            Err(_) => return false,
+        };
+
+        let touches: Vec<_> = manifest
+            .chunks
+            .iter()
+            .map(|chunk| self.content.touch(chunk))
+            .collect();
+
+        futures::future::join_all(touches)
+            .await
+            .into_iter()
+            .all(|touched| touched)
+    }
+
+    /// Also prioritizes the chunks of the blob, which may be shared with blobs that are not
+    #[instrument(skip(self))]
+    async fn prioritize(&self, key: &DigestInfo) -> bool {
+        if !self.index.prioritize(key).await {
+            return false;
+        }
+
+        if let Ok(manifest) = self.get_manifest(key).await {
+            for chunk in &manifest.chunks {
+                self.content.prioritize(chunk).await;
+            }
+        }
+
+        true
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        let manifest = self.get_manifest(key).await?;
+
+        let reads: Vec<_> = manifest
+            .ranges(offset, limit)
+            .into_iter()
+            .map(|(chunk, offset, limit)| self.content.get_chunk(chunk, offset, limit))
+            .collect();
+
+        let chunks = futures::future::try_join_all(reads)
+            .await
+            .map_err(|err| match err {
+                // a chunk was evicted, the blob is gone for good
+                Error::DigestInfoNotFound(_) => Error::DigestInfoNotFound(key.hash()),
+                err => err,
+            })?;
+
+        if chunks.len() == 1 {
+            return Ok(chunks.into_iter().next().unwrap());
+        }
+
+        let mut bytes = BytesMut::with_capacity(chunks.iter().map(Bytes::len).sum());
+        for chunk in chunks {
+            bytes.extend_from_slice(&chunk);
+        }
+
+        Ok(bytes.freeze())
+    }
+
+    /// Writes the chunks the content store is missing, then the manifest of the blob
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        let options = self.options;
+        // chunking and hashing a large blob would hold up the other requests
+        let chunks = tokio::task::spawn_blocking(move || split(&bytes, options)).await?;
+
+        let manifest = Manifest {
+            chunks: chunks.iter().map(|(chunk, _)| chunk.clone()).collect(),
+        };
+
+        let mut seen = HashSet::new();
+        let unique: Vec<_> = chunks
+            .into_iter()
+            .filter(|(chunk, _)| seen.insert(chunk.clone()))
+            .collect();
+
+        let keys: Vec<_> = unique.iter().map(|(chunk, _)| chunk.clone()).collect();
+        let missing: Vec<_> = unique
+            .into_iter()
+            .zip(self.content.contains_keys(&keys).await)
+            .filter(|(_, present)| !present)
+            .map(|(entry, _)| entry)
+            .collect();
+
+        tracing::debug!(
+            chunks = manifest.chunks.len(),
+            new_chunks = missing.len(),
+            "deduplicated blob"
+        );
+
+        for result in self.content.put_many(missing).await {
+            result?;
+        }
+
+        self.index.put(key, manifest.encode()).await
+    }
+
+    /// Only removes the manifest of the blob, its chunks may be shared with other blobs and are
+    /// left to the eviction of the content store
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        self.index.remove(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        self.get_manifest(key)
+            .await
+            .ok()
+            .map(|manifest| manifest.length())
+    }
+
+    #[instrument(skip(self))]
+    async fn list_keys(&self) -> Result<Vec<DigestInfo>, Error> {
+        self.index.list_keys().await
+    }
+
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        self.index.clear().await?;
+        self.content.clear().await
+    }
+
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        vec![self.index.clone(), self.content.clone()]
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats::layered("dedup", &self.tiers())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::memory::MemoryStore;
+
+    const CHUNKS: [&[u8]; 3] = [b"abcd", b"efgh", b"ij"];
+
+    fn manifest(chunks: &[&[u8]]) -> Manifest {
+        Manifest {
+            chunks: chunks
+                .iter()
+                .map(|chunk| DigestInfo::compute(chunk))
+                .collect(),
+        }
+    }
+
+    /// Ranges as the index of their chunk, offset and limit
+    fn ranges(manifest: &Manifest, offset: usize, limit: usize) -> Vec<(usize, usize, usize)> {
+        manifest
+            .ranges(offset, limit)
+            .into_iter()
+            .map(|(chunk, offset, limit)| {
+                let index = manifest.chunks.iter().position(|c| c == chunk).unwrap();
+                (index, offset, limit)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ranges_cover_the_chunks_of_the_bytes_asked_for() {
+        let manifest = manifest(&CHUNKS);
+
+        assert_eq!(
+            ranges(&manifest, 0, usize::MAX),
+            [(0, 0, 4), (1, 0, 4), (2, 0, 2)]
+        );
+        assert_eq!(ranges(&manifest, 4, 4), [(1, 0, 4)]);
+        assert_eq!(ranges(&manifest, 3, 2), [(0, 3, 1), (1, 0, 1)]);
+        assert_eq!(ranges(&manifest, 8, 10), [(2, 0, 2)]);
+        assert_eq!(ranges(&manifest, 4, 0), []);
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_empty() {
+        assert_eq!(ranges(&manifest(&CHUNKS), 10, 4), []);
+        assert_eq!(ranges(&manifest(&CHUNKS), 12, 4), []);
+        assert_eq!(ranges(&manifest(&[]), 0, usize::MAX), []);
+    }
+
+    async fn store(chunks: &[&[u8]]) -> (DedupStore, DigestInfo) {
+        let index = Arc::new(StoreKind::from(MemoryStore::new(1024)));
+        let content = Arc::new(StoreKind::from(MemoryStore::new(1024)));
+
+        for chunk in chunks {
+            let chunk = Bytes::copy_from_slice(chunk);
+            content
+                .put(DigestInfo::compute(&chunk), chunk)
+                .await
+                .unwrap();
+        }
+        let key = DigestInfo::compute(&chunks.concat());
+        index
+            .put(key.clone(), manifest(chunks).encode())
+            .await
+            .unwrap();
+
+        let store = DedupStore::new(index, content, ChunkingOptions::default());
+
+        (store, key)
+    }
+
+    #[tokio::test]
+    async fn partial_reads_are_reassembled_from_the_chunks() {
+        let (store, key) = store(&CHUNKS).await;
+
+        let read = |offset, limit| store.get_chunk(&key, offset, limit);
+        assert_eq!(read(0, usize::MAX).await.unwrap(), &b"abcdefghij"[..]);
+        assert_eq!(read(4, 4).await.unwrap(), &b"efgh"[..]);
+        assert_eq!(read(3, 6).await.unwrap(), &b"defghi"[..]);
+        assert_eq!(read(10, 4).await.unwrap(), &b""[..]);
+        assert_eq!(read(20, 4).await.unwrap(), &b""[..]);
+    }
+
+    #[tokio::test]
+    async fn empty_blobs_are_read_back_empty() {
+        let (store, key) = store(&[]).await;
+
+        assert_eq!(
+            store.get_chunk(&key, 0, usize::MAX).await.unwrap(),
+            &b""[..]
+        );
+        assert_eq!(store.size_of(&key).await, Some(0));
+    }
+}
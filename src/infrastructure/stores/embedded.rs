@@ -0,0 +1,485 @@
+use std::{fmt::Debug, path::Path, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use redb::{
+    Database, Durability, ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction,
+};
+use tracing::instrument;
+
+use super::{slice_chunk, Store, StoreStats};
+use crate::{domain::DigestInfo, errors::Error};
+
+type Table<'a> = TableDefinition<'a, &'static [u8], &'static [u8]>;
+
+/// Write sequence number of every entry of a store, by key
+type SequenceTable<'a> = TableDefinition<'a, &'static [u8], u64>;
+
+/// Key of every entry of a store, by write sequence number, oldest first
+type OrderTable<'a> = TableDefinition<'a, u64, &'static [u8]>;
+
+/// Counters of a store, see [`STORED_BYTES`] and [`NEXT_SEQUENCE`]
+type MetadataTable<'a> = TableDefinition<'a, &'static str, u64>;
+
+/// Bytes of the entries of a store, as counted against its limit
+const STORED_BYTES: &str = "stored_bytes";
+
+/// Sequence number of the next write to a store
+const NEXT_SEQUENCE: &str = "next_sequence";
+
+/// The tables of a store, named after its entries table. Those names never collide with the
+/// names of entries tables, which start with the API they are for
+#[derive(Clone)]
+struct TableNames {
+    entries: Arc<str>,
+    sequences: Arc<str>,
+    order: Arc<str>,
+    metadata: Arc<str>,
+}
+
+impl TableNames {
+    fn new(table: &str) -> Self {
+        Self {
+            entries: table.into(),
+            sequences: format!("sequences:{table}").into(),
+            order: format!("order:{table}").into(),
+            metadata: format!("metadata:{table}").into(),
+        }
+    }
+}
+
+/// The open tables of a store, within a write transaction
+struct WriteTables<'txn> {
+    entries: redb::Table<'txn, &'static [u8], &'static [u8]>,
+    sequences: redb::Table<'txn, &'static [u8], u64>,
+    order: redb::Table<'txn, u64, &'static [u8]>,
+    metadata: redb::Table<'txn, &'static str, u64>,
+}
+
+impl<'txn> WriteTables<'txn> {
+    fn open(write: &'txn WriteTransaction, names: &TableNames) -> Result<Self, Error> {
+        Ok(Self {
+            entries: write.open_table(Table::new(&names.entries))?,
+            sequences: write.open_table(SequenceTable::new(&names.sequences))?,
+            order: write.open_table(OrderTable::new(&names.order))?,
+            metadata: write.open_table(MetadataTable::new(&names.metadata))?,
+        })
+    }
+
+    fn counter(&self, name: &str) -> Result<u64, Error> {
+        Ok(self.metadata.get(name)?.map_or(0, |value| value.value()))
+    }
+
+    fn insert(&mut self, key: &DigestInfo, bytes: &[u8]) -> Result<(), Error> {
+        let encoded = encode_key(key);
+
+        let replaced_bytes = self
+            .entries
+            .insert(encoded.as_slice(), bytes)?
+            .map_or(0, |value| value.value().len());
+        let stored_bytes = self.counter(STORED_BYTES)? + bytes.len() as u64;
+        self.metadata
+            .insert(STORED_BYTES, stored_bytes - replaced_bytes as u64)?;
+
+        // a rewritten entry moves to the back of the queue
+        let sequence = self.counter(NEXT_SEQUENCE)?;
+        self.metadata.insert(NEXT_SEQUENCE, sequence + 1)?;
+        let replaced_sequence = self
+            .sequences
+            .insert(encoded.as_slice(), sequence)?
+            .map(|value| value.value());
+        if let Some(replaced_sequence) = replaced_sequence {
+            self.order.remove(replaced_sequence)?;
+        }
+        self.order.insert(sequence, encoded.as_slice())?;
+
+        Ok(())
+    }
+
+    fn remove(&mut self, encoded: &[u8]) -> Result<(), Error> {
+        let removed_bytes = self
+            .entries
+            .remove(encoded)?
+            .map_or(0, |value| value.value().len());
+        let stored_bytes = self.counter(STORED_BYTES)?;
+        self.metadata.insert(
+            STORED_BYTES,
+            stored_bytes.saturating_sub(removed_bytes as u64),
+        )?;
+
+        let removed_sequence = self.sequences.remove(encoded)?.map(|value| value.value());
+        if let Some(removed_sequence) = removed_sequence {
+            self.order.remove(removed_sequence)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the oldest entries until the store holds at most `max_bytes` bytes
+    fn evict(&mut self, max_bytes: u64) -> Result<(), Error> {
+        while self.counter(STORED_BYTES)? > max_bytes {
+            let oldest = self.order.first()?.map(|(_, key)| key.value().to_vec());
+
+            match oldest {
+                Some(encoded) => self.remove(&encoded)?,
+                // entries written before there was a limit are not in the queue
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Key of an entry in the database, its hash followed by its size. The size is part of the key
+/// as two ActionCache keys may only differ by the size of their action
+fn encode_key(key: &DigestInfo) -> [u8; 40] {
+    let mut encoded = [0; 40];
+    encoded[..32].copy_from_slice(&key.packed_hash);
+    encoded[32..].copy_from_slice(&key.size_bytes.to_be_bytes());
+
+    encoded
+}
+
+fn decode_key(encoded: &[u8]) -> Option<DigestInfo> {
+    let packed_hash = encoded.get(..32)?.try_into().ok()?;
+    let size_bytes = i64::from_be_bytes(encoded.get(32..40)?.try_into().ok()?);
+
+    Some(DigestInfo::new(packed_hash, size_bytes))
+}
+
+/// Opens the database file at `path`, creating it when missing. A database is shared by every
+/// [`EmbeddedStore`] of the server, each in its own table
+pub fn open_database(path: &Path) -> Result<Arc<Database>, Error> {
+    let database = Database::create(path).map_err(redb::Error::from)?;
+
+    Ok(Arc::new(database))
+}
+
+/// Store keeping its entries in a table of an embedded, on-disk, key-value database.
+///
+/// Meant for small entries (most ActionResults and many CAS blobs are a few hundred bytes),
+/// which would be wasteful to keep as files or objects of their own, see
+/// [`super::size_partitioned::SizePartitionedStore`]. Writes are only guaranteed to be on disk
+/// once the store is flushed, a crash may lose the latest ones. Once over its limit, the store
+/// evicts the entries written the longest ago.
+#[derive(Clone)]
+pub struct EmbeddedStore {
+    database: Arc<Database>,
+    table: Arc<str>,
+    tables: TableNames,
+    max_bytes: u64,
+}
+
+impl Debug for EmbeddedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EmbeddedStore")
+            .field("table", &self.table)
+            .field("max_bytes", &self.max_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EmbeddedStore {
+    /// Creates a store keeping at most `max_bytes` bytes of entries in `table` of `database`,
+    /// creating the table when missing
+    pub fn new(database: Arc<Database>, table: &str, max_bytes: u64) -> Result<Self, Error> {
+        let tables = TableNames::new(table);
+
+        let write = database.begin_write()?;
+        WriteTables::open(&write, &tables)?.evict(max_bytes)?;
+        write.commit()?;
+
+        Ok(Self {
+            database,
+            table: table.into(),
+            tables,
+            max_bytes,
+        })
+    }
+
+    /// Runs `call` on a thread where blocking is fine, as every database call may block on disk
+    /// access
+    async fn blocking<T, F>(&self, call: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database, Table) -> Result<T, Error> + Send + 'static,
+    {
+        let database = self.database.clone();
+        let table = self.table.clone();
+
+        tokio::task::spawn_blocking(move || call(&database, Table::new(&table))).await?
+    }
+
+    /// Runs `call` on the tables of the store within a write transaction, on a thread where
+    /// blocking is fine, evicting the oldest entries when over the limit before committing
+    async fn write<F>(&self, call: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut WriteTables) -> Result<(), Error> + Send + 'static,
+    {
+        let database = self.database.clone();
+        let tables = self.tables.clone();
+        let max_bytes = self.max_bytes;
+
+        tokio::task::spawn_blocking(move || {
+            let mut write = database.begin_write()?;
+            // made durable when flushed, a commit per entry going to disk would be too slow
+            write.set_durability(Durability::Eventual);
+            {
+                let mut tables = WriteTables::open(&write, &tables)?;
+                call(&mut tables)?;
+                tables.evict(max_bytes)?;
+            }
+            write.commit()?;
+
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn write_entries(&self, entries: Vec<(DigestInfo, Bytes)>) -> Result<(), Error> {
+        self.write(move |tables| {
+            for (key, bytes) in &entries {
+                tables.insert(key, bytes)?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl Store for EmbeddedStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        self.contains_keys(std::slice::from_ref(key)).await[0]
+    }
+
+    /// Looks up every key in a single read transaction
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        let encoded: Vec<_> = keys.iter().map(encode_key).collect();
+        let count = keys.len();
+
+        let result = self
+            .blocking(move |database, table| {
+                let read = database.begin_read()?;
+                let table = read.open_table(table)?;
+
+                encoded
+                    .iter()
+                    .map(|key| Ok(table.get(key.as_slice())?.is_some()))
+                    .collect()
+            })
+            .await;
+
+        result.unwrap_or_else(|err| {
+            tracing::warn!(%err, "failed to look up keys in the embedded store");
+            vec![false; count]
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        let encoded = encode_key(key);
+
+        let bytes = self
+            .blocking(move |database, table| {
+                let read = database.begin_read()?;
+                let table = read.open_table(table)?;
+                let value = table.get(encoded.as_slice())?;
+
+                Ok(value.map(|value| Bytes::copy_from_slice(value.value())))
+            })
+            .await?
+            .ok_or_else(|| Error::DigestInfoNotFound(key.hash()))?;
+
+        Ok(slice_chunk(&bytes, offset, limit))
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        self.write_entries(vec![(key, bytes)]).await
+    }
+
+    /// Writes every entry in a single write transaction, falling back to one transaction per
+    /// entry to tell which ones failed
+    #[instrument(skip(self, entries))]
+    async fn put_many(&self, entries: Vec<(DigestInfo, Bytes)>) -> Vec<Result<(), Error>> {
+        let count = entries.len();
+
+        match self.write_entries(entries.clone()).await {
+            Ok(()) => (0..count).map(|_| Ok(())).collect(),
+            Err(err) => {
+                tracing::warn!(%err, "failed to write a batch of entries, writing them one by one");
+
+                let mut results = Vec::with_capacity(count);
+                for (key, bytes) in entries {
+                    results.push(self.put(key, bytes).await);
+                }
+
+                results
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        let encoded = encode_key(key);
+
+        self.write(move |tables| tables.remove(&encoded)).await
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        let encoded = encode_key(key);
+
+        self.blocking(move |database, table| {
+            let read = database.begin_read()?;
+            let table = read.open_table(table)?;
+            let value = table.get(encoded.as_slice())?;
+
+            Ok(value.map(|value| value.value().len()))
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    #[instrument(skip(self))]
+    async fn list_keys(&self) -> Result<Vec<DigestInfo>, Error> {
+        self.blocking(move |database, table| {
+            let read = database.begin_read()?;
+            let table = read.open_table(table)?;
+
+            let mut keys = Vec::new();
+            for entry in table.iter()? {
+                let (key, _) = entry?;
+                keys.extend(decode_key(key.value()));
+            }
+
+            Ok(keys)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        let tables = self.tables.clone();
+
+        self.blocking(move |database, _| {
+            let write = database.begin_write()?;
+            write.delete_table(Table::new(&tables.entries))?;
+            write.delete_table(SequenceTable::new(&tables.sequences))?;
+            write.delete_table(OrderTable::new(&tables.order))?;
+            write.delete_table(MetadataTable::new(&tables.metadata))?;
+            WriteTables::open(&write, &tables)?;
+            write.commit()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Makes every write so far durable
+    #[instrument(skip(self))]
+    async fn flush(&self) {
+        let result = self
+            .blocking(move |database, _| {
+                let mut write = database.begin_write()?;
+                write.set_durability(Durability::Immediate);
+                write.commit()?;
+
+                Ok(())
+            })
+            .await;
+
+        if let Err(err) = result {
+            tracing::error!(%err, "failed to flush the embedded store");
+        }
+    }
+
+    fn stats(&self) -> StoreStats {
+        // stats are expected to be cheap, so the database is read from the calling thread
+        let stats = (|| -> Result<(u64, u64), Error> {
+            let read = self.database.begin_read()?;
+            let table = read.open_table(Table::new(&self.table))?;
+
+            Ok((table.len()?, table.stats()?.stored_bytes()))
+        })();
+
+        let (entry_count, size_bytes) = match stats {
+            Ok((entry_count, size_bytes)) => (Some(entry_count), Some(size_bytes)),
+            Err(err) => {
+                tracing::warn!(%err, table = %self.table, "failed to read embedded store stats");
+                (None, None)
+            }
+        };
+
+        StoreStats {
+            kind: "embedded",
+            entry_count,
+            size_bytes,
+            tiers: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redb::backends::InMemoryBackend;
+
+    use super::*;
+
+    fn store(max_bytes: u64) -> EmbeddedStore {
+        let database = Database::builder()
+            .create_with_backend(InMemoryBackend::new())
+            .unwrap();
+
+        EmbeddedStore::new(Arc::new(database), "cas/", max_bytes).unwrap()
+    }
+
+    async fn put(store: &EmbeddedStore, data: &'static [u8]) -> DigestInfo {
+        let key = DigestInfo::compute(data);
+        store
+            .put(key.clone(), Bytes::from_static(data))
+            .await
+            .unwrap();
+
+        key
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_entries_once_over_the_limit() {
+        let store = store(8);
+        let first = put(&store, b"1111").await;
+        let second = put(&store, b"2222").await;
+        // rewriting an entry makes it the newest
+        put(&store, b"1111").await;
+
+        let third = put(&store, b"3333").await;
+
+        assert!(store.contains_key(&first).await);
+        assert!(!store.contains_key(&second).await);
+        assert!(store.contains_key(&third).await);
+    }
+
+    #[tokio::test]
+    async fn removed_entries_free_their_bytes() {
+        let store = store(8);
+        let first = put(&store, b"1111").await;
+        let second = put(&store, b"2222").await;
+
+        store.remove(&second).await.unwrap();
+        let third = put(&store, b"3333").await;
+
+        assert!(store.contains_key(&first).await);
+        assert!(store.contains_key(&third).await);
+    }
+}
@@ -0,0 +1,443 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use moka::sync::Cache;
+use tracing::instrument;
+
+use super::{Store, StoreKind, StoreStats};
+use crate::{domain::DigestInfo, errors::Error};
+
+/// Most digests a negative cache remembers at once
+const NEGATIVE_CACHE_CAPACITY: u64 = 1_000_000;
+
+/// Bloom filter over digests. Digests are already uniformly distributed hashes, so the bit
+/// positions are derived from the digest itself rather than from hashing it again
+struct BloomFilter {
+    bits: Vec<AtomicU64>,
+    hash_count: u64,
+    /// Distinct digests inserted, as far as the filter can tell them apart
+    entries: AtomicU64,
+}
+
+impl BloomFilter {
+    /// Creates a filter answering with at most `false_positive_rate` false positives once holding
+    /// `expected_entries` digests
+    fn new(expected_entries: u64, false_positive_rate: f64) -> Self {
+        let expected_entries = expected_entries.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+
+        let bit_count = (-expected_entries * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let hash_count = (bit_count / expected_entries * ln2).round().max(1.0);
+        let word_count = (bit_count as u64).div_ceil(64).max(1);
+
+        Self {
+            bits: (0..word_count).map(|_| AtomicU64::new(0)).collect(),
+            hash_count: hash_count as u64,
+            entries: AtomicU64::new(0),
+        }
+    }
+
+    /// Bit positions of `key`, using double hashing over two halves of its hash
+    fn positions<'a>(&'a self, key: &DigestInfo) -> impl Iterator<Item = u64> + 'a {
+        let mut first = [0; 8];
+        let mut second = [0; 8];
+        first.copy_from_slice(&key.packed_hash[..8]);
+        second.copy_from_slice(&key.packed_hash[8..16]);
+
+        let first = u64::from_le_bytes(first);
+        let second = u64::from_le_bytes(second) | 1;
+        let bit_count = self.bits.len() as u64 * 64;
+
+        (0..self.hash_count)
+            .map(move |index| first.wrapping_add(index.wrapping_mul(second)) % bit_count)
+    }
+
+    fn insert(&self, key: &DigestInfo) {
+        let mut is_new = false;
+        for position in self.positions(key) {
+            let bit = 1 << (position % 64);
+            let word = self.bits[(position / 64) as usize].fetch_or(bit, Ordering::Relaxed);
+            is_new |= word & bit == 0;
+        }
+
+        if is_new {
+            self.entries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// `false` when `key` was never inserted, `true` when it probably was
+    fn may_contain(&self, key: &DigestInfo) -> bool {
+        self.positions(key).all(|position| {
+            self.bits[(position / 64) as usize].load(Ordering::Relaxed) & (1 << (position % 64))
+                != 0
+        })
+    }
+
+    /// Share of the digests never inserted the filter is expected to report as probably inserted
+    fn false_positive_rate(&self) -> f64 {
+        let bit_count = (self.bits.len() * 64) as f64;
+        let hash_count = self.hash_count as f64;
+        let entries = self.entries.load(Ordering::Relaxed) as f64;
+
+        (1.0 - (-hash_count * entries / bit_count).exp()).powf(hash_count)
+    }
+}
+
+/// Bloom filter of an index, replaced by a filter of the keys the wrapped store holds once it gets
+/// too full, because the store got more entries than the filter was sized for, or because keys
+/// the store evicted or removed since are still in the filter
+struct IndexFilter {
+    options: BloomFilterOptions,
+    /// Filter in use, and the filter being rebuilt, which gets every write as well so that it
+    /// misses none of the keys written while the wrapped store's keys are listed
+    filters: RwLock<(Arc<BloomFilter>, Option<Arc<BloomFilter>>)>,
+}
+
+impl IndexFilter {
+    fn new(options: BloomFilterOptions) -> Self {
+        let filter = BloomFilter::new(options.expected_entries, options.false_positive_rate);
+
+        Self {
+            options,
+            filters: RwLock::new((Arc::new(filter), None)),
+        }
+    }
+
+    fn insert(&self, key: &DigestInfo) {
+        let filters = self.filters.read().unwrap();
+        filters.0.insert(key);
+        if let Some(next) = &filters.1 {
+            next.insert(key);
+        }
+    }
+
+    fn may_contain(&self, key: &DigestInfo) -> bool {
+        self.filters.read().unwrap().0.may_contain(key)
+    }
+
+    /// Whether the filter in use lets through more than twice the configured rate of false
+    /// positives, with no rebuild under way
+    fn needs_rebuild(&self) -> bool {
+        let filters = self.filters.read().unwrap();
+
+        filters.1.is_none()
+            && filters.0.false_positive_rate() > 2.0 * self.options.false_positive_rate
+    }
+
+    /// Replaces the filter in use by a filter of the keys of `store`, sized for twice as many
+    /// entries as the filter in use holds, returning the number of keys. Returns `None` when a
+    /// rebuild is already under way
+    async fn rebuild(&self, store: &StoreKind) -> Result<Option<usize>, Error> {
+        let next = {
+            let mut filters = self.filters.write().unwrap();
+            if filters.1.is_some() {
+                return Ok(None);
+            }
+
+            let entries = filters.0.entries.load(Ordering::Relaxed);
+            let next = Arc::new(BloomFilter::new(
+                self.options.expected_entries.max(2 * entries),
+                self.options.false_positive_rate,
+            ));
+            filters.1 = Some(next.clone());
+
+            next
+        };
+
+        let keys = match store.list_keys().await {
+            Ok(keys) => keys,
+            Err(err) => {
+                self.filters.write().unwrap().1 = None;
+                return Err(err);
+            }
+        };
+        for key in &keys {
+            next.insert(key);
+        }
+
+        let mut filters = self.filters.write().unwrap();
+        *filters = (next, None);
+
+        Ok(Some(keys.len()))
+    }
+}
+
+/// Sizing of the bloom filter of an [`ExistenceIndexStore`]
+#[derive(Debug, Clone, Copy)]
+pub struct BloomFilterOptions {
+    pub expected_entries: u64,
+    pub false_positive_rate: f64,
+}
+
+/// Store answering most membership checks without asking the store it wraps.
+///
+/// Digests recently found to be missing are remembered for a short while, and answered as missing
+/// right away. When every write of the wrapped store goes through this one, a bloom filter of
+/// every key ever written also answers as missing the digests that were never written. The filter
+/// cannot forget keys, so it is rebuilt from the keys of the wrapped store once the keys removed
+/// from the store, or written past the entries it was sized for, make it too inaccurate.
+#[derive(Clone)]
+pub struct ExistenceIndexStore {
+    inner: Arc<StoreKind>,
+    filter: Option<Arc<IndexFilter>>,
+    missing: Cache<DigestInfo, ()>,
+}
+
+impl Debug for ExistenceIndexStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExistenceIndexStore")
+            .field("inner", &self.inner)
+            .field("bloom_filter", &self.filter.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ExistenceIndexStore {
+    /// Creates an index remembering missing digests for `negative_ttl`. `bloom_filter` must only
+    /// be given when nothing else writes to `inner`, or digests written elsewhere would be
+    /// reported as missing
+    pub fn new(
+        inner: Arc<StoreKind>,
+        negative_ttl: Duration,
+        bloom_filter: Option<BloomFilterOptions>,
+    ) -> Self {
+        let filter = bloom_filter.map(|options| Arc::new(IndexFilter::new(options)));
+
+        Self {
+            inner,
+            filter,
+            missing: Cache::builder()
+                .max_capacity(NEGATIVE_CACHE_CAPACITY)
+                .time_to_live(negative_ttl)
+                .build(),
+        }
+    }
+
+    /// Fills the bloom filter with the keys already in the wrapped store. Until then, keys
+    /// written before startup would be reported as missing
+    pub async fn populate(&self) -> Result<(), Error> {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return Ok(()),
+        };
+
+        if let Some(entries) = filter.rebuild(&self.inner).await? {
+            tracing::info!(entries, "populated existence index");
+        }
+
+        Ok(())
+    }
+
+    /// Rebuilds the bloom filter in the background when it got too inaccurate
+    fn rebuild_if_needed(&self) {
+        let filter = match &self.filter {
+            Some(filter) if filter.needs_rebuild() => filter.clone(),
+            _ => return,
+        };
+        let inner = self.inner.clone();
+
+        tokio::spawn(async move {
+            match filter.rebuild(&inner).await {
+                Ok(Some(entries)) => tracing::info!(entries, "rebuilt existence index"),
+                Ok(None) => {}
+                Err(err) => tracing::warn!(%err, "failed to rebuild existence index"),
+            }
+        });
+    }
+
+    /// Whether `key` is known to be missing, without asking the wrapped store
+    fn known_missing(&self, key: &DigestInfo) -> bool {
+        self.missing.contains_key(key)
+            || self
+                .filter
+                .as_ref()
+                .is_some_and(|filter| !filter.may_contain(key))
+    }
+}
+
+#[async_trait]
+impl Store for ExistenceIndexStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        if self.known_missing(key) {
+            return false;
+        }
+
+        let present = self.inner.contains_key(key).await;
+        if !present {
+            self.missing.insert(key.clone(), ());
+        }
+
+        present
+    }
+
+    /// Only asks the wrapped store about the keys not known to be missing, in a single batch
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        let mut present = vec![false; keys.len()];
+
+        let (positions, unknown): (Vec<usize>, Vec<DigestInfo>) = keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| !self.known_missing(key))
+            .map(|(position, key)| (position, key.clone()))
+            .unzip();
+
+        if !unknown.is_empty() {
+            let answers = self.inner.contains_keys(&unknown).await;
+
+            for ((position, key), found) in positions.into_iter().zip(unknown).zip(answers) {
+                if found {
+                    present[position] = true;
+                } else {
+                    self.missing.insert(key, ());
+                }
+            }
+        }
+
+        present
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        !self.known_missing(key) && self.inner.touch(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        if self.known_missing(key) {
+            return Err(Error::DigestInfoNotFound(key.hash()));
+        }
+
+        self.inner.get_chunk(key, offset, limit).await
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        // the filter is updated first, so that the entry is never reported missing once written
+        if let Some(filter) = &self.filter {
+            filter.insert(&key);
+        }
+
+        let result = self.inner.put(key.clone(), bytes).await;
+        self.missing.invalidate(&key);
+
+        // and again after, for a rebuild listing the keys of the store before the entry was
+        // written not to miss it
+        if let Some(filter) = &self.filter {
+            filter.insert(&key);
+            self.rebuild_if_needed();
+        }
+
+        result
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        self.inner.remove(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        if self.known_missing(key) {
+            return None;
+        }
+
+        self.inner.size_of(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_keys(&self) -> Result<Vec<DigestInfo>, Error> {
+        self.inner.list_keys().await
+    }
+
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        self.inner.clear().await
+    }
+
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        vec![self.inner.clone()]
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats::layered("existence_index", &self.tiers())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::memory::MemoryStore;
+
+    fn keys(count: u32) -> Vec<DigestInfo> {
+        (0..count)
+            .map(|key| DigestInfo::compute(&key.to_le_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn bloom_filter_has_no_false_negatives() {
+        // sized for far fewer entries than it gets, to have its bits collide
+        let filter = BloomFilter::new(100, 0.01);
+        let keys = keys(10_000);
+
+        for key in &keys {
+            filter.insert(key);
+        }
+
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+        assert!(filter.false_positive_rate() > 0.02);
+    }
+
+    #[tokio::test]
+    async fn puts_invalidate_the_negative_cache() {
+        let inner = Arc::new(StoreKind::from(MemoryStore::new(1024)));
+        let index = ExistenceIndexStore::new(inner, Duration::from_secs(60), None);
+        let bytes = Bytes::from_static(b"blob");
+        let key = DigestInfo::compute(&bytes);
+
+        assert!(!index.contains_key(&key).await);
+        index.put(key.clone(), bytes).await.unwrap();
+
+        assert!(index.contains_key(&key).await);
+    }
+
+    #[tokio::test]
+    async fn rebuilds_forget_the_removed_keys() {
+        let inner = Arc::new(StoreKind::from(MemoryStore::new(1024)));
+        let filter = IndexFilter::new(BloomFilterOptions {
+            expected_entries: 100,
+            false_positive_rate: 0.01,
+        });
+        let keys = keys(100);
+        for key in &keys {
+            filter.insert(key);
+        }
+        inner.put(keys[0].clone(), Bytes::new()).await.unwrap();
+
+        assert_eq!(filter.rebuild(&inner).await.unwrap(), Some(1));
+
+        assert!(filter.may_contain(&keys[0]));
+        assert!(
+            keys[1..]
+                .iter()
+                .filter(|key| filter.may_contain(key))
+                .count()
+                < 10
+        );
+    }
+}
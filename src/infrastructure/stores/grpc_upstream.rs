@@ -0,0 +1,388 @@
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use prost::Message;
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    transport::Channel,
+    Request,
+};
+use tracing::instrument;
+
+use super::{slice_chunk, Store, StoreStats};
+use crate::{
+    domain::{DigestInfo, ResourceName},
+    errors::Error,
+    protos::{
+        build::bazel::remote::execution::v2::{
+            action_cache_client::ActionCacheClient,
+            content_addressable_storage_client::ContentAddressableStorageClient, ActionResult,
+            Digest, FindMissingBlobsRequest, GetActionResultRequest, UpdateActionResultRequest,
+        },
+        google::bytestream::{byte_stream_client::ByteStreamClient, ReadRequest, WriteRequest},
+    },
+    replication::REPLICATED_HEADER,
+};
+
+/// Size of the chunks blobs are uploaded in over ByteStream
+const WRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Splits `data` into the requests of a ByteStream `Write` call
+pub(crate) fn chunked_write_requests(resource_name: String, data: &Bytes) -> Vec<WriteRequest> {
+    let mut requests = Vec::new();
+    let mut write_offset = 0;
+
+    loop {
+        let chunk = data.slice(write_offset..data.len().min(write_offset + WRITE_CHUNK_SIZE));
+        let finish_write = write_offset + chunk.len() == data.len();
+
+        requests.push(WriteRequest {
+            // only the first request needs to carry the resource name
+            resource_name: if write_offset == 0 {
+                resource_name.clone()
+            } else {
+                String::new()
+            },
+            write_offset: write_offset as i64,
+            finish_write,
+            data: chunk.to_vec(),
+        });
+
+        write_offset += chunk.len();
+        if finish_write {
+            return requests;
+        }
+    }
+}
+
+/// Which of the remote server's APIs the entries of a [`GrpcUpstreamStore`] live in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteApi {
+    /// Entries are blobs, accessed through the CAS and ByteStream services
+    ContentAddressableStorage,
+    /// Entries are encoded `ActionResult`s, keyed by action digest
+    ActionCache,
+}
+
+/// How a [`GrpcUpstreamStore`] talks to the remote server
+#[derive(Debug, Clone)]
+pub struct GrpcUpstreamOptions {
+    /// Number of connections opened to the server, requests being spread across them in turn
+    pub connections: usize,
+    /// Deadline of every call, including the time spent connecting. Calls have no deadline when
+    /// unset
+    pub timeout: Option<Duration>,
+    /// Whether the server is a peer replicating this one, which is to answer the calls from its
+    /// local store only, see [`crate::replication`]
+    pub replicated: bool,
+    /// Credential sent as a bearer token on every call. Calls are anonymous when unset
+    pub token: Option<String>,
+}
+
+impl Default for GrpcUpstreamOptions {
+    fn default() -> Self {
+        Self {
+            connections: 1,
+            timeout: None,
+            replicated: false,
+            token: None,
+        }
+    }
+}
+
+/// Store backed by another REAPI server, such as a peer bache node or a central cache.
+///
+/// Requests are sent under `instance_name`, which does not need to be the instance name the
+/// store is served under locally.
+#[derive(Clone)]
+pub struct GrpcUpstreamStore {
+    endpoint: String,
+    channels: Vec<Channel>,
+    next_channel: Arc<AtomicUsize>,
+    instance_name: String,
+    api: RemoteApi,
+    timeout: Option<Duration>,
+    replicated: bool,
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl Debug for GrpcUpstreamStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GrpcUpstreamStore")
+            .field("endpoint", &self.endpoint)
+            .field("instance_name", &self.instance_name)
+            .field("api", &self.api)
+            .field("connections", &self.channels.len())
+            .field("timeout", &self.timeout)
+            .field("replicated", &self.replicated)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GrpcUpstreamStore {
+    /// Creates a store sending requests for `instance_name` to `endpoint`, over a single
+    /// connection and without deadlines. Connecting happens lazily, so an unreachable server only
+    /// fails the requests made to it
+    pub fn new(endpoint: &str, instance_name: String, api: RemoteApi) -> Result<Self, Error> {
+        Self::with_options(endpoint, instance_name, api, GrpcUpstreamOptions::default())
+    }
+
+    pub fn with_options(
+        endpoint: &str,
+        instance_name: String,
+        api: RemoteApi,
+        options: GrpcUpstreamOptions,
+    ) -> Result<Self, Error> {
+        let mut channel_endpoint = Channel::from_shared(endpoint.to_string())
+            .map_err(|_| Error::InvalidEndpoint(endpoint.to_string()))?;
+        let authorization = options
+            .token
+            .map(|token| format!("Bearer {token}").parse())
+            .transpose()
+            .map_err(|_| {
+                Error::InvalidStoreConfig(format!("the credential of `{endpoint}` is not valid"))
+            })?;
+        if let Some(timeout) = options.timeout {
+            channel_endpoint = channel_endpoint.connect_timeout(timeout);
+        }
+
+        // every `Channel` is its own HTTP/2 connection, while clones of one share it
+        let channels = (0..options.connections.max(1))
+            .map(|_| channel_endpoint.connect_lazy())
+            .collect();
+
+        Ok(Self {
+            endpoint: endpoint.to_string(),
+            channels,
+            next_channel: Arc::new(AtomicUsize::new(0)),
+            instance_name,
+            api,
+            timeout: options.timeout,
+            replicated: options.replicated,
+            authorization,
+        })
+    }
+
+    /// Picks the connection of the next call, going through all of them in turn
+    fn channel(&self) -> Channel {
+        let index = self.next_channel.fetch_add(1, Ordering::Relaxed) % self.channels.len();
+
+        self.channels[index].clone()
+    }
+
+    /// Wraps `message` into a request carrying the deadline of the store, which both ends
+    /// enforce, its credential, and the replication marker when sent to a peer
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(timeout) = self.timeout {
+            request.set_timeout(timeout);
+        }
+        if self.replicated {
+            request
+                .metadata_mut()
+                .insert(REPLICATED_HEADER, MetadataValue::from_static("1"));
+        }
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+
+        request
+    }
+
+    fn blob_resource_name(&self, key: &DigestInfo) -> String {
+        ResourceName::blob(self.instance_name.as_str().into(), key).to_string()
+    }
+
+    fn upload_resource_name(&self, key: &DigestInfo) -> String {
+        ResourceName::upload(self.instance_name.as_str().into(), key).to_string()
+    }
+
+    async fn get_action_result(&self, key: &DigestInfo) -> Result<ActionResult, Error> {
+        let mut client = ActionCacheClient::new(self.channel());
+
+        client
+            .get_action_result(self.request(GetActionResultRequest {
+                instance_name: self.instance_name.clone(),
+                action_digest: Some(Digest::from(key.clone())),
+                ..Default::default()
+            }))
+            .await
+            .map(|response| response.into_inner())
+            .map_err(|status| Error::from_remote_status(status, key))
+    }
+
+    async fn read_blob(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        let mut client = ByteStreamClient::new(self.channel());
+
+        let mut responses = client
+            .read(self.request(ReadRequest {
+                resource_name: self.blob_resource_name(key),
+                read_offset: offset as i64,
+                // a `read_limit` of 0 means there is no limit
+                read_limit: limit.try_into().unwrap_or(0),
+            }))
+            .await
+            .map_err(|status| Error::from_remote_status(status, key))?
+            .into_inner();
+
+        let mut bytes = BytesMut::new();
+        while let Some(response) = responses
+            .message()
+            .await
+            .map_err(|status| Error::from_remote_status(status, key))?
+        {
+            bytes.extend_from_slice(&response.data);
+        }
+
+        Ok(bytes.freeze())
+    }
+}
+
+#[async_trait]
+impl Store for GrpcUpstreamStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        let result = match self.api {
+            RemoteApi::ContentAddressableStorage => {
+                let mut client = ContentAddressableStorageClient::new(self.channel());
+
+                client
+                    .find_missing_blobs(self.request(FindMissingBlobsRequest {
+                        instance_name: self.instance_name.clone(),
+                        blob_digests: vec![key.clone().into()],
+                    }))
+                    .await
+                    .map(|response| response.into_inner().missing_blob_digests.is_empty())
+                    .map_err(|status| Error::from_remote_status(status, key))
+            }
+            RemoteApi::ActionCache => match self.get_action_result(key).await {
+                Ok(_) => Ok(true),
+                Err(Error::DigestInfoNotFound(_)) => Ok(false),
+                Err(err) => Err(err),
+            },
+        };
+
+        result.unwrap_or_else(|err| {
+            tracing::warn!(%err, endpoint = %self.endpoint, "failed to look up digest");
+            false
+        })
+    }
+
+    /// Asks about every blob in a single round trip, instead of once per key
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        if self.api == RemoteApi::ActionCache {
+            return futures::future::join_all(keys.iter().map(|key| self.contains_key(key))).await;
+        }
+
+        let mut client = ContentAddressableStorageClient::new(self.channel());
+        let response = client
+            .find_missing_blobs(self.request(FindMissingBlobsRequest {
+                instance_name: self.instance_name.clone(),
+                blob_digests: keys.iter().cloned().map(Digest::from).collect(),
+            }))
+            .await;
+
+        match response {
+            Ok(response) => {
+                let missing: HashSet<DigestInfo> = response
+                    .into_inner()
+                    .missing_blob_digests
+                    .into_iter()
+                    .filter_map(|digest| DigestInfo::try_from(digest).ok())
+                    .collect();
+
+                keys.iter().map(|key| !missing.contains(key)).collect()
+            }
+            Err(status) => {
+                tracing::warn!(%status, endpoint = %self.endpoint, "failed to look up digests");
+                vec![false; keys.len()]
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        match self.api {
+            RemoteApi::ContentAddressableStorage => self.read_blob(key, offset, limit).await,
+            RemoteApi::ActionCache => {
+                let bytes = Bytes::from(self.get_action_result(key).await?.encode_to_vec());
+
+                Ok(slice_chunk(&bytes, offset, limit))
+            }
+        }
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        match self.api {
+            RemoteApi::ContentAddressableStorage => {
+                let mut client = ByteStreamClient::new(self.channel());
+                let requests = chunked_write_requests(self.upload_resource_name(&key), &bytes);
+
+                client
+                    .write(self.request(tokio_stream::iter(requests)))
+                    .await
+                    .map_err(|status| Error::from_remote_status(status, &key))?;
+            }
+            RemoteApi::ActionCache => {
+                let mut client = ActionCacheClient::new(self.channel());
+                let action_result = ActionResult::decode(bytes)?;
+
+                client
+                    .update_action_result(self.request(UpdateActionResultRequest {
+                        instance_name: self.instance_name.clone(),
+                        action_digest: Some(Digest::from(key.clone())),
+                        action_result: Some(action_result),
+                        ..Default::default()
+                    }))
+                    .await
+                    .map_err(|status| Error::from_remote_status(status, &key))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove(&self, _key: &DigestInfo) -> Result<(), Error> {
+        Err(Error::UnsupportedOperation(
+            "the remote execution API has no way of removing entries",
+        ))
+    }
+
+    async fn clear(&self) -> Result<(), Error> {
+        Err(Error::UnsupportedOperation(
+            "the remote execution API has no way of removing entries",
+        ))
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            kind: "grpc_upstream",
+            entry_count: None,
+            size_bytes: None,
+            tiers: Vec::new(),
+        }
+    }
+}
@@ -1,21 +1,388 @@
-use std::fmt::Debug;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use moka::future::Cache;
+use moka::future::{Cache, ConcurrentCacheExt};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    sync::mpsc::UnboundedSender,
+};
 use tracing::instrument;
 
-use super::Store;
+use super::{slice_chunk, Store, StoreKind, StoreStats};
 use crate::{domain::DigestInfo, errors::Error};
 
+/// First bytes of a snapshot file, ending with the version of the format
+const SNAPSHOT_MAGIC: &[u8; 8] = b"BACHEMS2";
+
+/// First bytes of the snapshot files written before entries could be prioritized, which are
+/// still restored
+const SNAPSHOT_MAGIC_V1: &[u8; 8] = b"BACHEMS1";
+
+/// Blob along with when it was last used, as a tick of the clock of its store
+#[derive(Clone)]
+struct Entry {
+    bytes: Bytes,
+    last_used: Arc<AtomicU64>,
+}
+
+/// Size of every entry, along with the `last_used` of the entry it was indexed for, which tells
+/// apart the entries successively written under a key. Sizes are read from it rather than from
+/// the caches, where any read counts as an access for the eviction policy
+#[derive(Default)]
+struct SizeIndex {
+    entries: Mutex<HashMap<DigestInfo, (usize, Arc<AtomicU64>)>>,
+}
+
+impl SizeIndex {
+    fn insert(&self, key: DigestInfo, entry: &Entry) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, (entry.bytes.len(), entry.last_used.clone()));
+    }
+
+    fn get(&self, key: &DigestInfo) -> Option<usize> {
+        self.entries.lock().unwrap().get(key).map(|(size, _)| *size)
+    }
+
+    fn remove(&self, key: &DigestInfo) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// Forgets `key` when it is still indexed for `entry`, and not for an entry written since
+    fn remove_entry(&self, key: &DigestInfo, entry: &Entry) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries
+            .get(key)
+            .is_some_and(|(_, last_used)| Arc::ptr_eq(last_used, &entry.last_used))
+        {
+            entries.remove(key);
+        }
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Where the store is snapshotted to, see [`MemoryStore::with_snapshot`]
+struct Snapshot {
+    path: PathBuf,
+    /// Whether the previous snapshot was restored. Until then the store only holds part of it,
+    /// and writing a new snapshot would throw the rest away
+    restored: AtomicBool,
+}
+
 #[derive(Clone)]
 pub struct MemoryStore {
-    cache: Cache<DigestInfo, Bytes>,
+    cache: Cache<DigestInfo, Entry>,
+    /// Prioritized entries, kept apart so that they are only evicted to make room for each
+    /// other, see [`MemoryStore::with_retained_capacity`]
+    retained: Option<Cache<DigestInfo, Entry>>,
+    evictions: Option<UnboundedSender<DigestInfo>>,
+    sizes: Arc<SizeIndex>,
+    clock: Arc<AtomicU64>,
+    snapshot: Option<Arc<Snapshot>>,
 }
 
 impl Debug for MemoryStore {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("MemoryStore").finish_non_exhaustive()
+        f.debug_struct("MemoryStore")
+            .field(
+                "snapshot",
+                &self.snapshot.as_ref().map(|snapshot| &snapshot.path),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl MemoryStore {
+    /// Creates a store holding at most `max_capacity` bytes of blobs
+    pub fn new(max_capacity: u64) -> Self {
+        let sizes = Arc::default();
+
+        Self::from_cache(Self::build_cache(max_capacity, &sizes, None), sizes, None)
+    }
+
+    /// Creates a store holding at most `max_capacity` bytes of blobs, which sends the key of every
+    /// entry it evicts (either for size or expiry) to `evictions`
+    pub fn with_eviction_listener(
+        max_capacity: u64,
+        evictions: UnboundedSender<DigestInfo>,
+    ) -> Self {
+        let sizes = Arc::default();
+        let cache = Self::build_cache(max_capacity, &sizes, Some(evictions.clone()));
+
+        Self::from_cache(cache, sizes, Some(evictions))
+    }
+
+    /// Keeps up to `max_capacity` more bytes of prioritized entries, see [`Store::prioritize`],
+    /// which are only evicted to make room for each other. Their evictions are sent to the
+    /// eviction listener too, if there is one
+    pub fn with_retained_capacity(mut self, max_capacity: u64) -> Self {
+        if max_capacity > 0 {
+            self.retained = Some(Self::build_cache(
+                max_capacity,
+                &self.sizes,
+                self.evictions.clone(),
+            ));
+        }
+
+        self
+    }
+
+    /// Snapshots the store to `path` when flushed, to be restored on the next start with
+    /// [`MemoryStore::restore_snapshot`]
+    pub fn with_snapshot(mut self, path: PathBuf) -> Self {
+        self.snapshot = Some(Arc::new(Snapshot {
+            path,
+            restored: AtomicBool::new(false),
+        }));
+
+        self
+    }
+
+    fn from_cache(
+        cache: Cache<DigestInfo, Entry>,
+        sizes: Arc<SizeIndex>,
+        evictions: Option<UnboundedSender<DigestInfo>>,
+    ) -> Self {
+        Self {
+            cache,
+            retained: None,
+            evictions,
+            sizes,
+            clock: Arc::default(),
+            snapshot: None,
+        }
+    }
+
+    /// Builds a cache holding at most `max_capacity` bytes of blobs, which drops every entry it
+    /// evicts (either for size or expiry) from `sizes` and sends its key to `evictions`
+    fn build_cache(
+        max_capacity: u64,
+        sizes: &Arc<SizeIndex>,
+        evictions: Option<UnboundedSender<DigestInfo>>,
+    ) -> Cache<DigestInfo, Entry> {
+        let sizes = sizes.clone();
+
+        Cache::builder()
+            .max_capacity(max_capacity)
+            .weigher(|_, entry: &Entry| entry.bytes.len().try_into().unwrap_or(u32::MAX))
+            .eviction_listener_with_queued_delivery_mode(
+                move |key: Arc<DigestInfo>, entry: Entry, cause| {
+                    if !cause.was_evicted() {
+                        return;
+                    }
+
+                    sizes.remove_entry(&key, &entry);
+                    if let Some(evictions) = &evictions {
+                        // the receiving end only goes away on shutdown, at which point nobody
+                        // cares about evictions anymore
+                        let _ = evictions.send(key.as_ref().clone());
+                    }
+                },
+            )
+            .build()
+    }
+
+    /// Both caches, the prioritized entries first
+    fn caches(&self) -> impl Iterator<Item = &Cache<DigestInfo, Entry>> {
+        self.retained.iter().chain(std::iter::once(&self.cache))
+    }
+
+    fn is_retained(&self, key: &DigestInfo) -> bool {
+        self.retained
+            .as_ref()
+            .is_some_and(|retained| retained.contains_key(key))
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Reads `key`, counting it as used
+    fn get_entry(&self, key: &DigestInfo) -> Option<Bytes> {
+        let entry = self.caches().find_map(|cache| cache.get(key))?;
+        entry.last_used.store(self.tick(), Ordering::Relaxed);
+
+        Some(entry.bytes)
+    }
+
+    /// Writes every entry to the snapshot file, from the least to the most recently used. The
+    /// previous snapshot is only replaced once the new one is complete
+    pub async fn write_snapshot(&self) -> Result<(), Error> {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        };
+
+        if !snapshot.restored.load(Ordering::SeqCst) {
+            tracing::warn!(
+                path = %snapshot.path.display(),
+                "previous snapshot is not restored yet, not replacing it"
+            );
+
+            return Ok(());
+        }
+
+        let mut entries: Vec<_> = self
+            .caches()
+            .enumerate()
+            .flat_map(|(index, cache)| {
+                let retained = self.retained.is_some() && index == 0;
+
+                cache.iter().map(move |(key, entry)| {
+                    (
+                        entry.last_used.load(Ordering::Relaxed),
+                        key,
+                        retained,
+                        entry.bytes,
+                    )
+                })
+            })
+            .collect();
+        entries.sort_unstable_by_key(|(last_used, ..)| *last_used);
+
+        let partial_path = snapshot.path.with_extension("partial");
+        let mut file = BufWriter::new(File::create(&partial_path).await?);
+
+        file.write_all(SNAPSHOT_MAGIC).await?;
+        for (_, key, retained, bytes) in &entries {
+            file.write_all(&key.packed_hash).await?;
+            file.write_i64_le(key.size_bytes).await?;
+            file.write_u8(u8::from(*retained)).await?;
+            file.write_u64_le(bytes.len() as u64).await?;
+            file.write_all(bytes).await?;
+        }
+        file.flush().await?;
+        file.into_inner().sync_all().await?;
+
+        tokio::fs::rename(&partial_path, &snapshot.path).await?;
+
+        tracing::info!(
+            path = %snapshot.path.display(),
+            entries = entries.len(),
+            "wrote memory store snapshot"
+        );
+
+        Ok(())
+    }
+
+    /// Puts every entry of the snapshot file into `store`, which is this store or one wrapping
+    /// it, so that the wrapping stores keep track of the entries too. Entries are put from the
+    /// least to the most recently used, restoring their recency, and prioritized again if they
+    /// were. A missing snapshot file is not an error, and neither is a truncated one, whose
+    /// complete entries are kept
+    pub async fn restore_snapshot(&self, store: &StoreKind) -> Result<(), Error> {
+        let snapshot = match &self.snapshot {
+            Some(snapshot) => snapshot,
+            None => return Ok(()),
+        };
+
+        let result = Self::read_snapshot(&snapshot.path, store).await;
+        snapshot.restored.store(true, Ordering::SeqCst);
+
+        match result {
+            Ok(entries) => {
+                tracing::info!(
+                    path = %snapshot.path.display(),
+                    entries,
+                    "restored memory store snapshot"
+                );
+
+                Ok(())
+            }
+            Err(Error::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(Error::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                tracing::warn!(
+                    path = %snapshot.path.display(),
+                    "memory store snapshot is truncated, only restored part of it"
+                );
+
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn read_snapshot(path: &Path, store: &StoreKind) -> Result<usize, Error> {
+        let file = File::open(path).await?;
+        let file_size = file.metadata().await?.len();
+        let mut file = BufReader::new(file);
+
+        let mut magic = [0; SNAPSHOT_MAGIC.len()];
+        file.read_exact(&mut magic).await?;
+        let has_priorities = &magic == SNAPSHOT_MAGIC;
+        if !has_priorities && &magic != SNAPSHOT_MAGIC_V1 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not a memory store snapshot", path.display()),
+            )
+            .into());
+        }
+
+        // lengths are checked against the bytes left before allocating them
+        let entry_header_size = 32 + 8 + u64::from(has_priorities) + 8;
+        let mut position = magic.len() as u64;
+
+        let mut entries = 0;
+        loop {
+            let mut packed_hash = [0; 32];
+            match file.read_exact(&mut packed_hash).await {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+
+            // the size of an ActionCache key is the size of the action, not of its result
+            let size_bytes = file.read_i64_le().await?;
+            let retained = has_priorities && file.read_u8().await? != 0;
+            let length = file.read_u64_le().await?;
+            position += entry_header_size;
+            if length > file_size.saturating_sub(position) {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{} is corrupt, it has an entry of {length} bytes past its end",
+                        path.display()
+                    ),
+                )
+                .into());
+            }
+            position += length;
+
+            let mut bytes = vec![0; length as usize];
+            file.read_exact(&mut bytes).await?;
+
+            let key = DigestInfo::new(packed_hash, size_bytes);
+            // an entry that no longer fits, e.g. in a smaller quota, should not stop the others
+            match store.put(key.clone(), Bytes::from(bytes)).await {
+                Ok(()) => entries += 1,
+                Err(err) => {
+                    tracing::warn!(%err, "failed to restore snapshot entry");
+                    continue;
+                }
+            }
+
+            if retained {
+                store.prioritize(&key).await;
+            }
+        }
+
+        Ok(entries)
     }
 }
 
@@ -23,7 +390,44 @@ impl Debug for MemoryStore {
 impl Store for MemoryStore {
     #[instrument(skip(self))]
     async fn contains_key(&self, key: &DigestInfo) -> bool {
-        self.cache.contains_key(key)
+        self.caches().any(|cache| cache.contains_key(key))
+    }
+
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        keys.iter()
+            .map(|key| self.caches().any(|cache| cache.contains_key(key)))
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        // unlike `contains_key`, reading the entry counts as an access for the eviction policy
+        self.get_entry(key).is_some()
+    }
+
+    /// Moves the entry to the prioritized entries, when the store keeps some apart
+    #[instrument(skip(self))]
+    async fn prioritize(&self, key: &DigestInfo) -> bool {
+        let retained = match &self.retained {
+            Some(retained) => retained,
+            None => return self.get_entry(key).is_some(),
+        };
+
+        if retained.get(key).is_some() {
+            return true;
+        }
+
+        let entry = match self.cache.get(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        retained.insert(key.clone(), entry).await;
+        // invalidated rather than evicted, so it is not reported to the eviction listener
+        self.cache.invalidate(key).await;
+
+        true
     }
 
     #[instrument(skip(self))]
@@ -34,17 +438,113 @@ impl Store for MemoryStore {
         limit: usize,
     ) -> Result<Bytes, Error> {
         let bytes = self
-            .cache
-            .get(key)
+            .get_entry(key)
             .ok_or_else(|| Error::DigestInfoNotFound(key.hash()))?;
 
-        // take the lowest of the limit of bits sent, or, the remaining bytes left
-        let length_bytes_to_send = limit.min(bytes.len() - offset);
+        Ok(slice_chunk(&bytes, offset, limit))
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        let entry = Entry {
+            bytes,
+            last_used: Arc::new(AtomicU64::new(self.tick())),
+        };
+        self.sizes.insert(key.clone(), &entry);
+
+        match &self.retained {
+            // a prioritized entry stays prioritized when written again
+            Some(retained) if self.is_retained(&key) => retained.insert(key, entry).await,
+            _ => self.cache.insert(key, entry).await,
+        }
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        for cache in self.caches() {
+            cache.invalidate(key).await;
+        }
+        self.sizes.remove(key);
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        // looked up without counting as an access, the entry may have been evicted since
+        self.sizes
+            .get(key)
+            .filter(|_| self.caches().any(|cache| cache.contains_key(key)))
+    }
+
+    #[instrument(skip(self))]
+    async fn list_keys(&self) -> Result<Vec<DigestInfo>, Error> {
+        Ok(self
+            .caches()
+            .flat_map(|cache| cache.iter())
+            .map(|(key, _)| key.as_ref().clone())
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        for cache in self.caches() {
+            cache.invalidate_all();
+        }
+        self.sizes.clear();
+
+        Ok(())
+    }
 
-        if length_bytes_to_send > 0 {
-            Ok(bytes.slice(offset..(offset + length_bytes_to_send)))
-        } else {
-            Ok(Bytes::new())
+    #[instrument(skip(self))]
+    async fn compact(&self) {
+        for cache in self.caches() {
+            cache.sync();
         }
     }
+
+    /// Writes a snapshot, when the store has one
+    #[instrument(skip(self))]
+    async fn flush(&self) {
+        if let Err(err) = self.write_snapshot().await {
+            tracing::error!(%err, "failed to write memory store snapshot");
+        }
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            kind: "memory",
+            entry_count: Some(self.caches().map(|cache| cache.entry_count()).sum()),
+            size_bytes: Some(self.caches().map(|cache| cache.weighted_size()).sum()),
+            tiers: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn size_of_follows_writes_and_removals() {
+        let store = MemoryStore::new(1024);
+        let key = DigestInfo::compute(b"data");
+
+        store
+            .put(key.clone(), Bytes::from_static(b"data"))
+            .await
+            .unwrap();
+        assert_eq!(store.size_of(&key).await, Some(4));
+
+        store
+            .put(key.clone(), Bytes::from_static(b"longer data"))
+            .await
+            .unwrap();
+        assert_eq!(store.size_of(&key).await, Some(11));
+
+        store.remove(&key).await.unwrap();
+        assert_eq!(store.size_of(&key).await, None);
+    }
 }
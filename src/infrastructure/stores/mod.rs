@@ -3,53 +3,362 @@ use std::{collections::HashMap, sync::Arc};
 use async_trait::async_trait;
 use bytes::Bytes;
 use enum_dispatch::enum_dispatch;
+use futures::{stream, StreamExt};
 
-use self::memory::MemoryStore;
+use self::{
+    coalescing::CoalescingStore,
+    dedup::DedupStore,
+    embedded::EmbeddedStore,
+    existence_index::ExistenceIndexStore,
+    grpc_upstream::GrpcUpstreamStore,
+    memory::MemoryStore,
+    quota::{QuotaStore, QuotaUsage},
+    redis::RedisStore,
+    reference_tracking::ReferenceTrackingStore,
+    replicated::ReplicatedStore,
+    sharded::ShardedStore,
+    size_partitioned::SizePartitionedStore,
+    tiered::TieredStore,
+};
 use crate::{
-    domain::{DigestInfo, InstanceName},
+    domain::{DigestInfo, InstanceName, InstanceRoute},
     errors::Error,
 };
 
+pub mod coalescing;
+pub mod dedup;
+pub mod embedded;
+pub mod existence_index;
+pub mod grpc_upstream;
 pub mod memory;
+pub mod quota;
+pub mod redis;
+pub mod reference_tracking;
+pub mod replicated;
+pub mod sharded;
+pub mod size_partitioned;
+pub mod tiered;
+
+/// Most keys looked up at once by the default `contains_keys`
+const DEFAULT_LOOKUP_CONCURRENCY: usize = 32;
 
 #[async_trait]
 #[enum_dispatch]
 pub trait Store {
     async fn contains_key(&self, key: &DigestInfo) -> bool;
 
+    /// Batched `contains_key`, the result at every index being the answer for the key at the same
+    /// index of `keys`. Stores able to look up several keys at once (in a single round trip, with
+    /// a multi-get...) should override the default of looking them up one by one
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        // collected first, a lazy iterator would trip up the `Send` check of `async_trait`
+        let lookups: Vec<_> = keys.iter().map(|key| self.contains_key(key)).collect();
+
+        stream::iter(lookups)
+            .buffered(DEFAULT_LOOKUP_CONCURRENCY)
+            .collect()
+            .await
+    }
+
+    /// Like `contains_key`, but also marks the entry as recently used in stores that evict by
+    /// recency
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        self.contains_key(key).await
+    }
+
     async fn get_chunk(
         &self,
         key: &DigestInfo,
         offset: usize,
         limit: usize,
     ) -> Result<Bytes, Error>;
+
+    /// Keeps the entry stored under `key` for longer, only evicting it to make room for other
+    /// prioritized entries, as asked for by a high cache priority. Returns whether the entry was
+    /// found
+    async fn prioritize(&self, key: &DigestInfo) -> bool {
+        let mut found = false;
+        for tier in self.tiers() {
+            found |= tier.prioritize(key).await;
+        }
+
+        found
+    }
+
+    /// Reads the whole entry stored under `key`
+    async fn get(&self, key: &DigestInfo) -> Result<Bytes, Error> {
+        self.get_chunk(key, 0, usize::MAX).await
+    }
+
+    /// Batched `get`, the result at every index being the entry for the key at the same index of
+    /// `keys`
+    async fn get_many(&self, keys: &[DigestInfo]) -> Vec<Result<Bytes, Error>> {
+        futures::future::join_all(keys.iter().map(|key| self.get(key))).await
+    }
+
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error>;
+
+    /// Batched `put`, the result at every index being the outcome of the entry at the same index
+    /// of `entries`
+    async fn put_many(&self, entries: Vec<(DigestInfo, Bytes)>) -> Vec<Result<(), Error>> {
+        futures::future::join_all(entries.into_iter().map(|(key, bytes)| self.put(key, bytes)))
+            .await
+    }
+
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error>;
+
+    /// Size of the entry stored under `key`, if there is one
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        self.get(key).await.ok().map(|bytes| bytes.len())
+    }
+
+    /// Keys of every entry of the store, for stores that can list them
+    async fn list_keys(&self) -> Result<Vec<DigestInfo>, Error> {
+        Err(Error::UnsupportedOperation(
+            "the store cannot list its entries",
+        ))
+    }
+
+    /// Removes every entry of the store
+    async fn clear(&self) -> Result<(), Error>;
+
+    /// Runs any pending maintenance work (evictions, expirations...) right away
+    async fn compact(&self) {
+        for tier in self.tiers() {
+            tier.compact().await;
+        }
+    }
+
+    /// Waits for the writes still done in the background to be over, so that nothing is lost on
+    /// shutdown
+    async fn flush(&self) {
+        for tier in self.tiers() {
+            tier.flush().await;
+        }
+    }
+
+    /// The stores this one is layered over, used to inspect each of them separately
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        Vec::new()
+    }
+
+    fn stats(&self) -> StoreStats;
+}
+
+/// Point in time usage of a store, along with the usage of the stores it is layered over
+#[derive(Debug, Clone)]
+pub struct StoreStats {
+    pub kind: &'static str,
+    /// `None` when the store cannot cheaply count its entries
+    pub entry_count: Option<u64>,
+    /// `None` when the store cannot cheaply sum up the size of its entries
+    pub size_bytes: Option<u64>,
+    pub tiers: Vec<StoreStats>,
+}
+
+impl StoreStats {
+    /// Stats of a store that only forwards to `tiers`, and holds no entries of its own
+    pub fn layered(kind: &'static str, tiers: &[Arc<StoreKind>]) -> Self {
+        Self {
+            kind,
+            entry_count: None,
+            size_bytes: None,
+            tiers: tiers.iter().map(|tier| tier.stats()).collect(),
+        }
+    }
 }
 
 #[enum_dispatch(Store)]
 #[derive(Clone, Debug)]
 pub enum StoreKind {
     Memory(MemoryStore),
+    ReferenceTracking(ReferenceTrackingStore),
+    Replicated(ReplicatedStore),
+    GrpcUpstream(GrpcUpstreamStore),
+    Sharded(ShardedStore),
+    Tiered(TieredStore),
+    Coalescing(CoalescingStore),
+    ExistenceIndex(ExistenceIndexStore),
+    Quota(QuotaStore),
+    Embedded(EmbeddedStore),
+    SizePartitioned(SizePartitionedStore),
+    Redis(RedisStore),
+    Dedup(DedupStore),
 }
 
+/// Takes at most `limit` bytes of `bytes`, starting at `offset`
+pub(crate) fn slice_chunk(bytes: &Bytes, offset: usize, limit: usize) -> Bytes {
+    // take the lowest of the limit of bytes asked for, or, the remaining bytes left
+    let offset = offset.min(bytes.len());
+    let length_bytes_to_send = limit.min(bytes.len() - offset);
+
+    bytes.slice(offset..(offset + length_bytes_to_send))
+}
+
+#[derive(Clone)]
 pub struct StoreManager {
     stores: HashMap<InstanceName, Arc<StoreKind>>,
+    quotas: HashMap<InstanceName, Arc<QuotaUsage>>,
+    routes: Arc<Vec<InstanceRoute>>,
+    default_instance_name: Option<InstanceName>,
 }
 
 impl StoreManager {
     pub fn new(stores: HashMap<InstanceName, Arc<StoreKind>>) -> Self {
-        Self { stores }
+        Self::with_quotas(stores, HashMap::new())
+    }
+
+    /// Creates a manager reporting the usage of `quotas`, enforced by the [`QuotaStore`]s the
+    /// stores of those instances were built with
+    pub fn with_quotas(
+        stores: HashMap<InstanceName, Arc<StoreKind>>,
+        quotas: HashMap<InstanceName, Arc<QuotaUsage>>,
+    ) -> Self {
+        Self {
+            stores,
+            quotas,
+            routes: Arc::default(),
+            default_instance_name: None,
+        }
+    }
+
+    /// Sends the instance names no store is named after to the store of the first of `routes`
+    /// they match, or else to the store of `default_instance_name`
+    pub fn with_routing(
+        mut self,
+        routes: Vec<InstanceRoute>,
+        default_instance_name: Option<InstanceName>,
+    ) -> Result<Self, Error> {
+        let targets = routes
+            .iter()
+            .map(|route| &route.instance_name)
+            .chain(&default_instance_name);
+
+        for instance_name in targets {
+            if !self.stores.contains_key(instance_name) {
+                return Err(Error::InvalidStoreConfig(format!(
+                    "instance `{instance_name}` is routed to, but not served"
+                )));
+            }
+        }
+
+        self.routes = Arc::new(routes);
+        self.default_instance_name = default_instance_name;
+
+        Ok(self)
+    }
+
+    /// Quota of `instance_name` along with its usage, if the instance has one
+    pub fn quota_usage(&self, instance_name: &InstanceName) -> Option<Arc<QuotaUsage>> {
+        let instance_name = self.resolve(instance_name).ok()?;
+
+        self.quotas.get(instance_name).cloned()
+    }
+
+    pub fn instance_names(&self) -> impl Iterator<Item = &InstanceName> {
+        self.stores.keys()
+    }
+
+    /// Name of the instance whose store serves the requests for `instance_name`
+    pub fn resolve<'a>(
+        &'a self,
+        instance_name: &'a InstanceName,
+    ) -> Result<&'a InstanceName, Error> {
+        if self.stores.contains_key(instance_name) {
+            return Ok(instance_name);
+        }
+
+        instance_name.validate()?;
+
+        self.routes
+            .iter()
+            .find(|route| route.pattern.matches(instance_name))
+            .map(|route| &route.instance_name)
+            .or(self.default_instance_name.as_ref())
+            .ok_or_else(|| Error::StoreNotFound(instance_name.clone()))
+    }
+
+    /// Flushes the store of every instance, see [`Store::flush`]
+    pub async fn flush(&self) {
+        for store in self.stores.values() {
+            store.flush().await;
+        }
     }
 
     pub fn get_store_by_instance_name(
         &self,
         instance_name: &InstanceName,
     ) -> Result<Arc<StoreKind>, Error> {
-        let store = self
-            .stores
-            .get(instance_name)
-            .ok_or_else(|| Error::StoreNotFound(instance_name.clone()))?
-            .to_owned();
+        let instance_name = self.resolve(instance_name)?;
+
+        Ok(self.stores[instance_name].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(default_instance_name: Option<&str>) -> StoreManager {
+        let stores = ["main", "ci", "ci/linux"]
+            .into_iter()
+            .map(|instance_name| {
+                let store = Arc::new(StoreKind::from(MemoryStore::new(1024)));
+                (InstanceName::from(instance_name), store)
+            })
+            .collect();
+        let routes = ["ci/*=ci", "bazel-?=main"]
+            .into_iter()
+            .map(|route| route.parse().unwrap())
+            .collect();
+
+        StoreManager::new(stores)
+            .with_routing(routes, default_instance_name.map(InstanceName::from))
+            .unwrap()
+    }
+
+    #[test]
+    fn resolves_instance_names() {
+        let cases = [
+            // served instances win over the routes matching them
+            (None, "ci/linux", Some("ci/linux")),
+            (None, "ci/mac", Some("ci")),
+            (None, "bazel-5", Some("main")),
+            (None, "other", None),
+            (Some("main"), "other", Some("main")),
+            (Some("main"), "ci/mac", Some("ci")),
+            // invalid instance names are not routed anywhere
+            (Some("main"), "ci/blobs", None),
+        ];
+
+        for (default_instance_name, instance_name, expected) in cases {
+            let manager = manager(default_instance_name);
+            let instance_name = InstanceName::from(instance_name);
+
+            assert_eq!(
+                manager
+                    .resolve(&instance_name)
+                    .ok()
+                    .map(InstanceName::as_str),
+                expected,
+                "`{instance_name}` with default {default_instance_name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn routes_to_unknown_instances_are_rejected() {
+        let stores = HashMap::from([(
+            InstanceName::from("main"),
+            Arc::new(StoreKind::from(MemoryStore::new(1024))),
+        )]);
+
+        let routed =
+            StoreManager::new(stores.clone()).with_routing(vec!["ci/*=ci".parse().unwrap()], None);
+        let defaulted =
+            StoreManager::new(stores).with_routing(Vec::new(), Some(InstanceName::from("ci")));
 
-        Ok(store)
+        assert!(matches!(routed, Err(Error::InvalidStoreConfig(_))));
+        assert!(matches!(defaulted, Err(Error::InvalidStoreConfig(_))));
     }
 }
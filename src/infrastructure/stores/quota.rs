@@ -0,0 +1,367 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use opentelemetry::{global, KeyValue};
+use tracing::instrument;
+
+use super::{Store, StoreKind, StoreStats};
+use crate::{
+    domain::{DigestInfo, InstanceName},
+    errors::Error,
+};
+
+/// Limits on what a single instance may store
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InstanceQuota {
+    pub max_bytes: Option<u64>,
+    pub max_entries: Option<u64>,
+}
+
+/// Quota of an instance as given on the command line, `{instance_name}={max_bytes}:{max_entries}`
+/// where either limit may be left empty, e.g. `ci=1073741824:` or `=:10000` for the empty
+/// instance name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceQuotaSpec {
+    pub instance_name: String,
+    pub quota: InstanceQuota,
+}
+
+impl FromStr for InstanceQuotaSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("`{value}` is not of the form `instance_name=max_bytes:max_entries`");
+
+        let (instance_name, limits) = value.rsplit_once('=').ok_or_else(invalid)?;
+        let (max_bytes, max_entries) = limits.split_once(':').ok_or_else(invalid)?;
+
+        let parse_limit = |limit: &str| -> Result<Option<u64>, String> {
+            if limit.is_empty() {
+                Ok(None)
+            } else {
+                limit.parse().map(Some).map_err(|_| invalid())
+            }
+        };
+
+        Ok(Self {
+            instance_name: instance_name.to_string(),
+            quota: InstanceQuota {
+                max_bytes: parse_limit(max_bytes)?,
+                max_entries: parse_limit(max_entries)?,
+            },
+        })
+    }
+}
+
+/// Quota of an instance, along with how much of it is in use
+#[derive(Debug, Default)]
+pub struct QuotaUsage {
+    pub quota: InstanceQuota,
+    used_bytes: AtomicU64,
+    used_entries: AtomicU64,
+}
+
+impl QuotaUsage {
+    pub fn new(quota: InstanceQuota) -> Self {
+        Self {
+            quota,
+            ..Default::default()
+        }
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn used_entries(&self) -> u64 {
+        self.used_entries.load(Ordering::Relaxed)
+    }
+
+    fn is_exceeded(&self) -> bool {
+        self.quota
+            .max_bytes
+            .is_some_and(|max_bytes| self.used_bytes() > max_bytes)
+            || self
+                .quota
+                .max_entries
+                .is_some_and(|max_entries| self.used_entries() > max_entries)
+    }
+}
+
+/// Reports the usage of every quota as metrics, labelled with the instance name and the store,
+/// `cas` or `action_cache`, held to it
+pub fn register_quota_metrics(quotas: Vec<(InstanceName, &'static str, Arc<QuotaUsage>)>) {
+    let meter = global::meter("bache");
+    let quotas = Arc::new(quotas);
+
+    let bytes_quotas = quotas.clone();
+    meter
+        .u64_value_observer("bache.quota.used_bytes", move |result| {
+            for (instance_name, store, usage) in bytes_quotas.iter() {
+                result.observe(
+                    usage.used_bytes(),
+                    &[
+                        KeyValue::new("instance_name", instance_name.to_string()),
+                        KeyValue::new("store", *store),
+                    ],
+                );
+            }
+        })
+        .with_description("Bytes stored by an instance with a quota")
+        .init();
+
+    meter
+        .u64_value_observer("bache.quota.used_entries", move |result| {
+            for (instance_name, store, usage) in quotas.iter() {
+                result.observe(
+                    usage.used_entries(),
+                    &[
+                        KeyValue::new("instance_name", instance_name.to_string()),
+                        KeyValue::new("store", *store),
+                    ],
+                );
+            }
+        })
+        .with_description("Entries stored by an instance with a quota")
+        .init();
+}
+
+/// Every entry of a [`QuotaStore`] from the least to the most recently used
+#[derive(Default)]
+struct RecencyIndex {
+    /// Size and last use of every entry
+    entries: HashMap<DigestInfo, (u64, u64)>,
+    by_last_use: BTreeMap<u64, DigestInfo>,
+    clock: u64,
+}
+
+impl RecencyIndex {
+    fn record(&mut self, key: &DigestInfo, size: u64, usage: &QuotaUsage) {
+        self.forget(key, usage);
+
+        self.clock += 1;
+        self.entries.insert(key.clone(), (size, self.clock));
+        self.by_last_use.insert(self.clock, key.clone());
+
+        usage.used_bytes.fetch_add(size, Ordering::Relaxed);
+        usage.used_entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bump(&mut self, key: &DigestInfo) {
+        if let Some((_, last_use)) = self.entries.get_mut(key) {
+            self.by_last_use.remove(last_use);
+
+            self.clock += 1;
+            *last_use = self.clock;
+            self.by_last_use.insert(self.clock, key.clone());
+        }
+    }
+
+    fn forget(&mut self, key: &DigestInfo, usage: &QuotaUsage) {
+        if let Some((size, last_use)) = self.entries.remove(key) {
+            self.by_last_use.remove(&last_use);
+
+            usage.used_bytes.fetch_sub(size, Ordering::Relaxed);
+            usage.used_entries.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn least_recently_used(&self) -> Option<DigestInfo> {
+        self.by_last_use.values().next().cloned()
+    }
+}
+
+/// Store holding an instance to its quota.
+///
+/// Once a write takes the instance over its quota, its least recently used entries are evicted
+/// until it is back under, leaving the entries of other instances alone. Entries evicted by the
+/// wrapped store on its own are forgotten about when next looked up, or when they come up for
+/// eviction, so that they are not counted against the quota in place of live entries.
+#[derive(Clone)]
+pub struct QuotaStore {
+    inner: Arc<StoreKind>,
+    instance_name: InstanceName,
+    usage: Arc<QuotaUsage>,
+    index: Arc<Mutex<RecencyIndex>>,
+}
+
+impl Debug for QuotaStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QuotaStore")
+            .field("inner", &self.inner)
+            .field("instance_name", &self.instance_name)
+            .field("quota", &self.usage.quota)
+            .finish_non_exhaustive()
+    }
+}
+
+impl QuotaStore {
+    pub fn new(inner: Arc<StoreKind>, instance_name: InstanceName, usage: Arc<QuotaUsage>) -> Self {
+        Self {
+            inner,
+            instance_name,
+            usage,
+            index: Arc::default(),
+        }
+    }
+
+    /// Updates the index after a lookup of `key`
+    fn observe(&self, key: &DigestInfo, present: bool) {
+        let mut index = self.index.lock().unwrap();
+
+        if present {
+            index.bump(key);
+        } else {
+            index.forget(key, &self.usage);
+        }
+    }
+
+    async fn evict_over_quota(&self) {
+        while self.usage.is_exceeded() {
+            let key = match self.index.lock().unwrap().least_recently_used() {
+                Some(key) => key,
+                None => return,
+            };
+
+            // already evicted by the wrapped store, which frees no more room
+            if self.inner.size_of(&key).await.is_none() {
+                self.index.lock().unwrap().forget(&key, &self.usage);
+                continue;
+            }
+
+            if let Err(err) = self.inner.remove(&key).await {
+                tracing::warn!(%err, instance_name = %self.instance_name, "failed to evict entry");
+            }
+
+            self.index.lock().unwrap().forget(&key, &self.usage);
+        }
+    }
+}
+
+#[async_trait]
+impl Store for QuotaStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        let present = self.inner.contains_key(key).await;
+        if !present {
+            self.observe(key, false);
+        }
+
+        present
+    }
+
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        let present = self.inner.contains_keys(keys).await;
+
+        for (key, present) in keys.iter().zip(&present) {
+            if !present {
+                self.observe(key, false);
+            }
+        }
+
+        present
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        let present = self.inner.touch(key).await;
+        self.observe(key, present);
+
+        present
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        let result = self.inner.get_chunk(key, offset, limit).await;
+
+        match &result {
+            Ok(_) => self.observe(key, true),
+            Err(Error::DigestInfoNotFound(_)) => self.observe(key, false),
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        let size = bytes.len() as u64;
+
+        // evicting everything else would not make room for it
+        if self
+            .usage
+            .quota
+            .max_bytes
+            .is_some_and(|max_bytes| size > max_bytes)
+            || self.usage.quota.max_entries == Some(0)
+        {
+            return Err(Error::QuotaExceeded(self.instance_name.clone()));
+        }
+
+        self.inner.put(key.clone(), bytes).await?;
+        self.index.lock().unwrap().record(&key, size, &self.usage);
+
+        self.evict_over_quota().await;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        self.inner.remove(key).await?;
+        self.observe(key, false);
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        self.inner.size_of(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn list_keys(&self) -> Result<Vec<DigestInfo>, Error> {
+        self.inner.list_keys().await
+    }
+
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        self.inner.clear().await?;
+
+        let mut index = self.index.lock().unwrap();
+        *index = RecencyIndex::default();
+        self.usage.used_bytes.store(0, Ordering::Relaxed);
+        self.usage.used_entries.store(0, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        vec![self.inner.clone()]
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            kind: "quota",
+            entry_count: Some(self.usage.used_entries()),
+            size_bytes: Some(self.usage.used_bytes()),
+            tiers: self.tiers().iter().map(|tier| tier.stats()).collect(),
+        }
+    }
+}
@@ -0,0 +1,838 @@
+use std::{fmt::Debug, time::Duration};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use redis::{aio::ConnectionManager, FromRedisValue, Pipeline, Value};
+use tracing::instrument;
+
+use super::{Store, StoreStats};
+use crate::{domain::DigestInfo, errors::Error};
+
+/// Keys asked for by every `SCAN` call
+const SCAN_BATCH_SIZE: usize = 1000;
+
+/// How an entry is laid out: its bytes are split in chunks of `chunk_size` bytes, each under a
+/// key of its own. Kept in the entry's main key, written last, so that an entry is only ever
+/// seen once complete
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Manifest {
+    length: usize,
+    chunk_size: usize,
+}
+
+impl Manifest {
+    fn parse(value: &str) -> Option<Self> {
+        let (length, chunk_size) = value.split_once(':')?;
+
+        Some(Self {
+            length: length.parse().ok()?,
+            chunk_size: chunk_size
+                .parse()
+                .ok()
+                .filter(|chunk_size| *chunk_size > 0)?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!("{}:{}", self.length, self.chunk_size)
+    }
+
+    /// An empty entry still has a single, empty, chunk
+    fn chunk_count(&self) -> usize {
+        self.length.div_ceil(self.chunk_size).max(1)
+    }
+
+    /// `GETRANGE` arguments reading `limit` bytes from `offset`, as the index of a chunk along
+    /// with the inclusive range to read from it
+    fn ranges(&self, offset: usize, limit: usize) -> Vec<(usize, isize, isize)> {
+        let end = self.length.min(offset.saturating_add(limit));
+        if offset >= end {
+            return Vec::new();
+        }
+
+        (offset / self.chunk_size..=(end - 1) / self.chunk_size)
+            .map(|index| {
+                let chunk_start = index * self.chunk_size;
+                let start = offset.max(chunk_start) - chunk_start;
+                let stop = end.min(chunk_start + self.chunk_size) - chunk_start - 1;
+
+                // a guessed length may not fit, `GETRANGE` stops at the end of the value anyway
+                let clamp = |position: usize| isize::try_from(position).unwrap_or(isize::MAX);
+
+                (index, clamp(start), clamp(stop))
+            })
+            .collect()
+    }
+}
+
+/// Escapes the glob characters of `value`, for use in a `SCAN` pattern
+fn escape_pattern(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for character in value.chars() {
+        if matches!(character, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+
+    escaped
+}
+
+/// How a [`RedisStore`] lays out and expires its entries
+#[derive(Debug, Clone)]
+pub struct RedisOptions {
+    /// Prepended to every key, so that several stores can share a database
+    pub key_prefix: String,
+    /// Largest value written under a single key, larger entries are split in several chunks
+    pub chunk_size: usize,
+    /// How long entries are kept after being written or touched. Kept until evicted by the
+    /// server when unset
+    pub ttl: Option<Duration>,
+    /// Whether the size of entries is the one of their key, which saves a round trip on reads
+    pub content_addressed: bool,
+}
+
+/// Store keeping its entries in a Redis compatible server.
+///
+/// Entries larger than the chunk size are split in several values, so that no single value gets
+/// too large for the server and offset reads only fetch the chunks they need. Every entry is
+/// written atomically, along with a manifest telling how it is split, and read in a single
+/// round trip when its size is known up front.
+#[derive(Clone)]
+pub struct RedisStore {
+    connection: ConnectionManager,
+    options: RedisOptions,
+}
+
+impl Debug for RedisStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisStore {
+    /// Connects to the server at `url`, e.g. `redis://redis:6379/0`. The connection is
+    /// re-established on its own whenever it is lost
+    pub async fn connect(url: &str, options: RedisOptions) -> Result<Self, Error> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+
+        Ok(Self {
+            connection,
+            options,
+        })
+    }
+
+    fn manifest_key(&self, key: &DigestInfo) -> String {
+        format!(
+            "{}{}-{}",
+            self.options.key_prefix,
+            key.hash(),
+            key.size_bytes
+        )
+    }
+
+    fn chunk_key(&self, key: &DigestInfo, index: usize) -> String {
+        format!("{}:{index}", self.manifest_key(key))
+    }
+
+    /// Parses a key written by this store back to its digest, skipping chunk keys
+    fn parse_manifest_key(&self, redis_key: &str) -> Option<DigestInfo> {
+        let (hash, size) = redis_key
+            .strip_prefix(&self.options.key_prefix)?
+            .split_once('-')?;
+
+        if size.contains(':') {
+            return None;
+        }
+
+        DigestInfo::try_new(hash, size.parse::<usize>().ok()?).ok()
+    }
+
+    async fn query<T: FromRedisValue>(&self, pipeline: &Pipeline) -> Result<T, Error> {
+        let mut connection = self.connection.clone();
+
+        Ok(pipeline.query_async(&mut connection).await?)
+    }
+
+    async fn get_manifest(&self, key: &DigestInfo) -> Result<Option<Manifest>, Error> {
+        let manifest: Option<String> = self
+            .query(redis::pipe().get(self.manifest_key(key)))
+            .await
+            .map(|(manifest,): (Option<String>,)| manifest)?;
+
+        Ok(manifest.as_deref().and_then(Manifest::parse))
+    }
+
+    /// Reads the `ranges` of `key` along with its manifest, in one atomic round trip
+    async fn read_ranges(
+        &self,
+        key: &DigestInfo,
+        ranges: &[(usize, isize, isize)],
+    ) -> Result<(Option<Manifest>, Vec<Vec<u8>>), Error> {
+        let mut pipeline = redis::pipe();
+        pipeline.atomic().get(self.manifest_key(key));
+        for (index, start, stop) in ranges {
+            pipeline.getrange(self.chunk_key(key, *index), *start, *stop);
+        }
+
+        let mut values: Vec<Value> = self.query(&pipeline).await?;
+        if values.is_empty() {
+            return Ok((None, Vec::new()));
+        }
+
+        let manifest: Option<String> = FromRedisValue::from_redis_value(&values.remove(0))?;
+        let chunks = values
+            .iter()
+            .map(FromRedisValue::from_redis_value)
+            .collect::<Result<_, _>>()?;
+
+        Ok((manifest.as_deref().and_then(Manifest::parse), chunks))
+    }
+
+    /// Every key holding a part of `key`, its manifest first
+    fn entry_keys(&self, key: &DigestInfo, manifest: &Manifest) -> Vec<String> {
+        std::iter::once(self.manifest_key(key))
+            .chain((0..manifest.chunk_count()).map(|index| self.chunk_key(key, index)))
+            .collect()
+    }
+
+    /// Every key written by this store, chunks included
+    async fn scan(&self) -> Result<Vec<String>, Error> {
+        let pattern = format!("{}*", escape_pattern(&self.options.key_prefix));
+        let mut connection = self.connection.clone();
+
+        let mut keys = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next_cursor, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(SCAN_BATCH_SIZE)
+                .query_async(&mut connection)
+                .await?;
+
+            keys.extend(batch);
+
+            if next_cursor == 0 {
+                return Ok(keys);
+            }
+            cursor = next_cursor;
+        }
+    }
+}
+
+#[async_trait]
+impl Store for RedisStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        self.contains_keys(std::slice::from_ref(key)).await[0]
+    }
+
+    /// Checks every key with pipelined `EXISTS`, in a single round trip
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        if keys.is_empty() {
+            return Vec::new();
+        }
+
+        let mut pipeline = redis::pipe();
+        for key in keys {
+            pipeline.exists(self.manifest_key(key));
+        }
+
+        self.query(&pipeline).await.unwrap_or_else(|err| {
+            tracing::warn!(%err, "failed to look up keys in redis");
+            vec![false; keys.len()]
+        })
+    }
+
+    /// Also pushes back the expiry of the entry, when entries expire
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        let ttl = match self.options.ttl {
+            Some(ttl) => ttl,
+            None => return self.contains_key(key).await,
+        };
+
+        let manifest = match self.get_manifest(key).await {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => return false,
+            Err(err) => {
+                tracing::warn!(%err, "failed to touch entry in redis");
+                return false;
+            }
+        };
+
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+        for redis_key in self.entry_keys(key, &manifest) {
+            pipeline.pexpire(redis_key, ttl.as_millis() as usize);
+        }
+
+        // the entry may have expired in between, in which case its manifest is not touched
+        match self.query::<Vec<bool>>(&pipeline).await {
+            Ok(touched) => touched.first().copied().unwrap_or(false),
+            Err(err) => {
+                tracing::warn!(%err, "failed to touch entry in redis");
+                false
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        // guesses how the entry is laid out, so that it can be read along with its manifest
+        let guess = if self.options.content_addressed {
+            Manifest {
+                length: key.size_bytes as usize,
+                chunk_size: self.options.chunk_size,
+            }
+        } else {
+            // entries of unknown size are usually small enough to fit in a single chunk
+            Manifest {
+                length: usize::MAX,
+                chunk_size: usize::MAX,
+            }
+        };
+        let ranges = guess.ranges(offset, limit);
+
+        let (manifest, chunks) = self.read_ranges(key, &ranges).await?;
+        let manifest = manifest.ok_or_else(|| Error::DigestInfoNotFound(key.hash()))?;
+
+        let guessed_right = manifest == guess
+            || (manifest.chunk_count() == 1 && ranges.iter().all(|(index, ..)| *index == 0));
+
+        let chunks = if guessed_right {
+            chunks
+        } else {
+            let (manifest, chunks) = self
+                .read_ranges(key, &manifest.ranges(offset, limit))
+                .await?;
+            if manifest.is_none() {
+                return Err(Error::DigestInfoNotFound(key.hash()));
+            }
+
+            chunks
+        };
+
+        let mut bytes = BytesMut::with_capacity(chunks.iter().map(Vec::len).sum());
+        for chunk in chunks {
+            bytes.extend_from_slice(&chunk);
+        }
+
+        Ok(bytes.freeze())
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        let manifest = Manifest {
+            length: bytes.len(),
+            chunk_size: self.options.chunk_size,
+        };
+
+        let mut pipeline = redis::pipe();
+        pipeline.atomic();
+
+        let mut set = |redis_key: String, value: &[u8]| match self.options.ttl {
+            Some(ttl) => {
+                pipeline
+                    .pset_ex(redis_key, value, ttl.as_millis() as usize)
+                    .ignore();
+            }
+            None => {
+                pipeline.set(redis_key, value).ignore();
+            }
+        };
+
+        for index in 0..manifest.chunk_count() {
+            let start = index * manifest.chunk_size;
+            let end = bytes.len().min(start + manifest.chunk_size);
+
+            set(self.chunk_key(&key, index), &bytes[start..end]);
+        }
+        set(self.manifest_key(&key), manifest.encode().as_bytes());
+
+        // an entry that is not content addressed may be overwritten by a shorter one, whose
+        // manifest would no longer point to the trailing chunks of the previous one
+        if !self.options.content_addressed {
+            if let Some(previous) = self.get_manifest(&key).await? {
+                let surplus: Vec<_> = (manifest.chunk_count()..previous.chunk_count())
+                    .map(|index| self.chunk_key(&key, index))
+                    .collect();
+                if !surplus.is_empty() {
+                    pipeline.del(surplus).ignore();
+                }
+            }
+        }
+
+        self.query(&pipeline).await
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        let manifest = match self.get_manifest(key).await? {
+            Some(manifest) => manifest,
+            None => return Ok(()),
+        };
+
+        self.query(redis::pipe().del(self.entry_keys(key, &manifest)).ignore())
+            .await
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        match self.get_manifest(key).await {
+            Ok(manifest) => manifest.map(|manifest| manifest.length),
+            Err(err) => {
+                tracing::warn!(%err, "failed to read entry size from redis");
+                None
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn list_keys(&self) -> Result<Vec<DigestInfo>, Error> {
+        Ok(self
+            .scan()
+            .await?
+            .iter()
+            .filter_map(|redis_key| self.parse_manifest_key(redis_key))
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        for batch in self.scan().await?.chunks(SCAN_BATCH_SIZE) {
+            self.query::<()>(redis::pipe().del(batch).ignore()).await?;
+        }
+
+        Ok(())
+    }
+
+    fn stats(&self) -> StoreStats {
+        // counting the entries would mean scanning the whole keyspace
+        StoreStats {
+            kind: "redis",
+            entry_count: None,
+            size_bytes: None,
+            tiers: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    /// Reply of the stand-in server
+    enum Reply {
+        Ok,
+        Queued,
+        Integer(i64),
+        Bulk(Option<Vec<u8>>),
+        Array(Vec<Reply>),
+        Error(String),
+    }
+
+    impl Reply {
+        fn encode(&self, out: &mut Vec<u8>) {
+            match self {
+                Reply::Ok => out.extend_from_slice(b"+OK\r\n"),
+                Reply::Queued => out.extend_from_slice(b"+QUEUED\r\n"),
+                Reply::Integer(value) => out.extend_from_slice(format!(":{value}\r\n").as_bytes()),
+                Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+                Reply::Bulk(Some(value)) => {
+                    out.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                    out.extend_from_slice(value);
+                    out.extend_from_slice(b"\r\n");
+                }
+                Reply::Array(replies) => {
+                    out.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                    for reply in replies {
+                        reply.encode(out);
+                    }
+                }
+                Reply::Error(message) => {
+                    out.extend_from_slice(format!("-ERR {message}\r\n").as_bytes())
+                }
+            }
+        }
+    }
+
+    /// Values along with when they expire
+    type Keyspace = Arc<Mutex<HashMap<Vec<u8>, (Vec<u8>, Option<Instant>)>>>;
+
+    /// In-process stand-in for a Redis server, implementing the commands the store sends
+    struct StandIn {
+        url: String,
+        keyspace: Keyspace,
+    }
+
+    impl StandIn {
+        async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("redis://{}", listener.local_addr().unwrap());
+            let keyspace = Keyspace::default();
+
+            let shared = keyspace.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    tokio::spawn(Self::serve(stream, shared.clone()));
+                }
+            });
+
+            Self { url, keyspace }
+        }
+
+        async fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+            let mut line = String::new();
+            reader.read_line(&mut line).await.ok()?;
+            let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+
+            let mut arguments = Vec::with_capacity(count);
+            for _ in 0..count {
+                line.clear();
+                reader.read_line(&mut line).await.ok()?;
+                let length: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+
+                let mut argument = vec![0; length + 2];
+                reader.read_exact(&mut argument).await.ok()?;
+                argument.truncate(length);
+                arguments.push(argument);
+            }
+
+            Some(arguments)
+        }
+
+        async fn serve(stream: TcpStream, keyspace: Keyspace) {
+            let mut reader = BufReader::new(stream);
+            let mut transaction: Option<Vec<Vec<Vec<u8>>>> = None;
+
+            while let Some(command) = Self::read_command(&mut reader).await {
+                let name = String::from_utf8_lossy(&command[0]).to_uppercase();
+
+                let reply = match (name.as_str(), &mut transaction) {
+                    ("MULTI", _) => {
+                        transaction = Some(Vec::new());
+                        Reply::Ok
+                    }
+                    ("EXEC", _) => Reply::Array(
+                        transaction
+                            .take()
+                            .unwrap_or_default()
+                            .iter()
+                            .map(|command| Self::execute(command, &keyspace))
+                            .collect(),
+                    ),
+                    (_, Some(queued)) => {
+                        queued.push(command);
+                        Reply::Queued
+                    }
+                    (_, None) => Self::execute(&command, &keyspace),
+                };
+
+                let mut out = Vec::new();
+                reply.encode(&mut out);
+                reader.get_mut().write_all(&out).await.unwrap();
+            }
+        }
+
+        fn execute(command: &[Vec<u8>], keyspace: &Keyspace) -> Reply {
+            let mut keyspace = keyspace.lock().unwrap();
+            let now = Instant::now();
+            keyspace.retain(|_, (_, expiry)| expiry.is_none_or(|expiry| expiry > now));
+
+            let name = String::from_utf8_lossy(&command[0]).to_uppercase();
+            let text = |index: usize| String::from_utf8_lossy(&command[index]).to_string();
+            let number = |index: usize| text(index).parse::<i64>().unwrap();
+            let expiry = |ms: i64| Some(now + Duration::from_millis(ms as u64));
+
+            match name.as_str() {
+                "PING" => Reply::Ok,
+                "SET" => {
+                    keyspace.insert(command[1].clone(), (command[2].clone(), None));
+                    Reply::Ok
+                }
+                "PSETEX" => {
+                    keyspace.insert(command[1].clone(), (command[3].clone(), expiry(number(2))));
+                    Reply::Ok
+                }
+                "GET" => Reply::Bulk(keyspace.get(&command[1]).map(|(value, _)| value.clone())),
+                "GETRANGE" => {
+                    let value = keyspace
+                        .get(&command[1])
+                        .map(|(value, _)| value.clone())
+                        .unwrap_or_default();
+                    let length = value.len() as i64;
+                    let resolve = |index: i64| if index < 0 { length + index } else { index };
+
+                    let start = resolve(number(2)).max(0);
+                    let stop = resolve(number(3)).min(length - 1);
+
+                    Reply::Bulk(Some(if start > stop {
+                        Vec::new()
+                    } else {
+                        value[start as usize..=stop as usize].to_vec()
+                    }))
+                }
+                "EXISTS" => Reply::Integer(
+                    command[1..]
+                        .iter()
+                        .filter(|key| keyspace.contains_key(*key))
+                        .count() as i64,
+                ),
+                "DEL" => Reply::Integer(
+                    command[1..]
+                        .iter()
+                        .filter(|key| keyspace.remove(*key).is_some())
+                        .count() as i64,
+                ),
+                "PEXPIRE" => match keyspace.get_mut(&command[1]) {
+                    Some((_, current)) => {
+                        *current = expiry(number(2));
+                        Reply::Integer(1)
+                    }
+                    None => Reply::Integer(0),
+                },
+                "SCAN" => {
+                    // every key is returned at once, so the cursor is always done
+                    let prefix = text(3).trim_end_matches('*').replace('\\', "");
+                    let keys = keyspace
+                        .keys()
+                        .filter(|key| key.starts_with(prefix.as_bytes()))
+                        .map(|key| Reply::Bulk(Some(key.clone())))
+                        .collect();
+
+                    Reply::Array(vec![Reply::Bulk(Some(b"0".to_vec())), Reply::Array(keys)])
+                }
+                _ => Reply::Error(format!("unknown command `{name}`")),
+            }
+        }
+
+        fn key_count(&self) -> usize {
+            self.keyspace.lock().unwrap().len()
+        }
+    }
+
+    async fn connect(stand_in: &StandIn, prefix: &str, content_addressed: bool) -> RedisStore {
+        RedisStore::connect(
+            &stand_in.url,
+            RedisOptions {
+                key_prefix: prefix.to_string(),
+                chunk_size: 4,
+                ttl: None,
+                content_addressed,
+            },
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn manifest_ranges() {
+        let manifest = Manifest {
+            length: 10,
+            chunk_size: 4,
+        };
+
+        assert_eq!(manifest.chunk_count(), 3);
+        assert_eq!(
+            manifest.ranges(0, usize::MAX),
+            vec![(0, 0, 3), (1, 0, 3), (2, 0, 1)]
+        );
+        assert_eq!(manifest.ranges(3, 2), vec![(0, 3, 3), (1, 0, 0)]);
+        assert_eq!(manifest.ranges(9, 5), vec![(2, 1, 1)]);
+        assert_eq!(manifest.ranges(10, 5), vec![]);
+        assert_eq!(manifest.ranges(2, 0), vec![]);
+
+        let empty = Manifest {
+            length: 0,
+            chunk_size: 4,
+        };
+        assert_eq!(empty.chunk_count(), 1);
+        assert_eq!(Manifest::parse(&empty.encode()), Some(empty));
+        assert_eq!(Manifest::parse("10:0"), None);
+    }
+
+    #[tokio::test]
+    async fn round_trips_chunked_entries() {
+        let stand_in = StandIn::start().await;
+        let store = connect(&stand_in, "cas:", true).await;
+
+        for data in [&b""[..], b"abc", b"abcd", b"0123456789"] {
+            let key = DigestInfo::compute(data);
+            store.put(key.clone(), Bytes::from(data)).await.unwrap();
+
+            assert!(store.contains_key(&key).await);
+            assert_eq!(store.size_of(&key).await, Some(data.len()));
+            assert_eq!(store.get(&key).await.unwrap(), data);
+        }
+
+        let key = DigestInfo::compute(b"0123456789");
+        assert_eq!(store.get_chunk(&key, 3, 4).await.unwrap(), &b"3456"[..]);
+        assert_eq!(store.get_chunk(&key, 8, 100).await.unwrap(), &b"89"[..]);
+        assert_eq!(store.get_chunk(&key, 10, 1).await.unwrap(), &b""[..]);
+    }
+
+    #[tokio::test]
+    async fn reads_entries_of_unknown_size() {
+        let stand_in = StandIn::start().await;
+        let store = connect(&stand_in, "ac:", false).await;
+
+        // the size of an ActionCache key is not the size of its entry
+        let key = DigestInfo::compute(b"action");
+        for data in [&b"ab"[..], b"a longer action result"] {
+            store.put(key.clone(), Bytes::from(data)).await.unwrap();
+
+            assert_eq!(store.get(&key).await.unwrap(), data);
+            assert_eq!(
+                store.get_chunk(&key, 1, 6).await.unwrap(),
+                &data[1..data.len().min(7)]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn overwriting_with_a_shorter_entry_drops_the_surplus_chunks() {
+        let stand_in = StandIn::start().await;
+        let store = connect(&stand_in, "ac:", false).await;
+        let key = DigestInfo::compute(b"action");
+
+        store
+            .put(key.clone(), Bytes::from_static(b"0123456789"))
+            .await
+            .unwrap();
+        assert_eq!(stand_in.key_count(), 4);
+
+        store
+            .put(key.clone(), Bytes::from_static(b"ab"))
+            .await
+            .unwrap();
+        assert_eq!(stand_in.key_count(), 2);
+        assert_eq!(store.get(&key).await.unwrap(), &b"ab"[..]);
+    }
+
+    #[tokio::test]
+    async fn reads_entries_written_with_another_chunk_size() {
+        let stand_in = StandIn::start().await;
+        let writer = connect(&stand_in, "cas:", true).await;
+        let mut reader = connect(&stand_in, "cas:", true).await;
+        reader.options.chunk_size = 3;
+
+        let key = DigestInfo::compute(b"0123456789");
+        writer
+            .put(key.clone(), Bytes::from_static(b"0123456789"))
+            .await
+            .unwrap();
+
+        assert_eq!(reader.get(&key).await.unwrap(), &b"0123456789"[..]);
+        assert_eq!(reader.get_chunk(&key, 2, 5).await.unwrap(), &b"23456"[..]);
+    }
+
+    #[tokio::test]
+    async fn finds_missing_keys_and_removes_entries() {
+        let stand_in = StandIn::start().await;
+        let store = connect(&stand_in, "cas:", true).await;
+
+        let present = DigestInfo::compute(b"0123456789");
+        let missing = DigestInfo::compute(b"missing");
+        store
+            .put(present.clone(), Bytes::from_static(b"0123456789"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store
+                .contains_keys(&[present.clone(), missing.clone()])
+                .await,
+            vec![true, false]
+        );
+        assert!(matches!(
+            store.get(&missing).await,
+            Err(Error::DigestInfoNotFound(_))
+        ));
+
+        store.remove(&present).await.unwrap();
+        assert!(!store.contains_key(&present).await);
+        assert_eq!(stand_in.key_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn lists_and_clears_only_its_own_keys() {
+        let stand_in = StandIn::start().await;
+        let store = connect(&stand_in, "team/*:cas:", true).await;
+        let other = connect(&stand_in, "other:", true).await;
+
+        let keys: Vec<_> = [&b"first entry"[..], b"second"]
+            .into_iter()
+            .map(|data| {
+                let key = DigestInfo::compute(data);
+                (key, Bytes::from(data))
+            })
+            .collect();
+        for (key, bytes) in &keys {
+            store.put(key.clone(), bytes.clone()).await.unwrap();
+        }
+        other
+            .put(keys[0].0.clone(), keys[0].1.clone())
+            .await
+            .unwrap();
+
+        let mut listed = store.list_keys().await.unwrap();
+        listed.sort_by_key(|key| key.hash().to_string());
+        let mut expected: Vec<_> = keys.iter().map(|(key, _)| key.clone()).collect();
+        expected.sort_by_key(|key| key.hash().to_string());
+        assert_eq!(listed, expected);
+
+        store.clear().await.unwrap();
+        assert!(store.list_keys().await.unwrap().is_empty());
+        assert!(other.contains_key(&keys[0].0).await);
+    }
+
+    #[tokio::test]
+    async fn expires_entries_unless_touched() {
+        let stand_in = StandIn::start().await;
+        let mut store = connect(&stand_in, "cas:", true).await;
+        store.options.ttl = Some(Duration::from_secs(2));
+
+        let touched = DigestInfo::compute(b"0123456789");
+        let untouched = DigestInfo::compute(b"untouched");
+        store
+            .put(touched.clone(), Bytes::from_static(b"0123456789"))
+            .await
+            .unwrap();
+        store
+            .put(untouched.clone(), Bytes::from_static(b"untouched"))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert!(store.touch(&touched).await);
+
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert_eq!(store.get(&touched).await.unwrap(), &b"0123456789"[..]);
+        assert!(!store.contains_key(&untouched).await);
+    }
+}
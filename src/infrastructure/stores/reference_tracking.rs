@@ -0,0 +1,469 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use prost::Message;
+use tokio::{sync::mpsc::UnboundedReceiver, task::JoinHandle};
+use tracing::instrument;
+
+use super::{Store, StoreKind, StoreStats};
+use crate::{
+    domain::DigestInfo,
+    errors::Error,
+    protos::build::bazel::remote::execution::v2::{ActionResult, Digest, Directory, Tree},
+};
+
+/// Which CAS blobs every ActionCache entry points to, along with the reverse mapping
+#[derive(Default)]
+struct References {
+    blobs_by_action: HashMap<DigestInfo, HashSet<DigestInfo>>,
+    actions_by_blob: HashMap<DigestInfo, HashSet<DigestInfo>>,
+}
+
+impl References {
+    fn insert(&mut self, action: DigestInfo, blobs: HashSet<DigestInfo>) {
+        // an action result may have been replaced, so drop what the old one pointed to first
+        self.remove_action(&action);
+
+        for blob in &blobs {
+            self.actions_by_blob
+                .entry(blob.clone())
+                .or_default()
+                .insert(action.clone());
+        }
+
+        self.blobs_by_action.insert(action, blobs);
+    }
+
+    fn blobs(&self, action: &DigestInfo) -> Option<Vec<DigestInfo>> {
+        self.blobs_by_action
+            .get(action)
+            .map(|blobs| blobs.iter().cloned().collect())
+    }
+
+    /// Forgets about `action`, returning the blobs that are no longer referenced by any action
+    fn remove_action(&mut self, action: &DigestInfo) -> Vec<DigestInfo> {
+        let blobs = self.blobs_by_action.remove(action).unwrap_or_default();
+
+        blobs
+            .into_iter()
+            .filter(|blob| match self.actions_by_blob.get_mut(blob) {
+                Some(actions) => {
+                    actions.remove(action);
+
+                    if actions.is_empty() {
+                        self.actions_by_blob.remove(blob);
+                        true
+                    } else {
+                        false
+                    }
+                }
+                None => true,
+            })
+            .collect()
+    }
+
+    /// Forgets about `blob`, returning every action that pointed to it
+    fn remove_blob(&mut self, blob: &DigestInfo) -> Vec<DigestInfo> {
+        self.actions_by_blob
+            .remove(blob)
+            .unwrap_or_default()
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Wraps an ActionCache store so that every entry keeps track of the CAS blobs its `ActionResult`
+/// references.
+///
+/// Reading an entry refreshes the recency of all of its blobs, and an entry whose blobs are no
+/// longer all present is treated as a miss. Once hooked up to the eviction notifications of the
+/// underlying stores (see [`ReferenceTrackingStore::handle_evictions`]), evicting a blob also
+/// evicts the entries that need it, and evicting an entry evicts the blobs nothing else needs.
+///
+/// Those evictions only ever remove entries from the tiers set with
+/// [`ReferenceTrackingStore::with_evicting_tiers`], the ones sending the notifications. Slower
+/// tiers, upstreams, peers and shards still hold their copies.
+#[derive(Clone)]
+pub struct ReferenceTrackingStore {
+    action_cache: Arc<StoreKind>,
+    cas: Arc<StoreKind>,
+    evicting_tiers: Option<EvictingTiers>,
+    references: Arc<Mutex<References>>,
+}
+
+/// The local tiers of the ActionCache and CAS whose evictions are tracked
+#[derive(Clone, Debug)]
+struct EvictingTiers {
+    action_cache: Arc<StoreKind>,
+    cas: Arc<StoreKind>,
+}
+
+impl Debug for ReferenceTrackingStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReferenceTrackingStore")
+            .field("action_cache", &self.action_cache)
+            .field("cas", &self.cas)
+            .field("evicting_tiers", &self.evicting_tiers)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReferenceTrackingStore {
+    pub fn new(action_cache: Arc<StoreKind>, cas: Arc<StoreKind>) -> Self {
+        Self {
+            action_cache,
+            cas,
+            evicting_tiers: None,
+            references: Arc::new(Mutex::new(References::default())),
+        }
+    }
+
+    /// Sets the tiers of the ActionCache and CAS that send the eviction notifications, which are
+    /// the only ones entries get removed from when their action output is evicted
+    pub fn with_evicting_tiers(
+        mut self,
+        action_cache: Arc<StoreKind>,
+        cas: Arc<StoreKind>,
+    ) -> Self {
+        self.evicting_tiers = Some(EvictingTiers { action_cache, cas });
+        self
+    }
+
+    /// The same tracking, recording references into the same bookkeeping, over `action_cache`
+    /// instead, e.g. to restore entries into a local tier only
+    pub fn with_action_cache(&self, action_cache: Arc<StoreKind>) -> Self {
+        Self {
+            action_cache,
+            ..self.clone()
+        }
+    }
+
+    /// Spawns a task evicting whole action outputs together, driven by the eviction notifications
+    /// of the CAS and ActionCache stores, see
+    /// [`super::memory::MemoryStore::with_eviction_listener`]
+    pub fn handle_evictions(
+        &self,
+        mut cas_evictions: UnboundedReceiver<DigestInfo>,
+        mut action_cache_evictions: UnboundedReceiver<DigestInfo>,
+    ) -> JoinHandle<()> {
+        let store = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    Some(blob) = cas_evictions.recv() => store.on_blob_evicted(&blob).await,
+                    Some(action) = action_cache_evictions.recv() => {
+                        store.on_action_evicted(&action).await
+                    }
+                    else => break,
+                }
+            }
+        })
+    }
+
+    #[instrument(skip(self))]
+    async fn on_blob_evicted(&self, blob: &DigestInfo) {
+        let actions = self.references.lock().unwrap().remove_blob(blob);
+
+        for action in actions {
+            self.evict_action(&action).await;
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn on_action_evicted(&self, action: &DigestInfo) {
+        let orphaned_blobs = self.references.lock().unwrap().remove_action(action);
+
+        self.remove_blobs(orphaned_blobs).await;
+    }
+
+    /// Removes `action` and every blob only it referenced from the evicting tiers
+    async fn evict_action(&self, action: &DigestInfo) {
+        if let Some(tiers) = &self.evicting_tiers {
+            if let Err(err) = tiers.action_cache.remove(action).await {
+                tracing::warn!(%err, "failed to evict incomplete action result");
+            }
+        }
+
+        self.on_action_evicted(action).await;
+    }
+
+    async fn remove_blobs(&self, blobs: Vec<DigestInfo>) {
+        let tiers = match &self.evicting_tiers {
+            Some(tiers) => tiers,
+            None => return,
+        };
+
+        for blob in blobs {
+            if let Err(err) = tiers.cas.remove(&blob).await {
+                tracing::warn!(%err, "failed to evict blob of an evicted action result");
+            }
+        }
+    }
+
+    /// Collects the digests of every blob `action_result` needs in order to be usable, including
+    /// the files of its output directories when their trees are present in the CAS
+    async fn referenced_blobs(&self, action_result: &ActionResult) -> HashSet<DigestInfo> {
+        let mut digests: Vec<&Digest> = action_result
+            .output_files
+            .iter()
+            .filter_map(|output_file| output_file.digest.as_ref())
+            .chain(action_result.stdout_digest.as_ref())
+            .chain(action_result.stderr_digest.as_ref())
+            .collect();
+
+        let mut trees = Vec::new();
+        for output_directory in &action_result.output_directories {
+            if let Some(tree_digest) = &output_directory.tree_digest {
+                digests.push(tree_digest);
+
+                if let Some(tree) = self.get_tree(tree_digest).await {
+                    trees.push(tree);
+                }
+            }
+        }
+
+        let directory_digests = trees
+            .iter()
+            .flat_map(|tree| tree.root.iter().chain(tree.children.iter()))
+            .flat_map(|directory: &Directory| directory.files.iter())
+            .filter_map(|file| file.digest.as_ref());
+
+        digests
+            .into_iter()
+            .chain(directory_digests)
+            // the empty blob is always considered to be present, so there is nothing to track
+            .filter(|digest| digest.size_bytes > 0)
+            .filter_map(|digest| DigestInfo::try_from(digest.clone()).ok())
+            .collect()
+    }
+
+    async fn get_tree(&self, tree_digest: &Digest) -> Option<Tree> {
+        let tree_digest = DigestInfo::try_from(tree_digest.clone()).ok()?;
+        let bytes = self.cas.get(&tree_digest).await.ok()?;
+
+        Tree::decode(bytes).ok()
+    }
+
+    /// Refreshes the recency of every blob referenced by `action`, returning whether all of them
+    /// are still present
+    async fn touch_references(&self, action: &DigestInfo) -> bool {
+        let blobs = self.references.lock().unwrap().blobs(action);
+
+        for blob in blobs.unwrap_or_default() {
+            if !self.cas.touch(&blob).await {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[async_trait]
+impl Store for ReferenceTrackingStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        self.action_cache.contains_key(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        self.action_cache.touch(key).await && self.touch_references(key).await
+    }
+
+    /// Also prioritizes every blob the action result references, which it is useless without
+    #[instrument(skip(self))]
+    async fn prioritize(&self, key: &DigestInfo) -> bool {
+        if !self.action_cache.prioritize(key).await {
+            return false;
+        }
+
+        let blobs = self.references.lock().unwrap().blobs(key);
+        for blob in blobs.unwrap_or_default() {
+            self.cas.prioritize(&blob).await;
+        }
+
+        true
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        let bytes = self.action_cache.get_chunk(key, offset, limit).await?;
+
+        if !self.touch_references(key).await {
+            tracing::debug!("action result references evicted blobs, treating it as a miss");
+            self.evict_action(key).await;
+
+            return Err(Error::DigestInfoNotFound(key.hash()));
+        }
+
+        Ok(bytes)
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        let action_result = ActionResult::decode(bytes.clone())?;
+        let blobs = self.referenced_blobs(&action_result).await;
+
+        self.references.lock().unwrap().insert(key.clone(), blobs);
+
+        self.action_cache.put(key, bytes).await
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        // removing an entry on purpose keeps its blobs around, they may still be useful on their
+        // own
+        self.references.lock().unwrap().remove_action(key);
+
+        self.action_cache.remove(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        self.action_cache.size_of(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        *self.references.lock().unwrap() = References::default();
+
+        self.action_cache.clear().await
+    }
+
+    // the CAS is deliberately left out, it is not a tier of the ActionCache but a separate store
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        vec![self.action_cache.clone()]
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats::layered("reference_tracking", &self.tiers())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        infrastructure::memory::MemoryStore,
+        protos::build::bazel::remote::execution::v2::OutputFile,
+    };
+
+    fn digest(data: &[u8]) -> DigestInfo {
+        DigestInfo::compute(data)
+    }
+
+    fn action_result(blobs: &[&[u8]]) -> Bytes {
+        let output_files = blobs
+            .iter()
+            .map(|blob| OutputFile {
+                digest: Some(digest(blob).into()),
+                ..Default::default()
+            })
+            .collect();
+
+        ActionResult {
+            output_files,
+            ..Default::default()
+        }
+        .encode_to_vec()
+        .into()
+    }
+
+    async fn store_with(
+        blobs: &[&[u8]],
+    ) -> (ReferenceTrackingStore, Arc<StoreKind>, Arc<StoreKind>) {
+        let action_cache = Arc::new(StoreKind::from(MemoryStore::new(1024)));
+        let cas = Arc::new(StoreKind::from(MemoryStore::new(1024)));
+        for blob in blobs {
+            cas.put(digest(blob), Bytes::copy_from_slice(blob))
+                .await
+                .unwrap();
+        }
+
+        let store = ReferenceTrackingStore::new(action_cache.clone(), cas.clone())
+            .with_evicting_tiers(action_cache.clone(), cas.clone());
+
+        (store, action_cache, cas)
+    }
+
+    #[test]
+    fn removing_an_action_only_returns_unshared_blobs() {
+        let mut references = References::default();
+        references.insert(digest(b"a"), [digest(b"x"), digest(b"y")].into());
+        references.insert(digest(b"b"), [digest(b"y")].into());
+
+        assert_eq!(references.remove_action(&digest(b"a")), vec![digest(b"x")]);
+        assert_eq!(references.remove_action(&digest(b"b")), vec![digest(b"y")]);
+        assert!(references.actions_by_blob.is_empty());
+    }
+
+    #[tokio::test]
+    async fn blob_eviction_drops_the_referencing_actions() {
+        let (store, action_cache, cas) = store_with(&[b"x", b"y"]).await;
+        store
+            .put(digest(b"a"), action_result(&[b"x", b"y"]))
+            .await
+            .unwrap();
+        store
+            .put(digest(b"b"), action_result(&[b"y"]))
+            .await
+            .unwrap();
+
+        store.on_blob_evicted(&digest(b"x")).await;
+
+        assert!(!action_cache.contains_key(&digest(b"a")).await);
+        assert!(action_cache.contains_key(&digest(b"b")).await);
+        // `y` is still needed by `b`
+        assert!(cas.contains_key(&digest(b"y")).await);
+    }
+
+    #[tokio::test]
+    async fn action_eviction_frees_only_unshared_blobs() {
+        let (store, _, cas) = store_with(&[b"x", b"y"]).await;
+        store
+            .put(digest(b"a"), action_result(&[b"x", b"y"]))
+            .await
+            .unwrap();
+        store
+            .put(digest(b"b"), action_result(&[b"y"]))
+            .await
+            .unwrap();
+
+        store.on_action_evicted(&digest(b"a")).await;
+
+        assert!(!cas.contains_key(&digest(b"x")).await);
+        assert!(cas.contains_key(&digest(b"y")).await);
+    }
+
+    #[tokio::test]
+    async fn evictions_leave_other_tiers_alone() {
+        let (store, _, cas) = store_with(&[b"x"]).await;
+        let store = ReferenceTrackingStore {
+            evicting_tiers: Some(EvictingTiers {
+                action_cache: Arc::new(StoreKind::from(MemoryStore::new(1024))),
+                cas: Arc::new(StoreKind::from(MemoryStore::new(1024))),
+            }),
+            ..store
+        };
+        store
+            .put(digest(b"a"), action_result(&[b"x"]))
+            .await
+            .unwrap();
+
+        store.on_action_evicted(&digest(b"a")).await;
+
+        assert!(cas.contains_key(&digest(b"x")).await);
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use clap::ArgEnum;
+use tokio::sync::Notify;
+use tracing::instrument;
+
+use super::{slice_chunk, Store, StoreKind, StoreStats};
+use crate::{domain::DigestInfo, errors::Error, replication::is_replicated_request};
+
+/// When a write to a [`ReplicatedStore`] is acknowledged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+pub enum Acknowledgement {
+    /// Once the local store and every replica hold the entry
+    Sync,
+    /// As soon as the local store holds the entry, replicas are written to in the background
+    Async,
+}
+
+/// Replications running in the background, for [`Acknowledgement::Async`] writes
+#[derive(Default)]
+struct PendingReplications {
+    count: AtomicUsize,
+    /// Notified whenever the last pending replication is done
+    done: Notify,
+}
+
+impl PendingReplications {
+    async fn wait(&self) {
+        loop {
+            // created before checking, so that a replication finishing in between is not missed
+            let done = self.done.notified();
+
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+
+            done.await;
+        }
+    }
+}
+
+/// Store keeping copies of its entries on peer nodes.
+///
+/// Every write goes to the local store and to `replication_factor - 1` of the peers, picked from
+/// the key so that writes spread evenly across the cluster. Reads are served locally when possible,
+/// and otherwise fall back to the peers, copying the entry into the local store when one of them
+/// has it.
+///
+/// Requests of the peers themselves are served by the local store alone, see
+/// [`crate::replication`]
+#[derive(Clone)]
+pub struct ReplicatedStore {
+    local: Arc<StoreKind>,
+    peers: Vec<Arc<StoreKind>>,
+    replication_factor: usize,
+    acknowledgement: Acknowledgement,
+    pending: Arc<PendingReplications>,
+}
+
+impl Debug for ReplicatedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplicatedStore")
+            .field("local", &self.local)
+            .field("peers", &self.peers)
+            .field("replication_factor", &self.replication_factor)
+            .field("acknowledgement", &self.acknowledgement)
+            .finish()
+    }
+}
+
+impl ReplicatedStore {
+    pub fn new(
+        local: Arc<StoreKind>,
+        peers: Vec<Arc<StoreKind>>,
+        replication_factor: usize,
+        acknowledgement: Acknowledgement,
+    ) -> Self {
+        Self {
+            local,
+            peers,
+            replication_factor,
+            acknowledgement,
+            pending: Arc::default(),
+        }
+    }
+
+    /// Every peer, in the order they should be asked about `key`. The first ones are the replicas
+    /// `key` is written to
+    fn peers_for<'a>(&'a self, key: &DigestInfo) -> impl Iterator<Item = &'a Arc<StoreKind>> {
+        let start = if self.peers.is_empty() {
+            0
+        } else {
+            // the hash is already uniformly distributed, so any part of it will do
+            let mut prefix = [0; 8];
+            prefix.copy_from_slice(&key.packed_hash[..8]);
+
+            (u64::from_le_bytes(prefix) % self.peers.len() as u64) as usize
+        };
+
+        self.peers[start..].iter().chain(self.peers[..start].iter())
+    }
+
+    async fn replicate(
+        peers: Vec<Arc<StoreKind>>,
+        key: DigestInfo,
+        bytes: Bytes,
+    ) -> Result<(), Error> {
+        let writes = peers
+            .iter()
+            .map(|peer| peer.put(key.clone(), bytes.clone()));
+
+        futures::future::try_join_all(writes).await?;
+
+        Ok(())
+    }
+
+    /// Looks for `key` on the peers, keeping a local copy of it when found
+    async fn fetch_from_peers(&self, key: &DigestInfo) -> Result<Bytes, Error> {
+        for peer in self.peers_for(key) {
+            match peer.get(key).await {
+                Ok(bytes) => {
+                    if let Err(err) = self.local.put(key.clone(), bytes.clone()).await {
+                        tracing::warn!(%err, "failed to keep a local copy of a replicated entry");
+                    }
+
+                    return Ok(bytes);
+                }
+                Err(Error::DigestInfoNotFound(_)) => continue,
+                Err(err) => tracing::warn!(%err, ?peer, "failed to read from peer"),
+            }
+        }
+
+        Err(Error::DigestInfoNotFound(key.hash()))
+    }
+
+    async fn peers_contain(&self, key: &DigestInfo) -> bool {
+        for peer in self.peers_for(key) {
+            if peer.contains_key(key).await {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+#[async_trait]
+impl Store for ReplicatedStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        self.local.contains_key(key).await
+            || (!is_replicated_request() && self.peers_contain(key).await)
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        self.local.touch(key).await || (!is_replicated_request() && self.peers_contain(key).await)
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        match self.local.get_chunk(key, offset, limit).await {
+            Err(Error::DigestInfoNotFound(_)) if !is_replicated_request() => {
+                let bytes = self.fetch_from_peers(key).await?;
+
+                Ok(slice_chunk(&bytes, offset, limit))
+            }
+            result => result,
+        }
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        self.local.put(key.clone(), bytes.clone()).await?;
+
+        // the peer that sent the entry is the one replicating it
+        if is_replicated_request() {
+            return Ok(());
+        }
+
+        let replicas: Vec<_> = self
+            .peers_for(&key)
+            .take(self.replication_factor.saturating_sub(1))
+            .cloned()
+            .collect();
+
+        match self.acknowledgement {
+            Acknowledgement::Sync => Self::replicate(replicas, key, bytes).await,
+            Acknowledgement::Async => {
+                let pending = self.pending.clone();
+                pending.count.fetch_add(1, Ordering::SeqCst);
+
+                tokio::spawn(async move {
+                    if let Err(err) = Self::replicate(replicas, key, bytes).await {
+                        tracing::warn!(%err, "failed to replicate entry to peers");
+                    }
+
+                    if pending.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        pending.done.notify_waiters();
+                    }
+                });
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Only removes the local copy, peers keep theirs
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        self.local.remove(key).await
+    }
+
+    /// Only clears the local store, peers keep their entries
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        self.local.clear().await
+    }
+
+    #[instrument(skip(self))]
+    async fn compact(&self) {
+        self.local.compact().await;
+    }
+
+    /// Waits for the replications of asynchronously acknowledged writes
+    #[instrument(skip(self))]
+    async fn flush(&self) {
+        let pending = self.pending.count.load(Ordering::SeqCst);
+        if pending > 0 {
+            tracing::info!(pending, "waiting for pending replications");
+        }
+
+        self.pending.wait().await;
+        self.local.flush().await;
+    }
+
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        std::iter::once(self.local.clone())
+            .chain(self.peers.iter().cloned())
+            .collect()
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats::layered("replicated", &self.tiers())
+    }
+}
@@ -0,0 +1,328 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use sha2::{Digest as _, Sha256};
+use tracing::instrument;
+
+use super::{Store, StoreKind, StoreStats};
+use crate::{domain::DigestInfo, errors::Error};
+
+/// One of the backends of a [`ShardedStore`]
+#[derive(Debug, Clone)]
+pub struct Shard {
+    /// Stable identity of the shard, such as the endpoint of the node holding it. Every node of a
+    /// cluster must use the same ids, or they will disagree on where keys live
+    pub id: String,
+    pub store: Arc<StoreKind>,
+}
+
+impl Shard {
+    pub fn new(id: String, store: Arc<StoreKind>) -> Self {
+        Self { id, store }
+    }
+}
+
+/// Mixes the bits of `value`, see <https://prng.di.unimi.it/splitmix64.c>
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+    z ^ (z >> 31)
+}
+
+fn first_u64(bytes: &[u8]) -> u64 {
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&bytes[..8]);
+
+    u64::from_le_bytes(prefix)
+}
+
+/// Store partitioning its keys across several backends.
+///
+/// Keys are assigned with rendezvous hashing: every shard scores every key, and the highest score
+/// wins. Adding or removing a shard only moves the keys won or lost by that shard, everything
+/// else stays where it is. Batched calls are split per shard, and their results merged back in
+/// order.
+#[derive(Clone)]
+pub struct ShardedStore {
+    shards: Vec<Shard>,
+    /// Hash of every shard's id, at the same index as `shards`
+    seeds: Vec<u64>,
+}
+
+impl Debug for ShardedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShardedStore")
+            .field("shards", &self.shards)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ShardedStore {
+    pub fn new(shards: Vec<Shard>) -> Result<Self, Error> {
+        if shards.is_empty() {
+            return Err(Error::InvalidStoreConfig(
+                "a sharded store needs at least one shard".to_string(),
+            ));
+        }
+
+        let seeds = shards
+            .iter()
+            .map(|shard| first_u64(&Sha256::digest(shard.id.as_bytes())))
+            .collect();
+
+        Ok(Self { shards, seeds })
+    }
+
+    /// Index of the shard owning `key`
+    fn shard_index(&self, key: &DigestInfo) -> usize {
+        let key_hash = first_u64(&key.packed_hash);
+
+        self.seeds
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, seed)| splitmix64(key_hash ^ **seed))
+            .map(|(index, _)| index)
+            .unwrap_or_default()
+    }
+
+    fn shard_for(&self, key: &DigestInfo) -> &Arc<StoreKind> {
+        &self.shards[self.shard_index(key)].store
+    }
+
+    /// Groups `items` by the shard owning their key, remembering the position of every item
+    fn split<T>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        key: impl Fn(&T) -> &DigestInfo,
+    ) -> HashMap<usize, (Vec<usize>, Vec<T>)> {
+        let mut groups: HashMap<usize, (Vec<usize>, Vec<T>)> = HashMap::new();
+
+        for (position, item) in items.into_iter().enumerate() {
+            let (positions, group) = groups.entry(self.shard_index(key(&item))).or_default();
+            positions.push(position);
+            group.push(item);
+        }
+
+        groups
+    }
+
+    /// Puts the results of every shard back in the order of the original items
+    fn merge<R>(len: usize, results: Vec<(Vec<usize>, Vec<R>)>) -> Vec<R> {
+        let mut merged: Vec<Option<R>> = (0..len).map(|_| None).collect();
+
+        for (positions, results) in results {
+            for (position, result) in positions.into_iter().zip(results) {
+                merged[position] = Some(result);
+            }
+        }
+
+        merged
+            .into_iter()
+            .map(|result| result.expect("every item belongs to exactly one shard"))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Store for ShardedStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        self.shard_for(key).contains_key(key).await
+    }
+
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        let lookups = self.split(keys.iter().cloned(), |key| key).into_iter().map(
+            |(index, (positions, keys))| async move {
+                (
+                    positions,
+                    self.shards[index].store.contains_keys(&keys).await,
+                )
+            },
+        );
+
+        Self::merge(keys.len(), futures::future::join_all(lookups).await)
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        self.shard_for(key).touch(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn prioritize(&self, key: &DigestInfo) -> bool {
+        self.shard_for(key).prioritize(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        self.shard_for(key).get_chunk(key, offset, limit).await
+    }
+
+    #[instrument(skip(self, keys))]
+    async fn get_many(&self, keys: &[DigestInfo]) -> Vec<Result<Bytes, Error>> {
+        let reads = self.split(keys.iter().cloned(), |key| key).into_iter().map(
+            |(index, (positions, keys))| async move {
+                (positions, self.shards[index].store.get_many(&keys).await)
+            },
+        );
+
+        Self::merge(keys.len(), futures::future::join_all(reads).await)
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        self.shard_for(&key).put(key, bytes).await
+    }
+
+    #[instrument(skip(self, entries))]
+    async fn put_many(&self, entries: Vec<(DigestInfo, Bytes)>) -> Vec<Result<(), Error>> {
+        let len = entries.len();
+        let writes = self.split(entries, |(key, _)| key).into_iter().map(
+            |(index, (positions, entries))| async move {
+                (positions, self.shards[index].store.put_many(entries).await)
+            },
+        );
+
+        Self::merge(len, futures::future::join_all(writes).await)
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        self.shard_for(key).remove(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        self.shard_for(key).size_of(key).await
+    }
+
+    /// Clears every shard that can be cleared, remote nodes have to be flushed on their own
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        for shard in &self.shards {
+            match shard.store.clear().await {
+                Ok(()) | Err(Error::UnsupportedOperation(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.store.clone())
+            .collect()
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats::layered("sharded", &self.tiers())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infrastructure::memory::MemoryStore;
+
+    fn store(ids: &[String]) -> ShardedStore {
+        let shards = ids
+            .iter()
+            .map(|id| {
+                Shard::new(
+                    id.clone(),
+                    Arc::new(StoreKind::from(MemoryStore::new(1024))),
+                )
+            })
+            .collect();
+
+        ShardedStore::new(shards).unwrap()
+    }
+
+    fn ids(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|node| format!("http://bache-{node}:50051"))
+            .collect()
+    }
+
+    fn keys() -> Vec<DigestInfo> {
+        (0..10_000u32)
+            .map(|key| DigestInfo::compute(&key.to_le_bytes()))
+            .collect()
+    }
+
+    /// Id of the shard owning every key
+    fn owners(store: &ShardedStore, keys: &[DigestInfo]) -> Vec<String> {
+        keys.iter()
+            .map(|key| store.shards[store.shard_index(key)].id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn adding_a_shard_only_moves_the_keys_it_wins() {
+        let keys = keys();
+        let before = owners(&store(&ids(4)), &keys);
+        let after = owners(&store(&ids(5)), &keys);
+        let added = &ids(5)[4];
+
+        let mut moved = 0;
+        for (before, after) in before.iter().zip(&after) {
+            if before != after {
+                assert_eq!(
+                    after, added,
+                    "a key moved to a shard that was already there"
+                );
+                moved += 1;
+            }
+        }
+
+        // the new shard takes about a fifth of the keys
+        assert!((1_500..2_500).contains(&moved), "{moved} keys moved");
+    }
+
+    #[test]
+    fn removing_a_shard_only_moves_the_keys_it_held() {
+        let keys = keys();
+        let all = ids(5);
+        let removed = &all[2];
+        let remaining: Vec<_> = all.iter().filter(|id| *id != removed).cloned().collect();
+
+        let before = owners(&store(&all), &keys);
+        let after = owners(&store(&remaining), &keys);
+
+        for (before, after) in before.iter().zip(&after) {
+            if before != removed {
+                assert_eq!(before, after, "a key of a remaining shard moved");
+            }
+        }
+    }
+
+    #[test]
+    fn split_and_merge_round_trip() {
+        let store = store(&ids(3));
+        let keys = keys();
+
+        let groups = store.split(keys.iter().cloned(), |key| key);
+        assert_eq!(groups.len(), 3);
+
+        let results = groups
+            .into_iter()
+            .map(|(index, (positions, group))| {
+                assert!(group.iter().all(|key| store.shard_index(key) == index));
+                (positions, group)
+            })
+            .collect();
+
+        assert_eq!(ShardedStore::merge(keys.len(), results), keys);
+    }
+}
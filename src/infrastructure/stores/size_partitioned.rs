@@ -0,0 +1,209 @@
+use std::{fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::instrument;
+
+use super::{Store, StoreKind, StoreStats};
+use crate::{domain::DigestInfo, errors::Error};
+
+/// Store sending entries of at most `threshold` bytes to one store and larger ones to another,
+/// such as small entries to an embedded key-value store and larger ones to a blob store.
+///
+/// The size of a CAS blob is part of its key, so a content addressed store knows where to look
+/// for it right away. The size of any other entry, such as an ActionResult, is only known once
+/// written, so it is looked for in the store for small entries first, and then in the other one.
+#[derive(Clone)]
+pub struct SizePartitionedStore {
+    small: Arc<StoreKind>,
+    large: Arc<StoreKind>,
+    threshold: usize,
+    content_addressed: bool,
+}
+
+impl Debug for SizePartitionedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SizePartitionedStore")
+            .field("small", &self.small)
+            .field("large", &self.large)
+            .field("threshold", &self.threshold)
+            .field("content_addressed", &self.content_addressed)
+            .finish()
+    }
+}
+
+impl SizePartitionedStore {
+    pub fn new(
+        small: Arc<StoreKind>,
+        large: Arc<StoreKind>,
+        threshold: usize,
+        content_addressed: bool,
+    ) -> Self {
+        Self {
+            small,
+            large,
+            threshold,
+            content_addressed,
+        }
+    }
+
+    fn store_for_size(&self, size: usize) -> &Arc<StoreKind> {
+        if size <= self.threshold {
+            &self.small
+        } else {
+            &self.large
+        }
+    }
+
+    /// The store holding `key`, when that can be told from the key alone
+    fn store_for_key(&self, key: &DigestInfo) -> Option<&Arc<StoreKind>> {
+        self.content_addressed
+            .then(|| self.store_for_size(key.size_bytes as usize))
+    }
+}
+
+#[async_trait]
+impl Store for SizePartitionedStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        match self.store_for_key(key) {
+            Some(store) => store.contains_key(key).await,
+            None => self.small.contains_key(key).await || self.large.contains_key(key).await,
+        }
+    }
+
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        if !self.content_addressed {
+            let mut present = self.small.contains_keys(keys).await;
+
+            let (positions, missing): (Vec<usize>, Vec<DigestInfo>) = keys
+                .iter()
+                .enumerate()
+                .filter(|(position, _)| !present[*position])
+                .map(|(position, key)| (position, key.clone()))
+                .unzip();
+
+            if !missing.is_empty() {
+                for (position, found) in positions
+                    .into_iter()
+                    .zip(self.large.contains_keys(&missing).await)
+                {
+                    present[position] = found;
+                }
+            }
+
+            return present;
+        }
+
+        // every store is asked about its own keys in a single batch
+        let (small, large): (Vec<_>, Vec<_>) = keys
+            .iter()
+            .cloned()
+            .enumerate()
+            .partition(|(_, key)| key.size_bytes as usize <= self.threshold);
+
+        let mut present = vec![false; keys.len()];
+        for (store, entries) in [(&self.small, small), (&self.large, large)] {
+            if entries.is_empty() {
+                continue;
+            }
+
+            let (positions, keys): (Vec<usize>, Vec<DigestInfo>) = entries.into_iter().unzip();
+            for (position, found) in positions.into_iter().zip(store.contains_keys(&keys).await) {
+                present[position] = found;
+            }
+        }
+
+        present
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        match self.store_for_key(key) {
+            Some(store) => store.touch(key).await,
+            None => self.small.touch(key).await || self.large.touch(key).await,
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        if let Some(store) = self.store_for_key(key) {
+            return store.get_chunk(key, offset, limit).await;
+        }
+
+        match self.small.get_chunk(key, offset, limit).await {
+            Err(Error::DigestInfoNotFound(_)) => self.large.get_chunk(key, offset, limit).await,
+            result => result,
+        }
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        let store = self.store_for_size(bytes.len());
+
+        if !self.content_addressed {
+            // an entry changing size must not leave its previous value behind in the other store
+            let other = if Arc::ptr_eq(store, &self.small) {
+                &self.large
+            } else {
+                &self.small
+            };
+
+            match other.remove(&key).await {
+                Ok(()) | Err(Error::UnsupportedOperation(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        store.put(key, bytes).await
+    }
+
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        if let Some(store) = self.store_for_key(key) {
+            return store.remove(key).await;
+        }
+
+        self.small.remove(key).await?;
+        self.large.remove(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        match self.store_for_key(key) {
+            Some(store) => store.size_of(key).await,
+            None => match self.small.size_of(key).await {
+                Some(size) => Some(size),
+                None => self.large.size_of(key).await,
+            },
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn list_keys(&self) -> Result<Vec<DigestInfo>, Error> {
+        let mut keys = self.small.list_keys().await?;
+        keys.extend(self.large.list_keys().await?);
+
+        Ok(keys)
+    }
+
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        self.small.clear().await?;
+        self.large.clear().await
+    }
+
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        vec![self.small.clone(), self.large.clone()]
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats::layered("size_partitioned", &self.tiers())
+    }
+}
@@ -0,0 +1,144 @@
+use std::{fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tracing::instrument;
+
+use super::{slice_chunk, Store, StoreKind, StoreStats};
+use crate::{domain::DigestInfo, errors::Error};
+
+/// Store putting a fast, usually small, store in front of a slow one, such as a memory store in
+/// front of an upstream cache.
+///
+/// Writes go through to both stores. Reads are served by the fast store when possible, and
+/// otherwise by the slow one, copying the entry into the fast store on the way back.
+#[derive(Clone)]
+pub struct TieredStore {
+    fast: Arc<StoreKind>,
+    slow: Arc<StoreKind>,
+}
+
+impl Debug for TieredStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TieredStore")
+            .field("fast", &self.fast)
+            .field("slow", &self.slow)
+            .finish()
+    }
+}
+
+impl TieredStore {
+    pub fn new(fast: Arc<StoreKind>, slow: Arc<StoreKind>) -> Self {
+        Self { fast, slow }
+    }
+
+    /// Reads `key` from the slow store, keeping a copy of it in the fast one
+    async fn fetch_from_slow(&self, key: &DigestInfo) -> Result<Bytes, Error> {
+        let bytes = self.slow.get(key).await?;
+
+        if let Err(err) = self.fast.put(key.clone(), bytes.clone()).await {
+            tracing::warn!(%err, "failed to copy entry into the fast tier");
+        }
+
+        Ok(bytes)
+    }
+}
+
+#[async_trait]
+impl Store for TieredStore {
+    #[instrument(skip(self))]
+    async fn contains_key(&self, key: &DigestInfo) -> bool {
+        self.fast.contains_key(key).await || self.slow.contains_key(key).await
+    }
+
+    /// Only asks the slow store about the keys missing from the fast one
+    #[instrument(skip(self, keys))]
+    async fn contains_keys(&self, keys: &[DigestInfo]) -> Vec<bool> {
+        let mut present = self.fast.contains_keys(keys).await;
+
+        let (positions, missing): (Vec<usize>, Vec<DigestInfo>) = keys
+            .iter()
+            .enumerate()
+            .filter(|(position, _)| !present[*position])
+            .map(|(position, key)| (position, key.clone()))
+            .unzip();
+
+        if !missing.is_empty() {
+            for (position, found) in positions
+                .into_iter()
+                .zip(self.slow.contains_keys(&missing).await)
+            {
+                present[position] = found;
+            }
+        }
+
+        present
+    }
+
+    #[instrument(skip(self))]
+    async fn touch(&self, key: &DigestInfo) -> bool {
+        self.fast.touch(key).await || self.slow.touch(key).await
+    }
+
+    #[instrument(skip(self))]
+    async fn get_chunk(
+        &self,
+        key: &DigestInfo,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Bytes, Error> {
+        match self.fast.get_chunk(key, offset, limit).await {
+            Err(Error::DigestInfoNotFound(_)) => {
+                let bytes = self.fetch_from_slow(key).await?;
+
+                Ok(slice_chunk(&bytes, offset, limit))
+            }
+            result => result,
+        }
+    }
+
+    #[instrument(skip(self, bytes))]
+    async fn put(&self, key: DigestInfo, bytes: Bytes) -> Result<(), Error> {
+        self.fast.put(key.clone(), bytes.clone()).await?;
+
+        self.slow.put(key, bytes).await
+    }
+
+    /// Removes the entry from both stores, when the slow one allows removing entries
+    #[instrument(skip(self))]
+    async fn remove(&self, key: &DigestInfo) -> Result<(), Error> {
+        self.fast.remove(key).await?;
+
+        match self.slow.remove(key).await {
+            Ok(()) | Err(Error::UnsupportedOperation(_)) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn size_of(&self, key: &DigestInfo) -> Option<usize> {
+        match self.fast.size_of(key).await {
+            Some(size) => Some(size),
+            None => self.slow.size_of(key).await,
+        }
+    }
+
+    /// Clears both stores, when the slow one allows removing entries
+    #[instrument(skip(self))]
+    async fn clear(&self) -> Result<(), Error> {
+        self.fast.clear().await?;
+
+        match self.slow.clear().await {
+            Ok(()) | Err(Error::UnsupportedOperation(_)) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn tiers(&self) -> Vec<Arc<StoreKind>> {
+        vec![self.fast.clone(), self.slow.clone()]
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats::layered("tiered", &self.tiers())
+    }
+}
@@ -1,8 +1,15 @@
+pub mod client;
 pub mod config;
 pub mod domain;
+pub mod drain;
 pub mod errors;
 pub mod infrastructure;
 pub mod protos;
+pub mod rate_limit;
+pub mod replication;
+pub mod scheduler;
 pub mod server;
 pub mod services;
 pub mod tracing;
+pub mod uploads;
+pub mod worker;
@@ -1,4 +1,4 @@
-use bache::{config::Args, server};
+use bache::{client, config::Args, server};
 use clap::Parser;
 use dotenv::dotenv;
 
@@ -8,5 +8,8 @@ async fn main() -> eyre::Result<()> {
 
     let args = Args::parse();
 
-    server::start(args).await
+    match args.command {
+        Some(command) => client::run(command).await,
+        None => server::start(args).await,
+    }
 }
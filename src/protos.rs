@@ -1,6 +1,23 @@
+// generated code is not held to our lint standards
+#![allow(clippy::all)]
+
 pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
     tonic::include_file_descriptor_set!("bache_descriptor");
 
+pub mod bache {
+    pub mod admin {
+        pub mod v1 {
+            tonic::include_proto!("bache.admin.v1");
+        }
+    }
+
+    pub mod worker {
+        pub mod v1 {
+            tonic::include_proto!("bache.worker.v1");
+        }
+    }
+}
+
 pub mod build {
     pub mod bazel {
         pub mod semver {
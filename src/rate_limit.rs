@@ -0,0 +1,589 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    net::IpAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use clap::Parser;
+use futures::{future::BoxFuture, TryStreamExt};
+use http::{HeaderMap, Request, Response};
+use http_body::Body as HttpBody;
+use hyper::Body;
+use moka::sync::Cache;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use prost::Message;
+use sha2::{Digest as _, Sha256};
+use subtle::ConstantTimeEq;
+use tonic::{body::BoxBody, transport::server::TcpConnectInfo, Code, Status};
+use tower::{Layer, Service};
+
+use crate::protos::google::rpc::{
+    quota_failure::Violation, QuotaFailure, RetryInfo, Status as RpcStatus,
+};
+
+/// How long a client is remembered once it stops sending requests
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Requests to these services are never limited, orchestrators must always be able to probe
+/// the server
+const EXEMPT_PATH_PREFIXES: &[&str] = &["/grpc.health.v1.Health/"];
+
+#[derive(Parser, Debug, Clone)]
+#[clap(rename_all = "kebab-case", next_help_heading = "RATE LIMIT CONFIGS")]
+pub struct RateLimitConfig {
+    /// Requests per second each client may send. Unlimited when unset
+    #[clap(long, env = "BACHE_RATE_LIMIT_REQUESTS_PER_SECOND")]
+    pub rate_limit_requests_per_second: Option<u32>,
+
+    /// Requests a client may send at once above its rate, after having been idle. Defaults to a
+    /// second worth of requests
+    #[clap(long, env = "BACHE_RATE_LIMIT_REQUEST_BURST")]
+    pub rate_limit_request_burst: Option<u32>,
+
+    /// Bytes per second each client may upload and download, counted together. Unlimited when
+    /// unset
+    #[clap(long, env = "BACHE_RATE_LIMIT_BYTES_PER_SECOND")]
+    pub rate_limit_bytes_per_second: Option<u64>,
+
+    /// Requests each client may have in flight at once. Unlimited when unset
+    #[clap(long, env = "BACHE_RATE_LIMIT_MAX_CONCURRENT_REQUESTS")]
+    pub rate_limit_max_concurrent_requests: Option<usize>,
+}
+
+/// Who a request is accounted to. The server does not terminate TLS itself, so clients cannot be
+/// told by the common name of their certificate: behind a proxy checking client certificates,
+/// they are told by the bearer token the proxy forwards, or by the IP address of the proxy
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ClientIdentity {
+    /// Clients sending one of the configured bearer tokens, identified by a hash of it so that it
+    /// never shows up in metrics or logs
+    Token(String),
+    Ip(IpAddr),
+    Unknown,
+}
+
+impl ClientIdentity {
+    /// Tells the client by its bearer token only when it is one of `credentials`, as anyone can
+    /// make up a new token for every request
+    fn of<B>(request: &Request<B>, credentials: &Credentials) -> Self {
+        if let Some(hash) = bearer_token(request.headers())
+            .map(|token| Sha256::digest(token.as_bytes()))
+            .filter(|hash| credentials.client_token_hashes.contains(hash.as_slice()))
+        {
+            return Self::Token(hex::encode(&hash[..8]));
+        }
+
+        request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(|connect_info| connect_info.remote_addr())
+            .map_or(Self::Unknown, |addr| Self::Ip(addr.ip()))
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Token(hash) => write!(f, "token:{hash}"),
+            Self::Ip(ip) => write!(f, "ip:{ip}"),
+            Self::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+/// The credentials configured on the server, which requests are checked against
+#[derive(Clone, Default)]
+struct Credentials {
+    /// Hashes of the tokens clients authenticate with, compared instead of the tokens themselves
+    /// so that looking them up does not tell how much of a guessed token is right
+    client_token_hashes: HashSet<Vec<u8>>,
+    peer_authorization: Option<String>,
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Token bucket refilled continuously at `rate` tokens per second, up to `capacity`
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated_at = now;
+    }
+
+    /// Takes a token, or tells how long until one is available
+    fn try_take(&mut self) -> Result<(), Duration> {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+
+    /// Takes `amount` tokens, going into debt when there are not enough of them
+    fn charge(&mut self, amount: f64) {
+        self.refill();
+        self.tokens -= amount;
+    }
+
+    /// Whether the bucket is out of debt, or how long until it is
+    fn check(&mut self) -> Result<(), Duration> {
+        self.refill();
+
+        if self.tokens >= 0.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(-self.tokens / self.rate))
+        }
+    }
+}
+
+/// Usage of a single client
+struct ClientState {
+    requests: Option<Mutex<TokenBucket>>,
+    bytes: Option<Mutex<TokenBucket>>,
+    in_flight: AtomicUsize,
+}
+
+impl ClientState {
+    fn charge_bytes(&self, amount: usize) {
+        if let Some(bytes) = &self.bytes {
+            bytes.lock().unwrap().charge(amount as f64);
+        }
+    }
+}
+
+/// Why a request was turned away
+#[derive(Debug, Clone, Copy)]
+enum Throttle {
+    RequestRate(Duration),
+    Bandwidth(Duration),
+    Concurrency,
+}
+
+impl Throttle {
+    fn reason(&self) -> &'static str {
+        match self {
+            Self::RequestRate(_) => "request_rate",
+            Self::Bandwidth(_) => "bandwidth",
+            Self::Concurrency => "concurrency",
+        }
+    }
+
+    /// Status sent back to the client, with `RetryInfo` and `QuotaFailure` details
+    fn into_status(self, client: &ClientIdentity) -> Status {
+        let (description, retry_delay) = match self {
+            Self::RequestRate(delay) => ("Request rate limit exceeded", delay),
+            Self::Bandwidth(delay) => ("Bandwidth limit exceeded", delay),
+            // there is no telling when a request will finish, so suggest a short pause
+            Self::Concurrency => ("Concurrent request limit exceeded", Duration::from_secs(1)),
+        };
+
+        let retry_info = RetryInfo {
+            retry_delay: Some(prost_types::Duration {
+                seconds: retry_delay.as_secs() as i64,
+                nanos: retry_delay.subsec_nanos() as i32,
+            }),
+        };
+        let quota_failure = QuotaFailure {
+            violations: vec![Violation {
+                subject: client.to_string(),
+                description: description.to_string(),
+            }],
+        };
+
+        let details = RpcStatus {
+            code: Code::ResourceExhausted as i32,
+            message: description.to_string(),
+            details: vec![
+                prost_types::Any {
+                    type_url: "type.googleapis.com/google.rpc.RetryInfo".to_string(),
+                    value: retry_info.encode_to_vec(),
+                },
+                prost_types::Any {
+                    type_url: "type.googleapis.com/google.rpc.QuotaFailure".to_string(),
+                    value: quota_failure.encode_to_vec(),
+                },
+            ],
+        };
+
+        Status::with_details(
+            Code::ResourceExhausted,
+            description,
+            Bytes::from(details.encode_to_vec()),
+        )
+    }
+}
+
+/// Keeps track of the usage of every client, deciding which requests go through
+struct RateLimiter {
+    config: RateLimitConfig,
+    clients: Cache<ClientIdentity, Arc<ClientState>>,
+    throttled_requests: Counter<u64>,
+}
+
+impl RateLimiter {
+    fn new(config: RateLimitConfig) -> Self {
+        let throttled_requests = global::meter("bache")
+            .u64_counter("bache.rate_limit.throttled_requests")
+            .with_description("Requests turned away for going over a client's limits")
+            .init();
+
+        Self {
+            config,
+            clients: Cache::builder().time_to_idle(CLIENT_IDLE_TIMEOUT).build(),
+            throttled_requests,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.config.rate_limit_requests_per_second.is_some()
+            || self.config.rate_limit_bytes_per_second.is_some()
+            || self.config.rate_limit_max_concurrent_requests.is_some()
+    }
+
+    fn client_state(&self, client: &ClientIdentity) -> Arc<ClientState> {
+        self.clients.get_with(client.clone(), || {
+            let requests = self.config.rate_limit_requests_per_second.map(|rate| {
+                let burst = self.config.rate_limit_request_burst.unwrap_or(rate);
+
+                Mutex::new(TokenBucket::new(rate.into(), burst.max(1).into()))
+            });
+            let bytes = self.config.rate_limit_bytes_per_second.map(|rate| {
+                // a second worth of bytes can go through at once
+                Mutex::new(TokenBucket::new(rate as f64, rate as f64))
+            });
+
+            Arc::new(ClientState {
+                requests,
+                bytes,
+                in_flight: AtomicUsize::new(0),
+            })
+        })
+    }
+
+    /// Lets the request of `client` in, counting it as in flight until the returned guard is
+    /// dropped
+    fn admit(&self, client: &ClientIdentity) -> Result<InFlightGuard, Throttle> {
+        let state = self.client_state(client);
+
+        if let Some(bytes) = &state.bytes {
+            bytes.lock().unwrap().check().map_err(Throttle::Bandwidth)?;
+        }
+
+        if let Some(max_concurrent_requests) = self.config.rate_limit_max_concurrent_requests {
+            if state.in_flight.load(Ordering::Relaxed) >= max_concurrent_requests {
+                return Err(Throttle::Concurrency);
+            }
+        }
+
+        if let Some(requests) = &state.requests {
+            requests
+                .lock()
+                .unwrap()
+                .try_take()
+                .map_err(Throttle::RequestRate)?;
+        }
+
+        state.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        Ok(InFlightGuard { state })
+    }
+}
+
+/// Counts a request as in flight for as long as it lives, and charges the bytes of its bodies
+/// to its client
+struct InFlightGuard {
+    state: Arc<ClientState>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.state.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Response body charging the bytes it sends to the client, and keeping the request in flight
+/// until it is fully sent
+pub struct MeteredBody {
+    inner: BoxBody,
+    guard: Option<InFlightGuard>,
+}
+
+impl HttpBody for MeteredBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_data(cx);
+
+        if let (Poll::Ready(Some(Ok(data))), Some(guard)) = (&poll, &self.guard) {
+            guard.state.charge_bytes(data.len());
+        }
+
+        poll
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Tower layer enforcing per client request rate, bandwidth and concurrency limits, see
+/// [`RateLimitConfig`]. Clients are told by their bearer token when it is one of the configured
+/// ones, and by their IP address otherwise
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    credentials: Credentials,
+}
+
+impl RateLimitLayer {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            limiter: Arc::new(RateLimiter::new(config)),
+            credentials: Credentials::default(),
+        }
+    }
+
+    /// Tells apart the clients sending one of `tokens` as their bearer token, the others being
+    /// told by their IP address
+    pub fn with_client_tokens<'a>(mut self, tokens: impl IntoIterator<Item = &'a str>) -> Self {
+        self.credentials.client_token_hashes = tokens
+            .into_iter()
+            .map(|token| Sha256::digest(token.as_bytes()).to_vec())
+            .collect();
+
+        self
+    }
+
+    /// Never limits the requests of the other nodes of the cluster, which send `peer_token`
+    pub fn with_peer_token(mut self, peer_token: Option<&str>) -> Self {
+        self.credentials.peer_authorization = peer_token.map(|token| format!("Bearer {token}"));
+
+        self
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+            credentials: Arc::new(self.credentials.clone()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    credentials: Arc<Credentials>,
+}
+
+impl<S> RateLimit<S> {
+    /// Whether `request` comes from another node of the cluster
+    fn is_from_peer<B>(&self, request: &Request<B>) -> bool {
+        let (Some(expected), Some(authorization)) = (
+            &self.credentials.peer_authorization,
+            request.headers().get(http::header::AUTHORIZATION),
+        ) else {
+            return false;
+        };
+
+        // compared in constant time, not to tell how much of a guessed token is right
+        authorization.as_bytes().ct_eq(expected.as_bytes()).into()
+    }
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<MeteredBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // the service that was polled ready is the one that has to be called
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let exempt = EXEMPT_PATH_PREFIXES
+            .iter()
+            .any(|prefix| request.uri().path().starts_with(prefix))
+            || self.is_from_peer(&request);
+
+        if exempt || !self.limiter.is_enabled() {
+            return Box::pin(async move {
+                let response = inner.call(request).await?;
+
+                Ok(response.map(|inner| MeteredBody { inner, guard: None }))
+            });
+        }
+
+        let client = ClientIdentity::of(&request, &self.credentials);
+        let guard = match self.limiter.admit(&client) {
+            Ok(guard) => guard,
+            Err(throttle) => {
+                tracing::debug!(%client, reason = throttle.reason(), "throttled request");
+                // clients are not a label, there is no bound to how many of them there are
+                self.limiter
+                    .throttled_requests
+                    .add(1, &[KeyValue::new("reason", throttle.reason())]);
+
+                let response = throttle.into_status(&client).to_http();
+
+                return Box::pin(async move {
+                    Ok(response.map(|inner| MeteredBody { inner, guard: None }))
+                });
+            }
+        };
+
+        // uploaded bytes are charged as they come in
+        let state = guard.state.clone();
+        let request = request.map(|body| {
+            Body::wrap_stream(body.inspect_ok(move |chunk| state.charge_bytes(chunk.len())))
+        });
+
+        Box::pin(async move {
+            let response = inner.call(request).await?;
+
+            Ok(response.map(|inner| MeteredBody {
+                inner,
+                guard: Some(guard),
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bucket as it would be `elapsed` after its last update
+    fn after(mut bucket: TokenBucket, elapsed: Duration) -> TokenBucket {
+        bucket.updated_at -= elapsed;
+        bucket
+    }
+
+    fn assert_close(delay: Duration, expected_millis: u64) {
+        let delay = delay.as_millis() as u64;
+        assert!(
+            delay.abs_diff(expected_millis) <= 10,
+            "{delay}ms instead of {expected_millis}ms"
+        );
+    }
+
+    fn request_with_token(token: &str) -> Request<()> {
+        Request::builder()
+            .header(http::header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn only_configured_tokens_identify_clients() {
+        let layer = RateLimitLayer::new(RateLimitConfig::parse_from(["bache"]))
+            .with_client_tokens(["known"]);
+
+        let known = ClientIdentity::of(&request_with_token("known"), &layer.credentials);
+        let made_up = ClientIdentity::of(&request_with_token("made-up"), &layer.credentials);
+
+        assert!(matches!(known, ClientIdentity::Token(_)));
+        assert_eq!(made_up, ClientIdentity::Unknown);
+    }
+
+    #[test]
+    fn token_bucket_refills_at_its_rate_up_to_its_capacity() {
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+
+        let mut bucket = after(bucket, Duration::from_millis(150));
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+
+        let mut bucket = after(bucket, Duration::from_secs(60));
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_ok());
+        assert!(bucket.try_take().is_err());
+    }
+
+    #[test]
+    fn token_bucket_tells_how_long_until_a_token() {
+        let mut bucket = TokenBucket::new(4.0, 1.0);
+        assert!(bucket.try_take().is_ok());
+
+        assert_close(bucket.try_take().unwrap_err(), 250);
+    }
+
+    #[test]
+    fn token_bucket_goes_into_debt_and_out_of_it() {
+        let mut bucket = TokenBucket::new(10.0, 10.0);
+        assert!(bucket.check().is_ok());
+
+        bucket.charge(15.0);
+        assert_close(bucket.check().unwrap_err(), 500);
+
+        let mut bucket = after(bucket, Duration::from_millis(500));
+        assert!(bucket.check().is_ok());
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    future::Future,
+    task::{Context, Poll},
+};
+
+use futures::future::BoxFuture;
+use http::{Request, Response};
+use hyper::Body;
+use tonic::body::BoxBody;
+use tower::{Layer, Service};
+
+/// Header of the requests a `ReplicatedStore` sends to its peers. Peers answer them from their
+/// local store only, rather than replicating the writes or asking their own peers on misses,
+/// which would bounce requests between the nodes forever
+pub const REPLICATED_HEADER: &str = "x-bache-replicated";
+
+tokio::task_local! {
+    static REPLICATED_REQUEST: bool;
+}
+
+/// Whether the request being served was sent by a replicating peer
+pub fn is_replicated_request() -> bool {
+    REPLICATED_REQUEST
+        .try_with(|replicated| *replicated)
+        .unwrap_or(false)
+}
+
+/// Runs `future` as serving a request of a replicating peer, or not
+async fn scope<F: Future>(replicated: bool, future: F) -> F::Output {
+    REPLICATED_REQUEST.scope(replicated, future).await
+}
+
+/// Tower layer telling the stores whether each request comes from a replicating peer, see
+/// [`is_replicated_request`]
+#[derive(Clone, Default)]
+pub struct ReplicationLayer;
+
+impl<S> Layer<S> for ReplicationLayer {
+    type Service = Replication<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Replication { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct Replication<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for Replication<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // the service that was polled ready is the one that has to be called
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let replicated = request.headers().contains_key(REPLICATED_HEADER);
+
+        Box::pin(scope(replicated, inner.call(request)))
+    }
+}
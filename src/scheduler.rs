@@ -0,0 +1,658 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use prost::Message;
+use tokio::{sync::watch, time::Instant};
+use uuid::Uuid;
+
+use crate::{
+    domain::{DigestInfo, InstanceName},
+    errors::Error,
+    infrastructure::{Store, StoreManager},
+    protos::{
+        build::bazel::remote::execution::v2::{
+            execution_stage::Value as ExecutionStage, ActionResult, ExecuteOperationMetadata,
+            ExecuteResponse, Platform,
+        },
+        google::{
+            longrunning::{operation, Operation},
+            rpc::Status as RpcStatus,
+        },
+    },
+};
+
+const EXECUTE_OPERATION_METADATA_TYPE_URL: &str =
+    "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteOperationMetadata";
+const EXECUTE_RESPONSE_TYPE_URL: &str =
+    "type.googleapis.com/build.bazel.remote.execution.v2.ExecuteResponse";
+
+#[derive(Debug, Clone, Copy)]
+pub struct SchedulerOptions {
+    /// How long a worker keeps an action without extending its lease on it
+    pub lease_duration: Duration,
+    /// How long a worker polling for work waits for an action to come up
+    pub poll_timeout: Duration,
+    /// How long the completed operations can still be waited on
+    pub operation_retention: Duration,
+    /// Times an action is handed out before giving up on it, when its workers keep going away
+    pub max_attempts: u32,
+    /// How long an action waits in the queue for a worker before giving up on it, e.g. when no
+    /// worker has its platform
+    pub queue_timeout: Duration,
+}
+
+impl Default for SchedulerOptions {
+    fn default() -> Self {
+        Self {
+            lease_duration: Duration::from_secs(30),
+            poll_timeout: Duration::from_secs(20),
+            operation_retention: Duration::from_secs(300),
+            max_attempts: 3,
+            queue_timeout: Duration::from_secs(600),
+        }
+    }
+}
+
+/// An action to run, as read from the CAS
+#[derive(Debug, Clone)]
+pub struct ExecutionSpec {
+    /// Name of the instance serving the action, once routed
+    pub instance_name: InstanceName,
+    pub action_digest: DigestInfo,
+    /// Properties the platform of the worker running the action must have
+    pub platform: Platform,
+    /// Lower values are run first
+    pub priority: i32,
+    /// Whether the result must not be written to the ActionCache
+    pub do_not_cache: bool,
+    /// Whether the result is kept for longer in the ActionCache, see [`Store::prioritize`]
+    pub prioritize_result: bool,
+}
+
+/// An action leased to a worker
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub operation_name: String,
+    pub instance_name: InstanceName,
+    pub action_digest: DigestInfo,
+    pub lease_duration: Duration,
+}
+
+#[derive(Debug)]
+struct Job {
+    operation_name: String,
+    spec: ExecutionSpec,
+    /// Position in the queue among the jobs of the same priority, kept when queued again
+    sequence: u64,
+    attempts: u32,
+    /// When the job was last queued
+    queued_at: Instant,
+}
+
+impl Job {
+    fn queue_key(&self) -> (i32, u64) {
+        (self.spec.priority, self.sequence)
+    }
+
+    fn in_flight_key(&self) -> (InstanceName, DigestInfo) {
+        (
+            self.spec.instance_name.clone(),
+            self.spec.action_digest.clone(),
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Lease {
+    job: Job,
+    worker_id: String,
+    expires_at: Instant,
+}
+
+#[derive(Debug)]
+struct TrackedOperation {
+    updates: watch::Sender<Operation>,
+    completed_at: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_sequence: u64,
+    queue: BTreeMap<(i32, u64), Job>,
+    leases: HashMap<String, Lease>,
+    /// Operation running every action queued or leased, shared by the identical executions
+    in_flight: HashMap<(InstanceName, DigestInfo), String>,
+    operations: HashMap<String, TrackedOperation>,
+}
+
+impl State {
+    fn publish(&self, operation: Operation) {
+        if let Some(tracked) = self.operations.get(&operation.name) {
+            tracked.updates.send_replace(operation);
+        }
+    }
+
+    /// Stops sending the identical executions to the operation of `job`. Actions that are not
+    /// to be cached never had their operation shared, and leave the one of the same action alone
+    fn remove_in_flight(&mut self, job: &Job) {
+        let key = job.in_flight_key();
+        if self.in_flight.get(&key) == Some(&job.operation_name) {
+            self.in_flight.remove(&key);
+        }
+    }
+
+    fn complete(&mut self, operation: Operation) {
+        if let Some(tracked) = self.operations.get_mut(&operation.name) {
+            tracked.completed_at = Some(Instant::now());
+            tracked.updates.send_replace(operation);
+        }
+    }
+}
+
+/// Queue of the actions to run, handed out to the workers whose platform matches theirs.
+///
+/// Identical actions executed while one of them is already queued or running share its
+/// operation, instead of running again, unless their results are not to be cached, which asks
+/// for them to run anew. Every action is leased to the worker running it, which
+/// has to keep extending its lease, so that the actions of the workers going away are queued
+/// again. Successful results are written to the ActionCache of the instance of the action.
+#[derive(Clone)]
+pub struct Scheduler {
+    state: Arc<Mutex<State>>,
+    /// Bumped whenever an action is queued, to wake up the polling workers
+    work_queued: Arc<watch::Sender<u64>>,
+    action_cache_stores: StoreManager,
+    options: SchedulerOptions,
+}
+
+impl Scheduler {
+    pub fn new(action_cache_stores: StoreManager, options: SchedulerOptions) -> Self {
+        Self {
+            state: Arc::default(),
+            work_queued: Arc::new(watch::channel(0).0),
+            action_cache_stores,
+            options,
+        }
+    }
+
+    /// Queues `spec`, or joins the operation of an identical action already queued or running
+    /// when its result may be cached. The updates of the operation are sent to the returned
+    /// receiver until it is done
+    pub fn schedule(&self, spec: ExecutionSpec) -> watch::Receiver<Operation> {
+        let mut state = self.state.lock().unwrap();
+
+        let in_flight_key = (spec.instance_name.clone(), spec.action_digest.clone());
+        let shared = (!spec.do_not_cache)
+            .then(|| state.in_flight.get(&in_flight_key))
+            .flatten();
+        if let Some(operation_name) = shared {
+            tracing::debug!(
+                %operation_name,
+                action_digest = %spec.action_digest,
+                "joined in-flight execution"
+            );
+
+            return state.operations[operation_name].updates.subscribe();
+        }
+
+        let operation_name = format!("operations/{}", Uuid::new_v4());
+        let (updates, receiver) = watch::channel(operation(
+            &operation_name,
+            &spec.action_digest,
+            ExecutionStage::Queued,
+            None,
+        ));
+
+        let job = Job {
+            operation_name: operation_name.clone(),
+            spec,
+            sequence: state.next_sequence,
+            attempts: 0,
+            queued_at: Instant::now(),
+        };
+        state.next_sequence += 1;
+
+        if !job.spec.do_not_cache {
+            state
+                .in_flight
+                .insert(in_flight_key, operation_name.clone());
+        }
+        state.operations.insert(
+            operation_name,
+            TrackedOperation {
+                updates,
+                completed_at: None,
+            },
+        );
+        state.queue.insert(job.queue_key(), job);
+        drop(state);
+
+        self.work_queued.send_modify(|generation| *generation += 1);
+
+        receiver
+    }
+
+    /// Receiver of the updates of the operation named `operation_name`, if it is still tracked
+    pub fn subscribe(&self, operation_name: &str) -> Result<watch::Receiver<Operation>, Error> {
+        self.state
+            .lock()
+            .unwrap()
+            .operations
+            .get(operation_name)
+            .map(|tracked| tracked.updates.subscribe())
+            .ok_or_else(|| Error::OperationNotFound(operation_name.to_string()))
+    }
+
+    /// Leases to `worker_id` the first queued action its `platform` can run, by priority then
+    /// in the order they were queued. Waits for such an action for up to the poll timeout
+    pub async fn poll(&self, worker_id: &str, platform: &Platform) -> Option<Assignment> {
+        let deadline = tokio::time::Instant::now() + self.options.poll_timeout;
+        let mut work_queued = self.work_queued.subscribe();
+
+        loop {
+            if let Some(assignment) = self.try_lease(worker_id, platform) {
+                return Some(assignment);
+            }
+
+            if tokio::time::timeout_at(deadline, work_queued.changed())
+                .await
+                .is_err()
+            {
+                return None;
+            }
+        }
+    }
+
+    fn try_lease(&self, worker_id: &str, platform: &Platform) -> Option<Assignment> {
+        let mut state = self.state.lock().unwrap();
+
+        let queue_key = state
+            .queue
+            .iter()
+            .find(|(_, job)| platform_matches(&job.spec.platform, platform))
+            .map(|(queue_key, _)| *queue_key)?;
+        let mut job = state.queue.remove(&queue_key).unwrap();
+        job.attempts += 1;
+
+        let assignment = Assignment {
+            operation_name: job.operation_name.clone(),
+            instance_name: job.spec.instance_name.clone(),
+            action_digest: job.spec.action_digest.clone(),
+            lease_duration: self.options.lease_duration,
+        };
+
+        state.publish(operation(
+            &job.operation_name,
+            &job.spec.action_digest,
+            ExecutionStage::Executing,
+            None,
+        ));
+        tracing::debug!(
+            operation_name = %job.operation_name,
+            action_digest = %job.spec.action_digest,
+            worker_id,
+            attempt = job.attempts,
+            "leased action"
+        );
+        state.leases.insert(
+            job.operation_name.clone(),
+            Lease {
+                job,
+                worker_id: worker_id.to_string(),
+                expires_at: Instant::now() + self.options.lease_duration,
+            },
+        );
+
+        Some(assignment)
+    }
+
+    /// Extends the lease of `worker_id` on the action of `operation_name`
+    pub fn keep_alive(&self, worker_id: &str, operation_name: &str) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.leases.get_mut(operation_name) {
+            Some(lease) if lease.worker_id == worker_id => {
+                lease.expires_at = Instant::now() + self.options.lease_duration;
+
+                Ok(())
+            }
+            _ => Err(Error::LeaseLost(operation_name.to_string())),
+        }
+    }
+
+    /// Ends the lease of `worker_id` on the action of `operation_name`, writing its result to the
+    /// ActionCache if it succeeded, then completes its operation
+    pub async fn complete(
+        &self,
+        worker_id: &str,
+        operation_name: &str,
+        result: ActionResult,
+        status: RpcStatus,
+    ) -> Result<(), Error> {
+        let job = {
+            let mut state = self.state.lock().unwrap();
+
+            match state.leases.get(operation_name) {
+                Some(lease) if lease.worker_id == worker_id => {}
+                _ => return Err(Error::LeaseLost(operation_name.to_string())),
+            }
+
+            let job = state.leases.remove(operation_name).unwrap().job;
+            state.remove_in_flight(&job);
+
+            job
+        };
+
+        let succeeded = status.code == tonic::Code::Ok as i32 && result.exit_code == 0;
+        if succeeded && !job.spec.do_not_cache {
+            // the client still gets the result, it is only run again next time
+            if let Err(err) = self.cache_result(&job.spec, &result).await {
+                tracing::warn!(
+                    %err,
+                    action_digest = %job.spec.action_digest,
+                    "failed to cache action result"
+                );
+            }
+        }
+
+        self.state.lock().unwrap().complete(operation(
+            &job.operation_name,
+            &job.spec.action_digest,
+            ExecutionStage::Completed,
+            Some(ExecuteResponse {
+                result: Some(result),
+                cached_result: false,
+                status: Some(status),
+                ..Default::default()
+            }),
+        ));
+
+        Ok(())
+    }
+
+    async fn cache_result(&self, spec: &ExecutionSpec, result: &ActionResult) -> Result<(), Error> {
+        let store = self
+            .action_cache_stores
+            .get_store_by_instance_name(&spec.instance_name)?;
+
+        store
+            .put(
+                spec.action_digest.clone(),
+                Bytes::from(result.encode_to_vec()),
+            )
+            .await?;
+
+        if spec.prioritize_result {
+            store.prioritize(&spec.action_digest).await;
+        }
+
+        Ok(())
+    }
+
+    /// Queues again the actions whose lease ran out, failing those handed out too many times
+    /// already as well as those queued for longer than the queue timeout, and forgets the
+    /// operations completed for longer than their retention
+    pub fn expire(&self) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        let expired: Vec<_> = state
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.expires_at <= now)
+            .map(|(operation_name, _)| operation_name.clone())
+            .collect();
+
+        let requeued = !expired.is_empty();
+        for operation_name in expired {
+            let Lease { job, worker_id, .. } = state.leases.remove(&operation_name).unwrap();
+            tracing::warn!(
+                %operation_name,
+                %worker_id,
+                attempt = job.attempts,
+                "lease on action expired"
+            );
+
+            if job.attempts < self.options.max_attempts {
+                state.publish(operation(
+                    &operation_name,
+                    &job.spec.action_digest,
+                    ExecutionStage::Queued,
+                    None,
+                ));
+                let job = Job {
+                    queued_at: now,
+                    ..job
+                };
+                state.queue.insert(job.queue_key(), job);
+                continue;
+            }
+
+            let message = format!(
+                "the workers running the action went away {} times",
+                job.attempts
+            );
+            fail(&mut state, job, message);
+        }
+
+        let timed_out: Vec<_> = state
+            .queue
+            .iter()
+            .filter(|(_, job)| now.duration_since(job.queued_at) >= self.options.queue_timeout)
+            .map(|(queue_key, _)| *queue_key)
+            .collect();
+        for queue_key in timed_out {
+            let job = state.queue.remove(&queue_key).unwrap();
+            tracing::warn!(
+                operation_name = %job.operation_name,
+                action_digest = %job.spec.action_digest,
+                "no worker took the action"
+            );
+
+            let message = format!(
+                "no worker took the action in {} seconds",
+                self.options.queue_timeout.as_secs()
+            );
+            fail(&mut state, job, message);
+        }
+
+        let retention = self.options.operation_retention;
+        state.operations.retain(|_, tracked| {
+            tracked
+                .completed_at
+                .is_none_or(|completed_at| now.duration_since(completed_at) < retention)
+        });
+        drop(state);
+
+        if requeued {
+            self.work_queued.send_modify(|generation| *generation += 1);
+        }
+    }
+}
+
+/// Completes the operation of `job` with UNAVAILABLE, as it was given up on
+fn fail(state: &mut State, job: Job, message: String) {
+    state.remove_in_flight(&job);
+
+    let status = RpcStatus {
+        code: tonic::Code::Unavailable as i32,
+        message,
+        details: Vec::new(),
+    };
+    state.complete(operation(
+        &job.operation_name,
+        &job.spec.action_digest,
+        ExecutionStage::Completed,
+        Some(ExecuteResponse {
+            status: Some(status),
+            ..Default::default()
+        }),
+    ));
+}
+
+/// Whether a worker of the `offered` platform has every property of the `required` one
+fn platform_matches(required: &Platform, offered: &Platform) -> bool {
+    required.properties.iter().all(|property| {
+        offered
+            .properties
+            .iter()
+            .any(|offer| offer.name == property.name && offer.value == property.value)
+    })
+}
+
+/// Operation of an execution at `stage`, done once given its `response`
+pub fn operation(
+    name: &str,
+    action_digest: &DigestInfo,
+    stage: ExecutionStage,
+    response: Option<ExecuteResponse>,
+) -> Operation {
+    let metadata = ExecuteOperationMetadata {
+        stage: stage.into(),
+        action_digest: Some(action_digest.clone().into()),
+        ..Default::default()
+    };
+
+    Operation {
+        name: name.to_string(),
+        metadata: Some(prost_types::Any {
+            type_url: EXECUTE_OPERATION_METADATA_TYPE_URL.to_string(),
+            value: metadata.encode_to_vec(),
+        }),
+        done: response.is_some(),
+        result: response.map(|response| {
+            operation::Result::Response(prost_types::Any {
+                type_url: EXECUTE_RESPONSE_TYPE_URL.to_string(),
+                value: response.encode_to_vec(),
+            })
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::infrastructure::{memory::MemoryStore, StoreKind};
+
+    const WORKER: &str = "worker";
+
+    fn scheduler(options: SchedulerOptions) -> Scheduler {
+        let stores = HashMap::from([(
+            InstanceName::from(""),
+            Arc::new(StoreKind::from(MemoryStore::new(1024 * 1024))),
+        )]);
+
+        Scheduler::new(StoreManager::new(stores), options)
+    }
+
+    fn spec(do_not_cache: bool) -> ExecutionSpec {
+        ExecutionSpec {
+            instance_name: InstanceName::from(""),
+            action_digest: DigestInfo::compute(b"action"),
+            platform: Platform::default(),
+            priority: 0,
+            do_not_cache,
+            prioritize_result: false,
+        }
+    }
+
+    /// Status the operation completed with, if it did
+    fn status(updates: &watch::Receiver<Operation>) -> Option<tonic::Code> {
+        match &updates.borrow().result {
+            Some(operation::Result::Response(response)) => {
+                let response = ExecuteResponse::decode(response.value.as_slice()).unwrap();
+
+                Some(response.status.unwrap_or_default().code.into())
+            }
+            _ => None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_leases_are_queued_again_until_out_of_attempts() {
+        let options = SchedulerOptions {
+            max_attempts: 2,
+            ..Default::default()
+        };
+        let scheduler = scheduler(options);
+        let updates = scheduler.schedule(spec(false));
+
+        for _ in 0..options.max_attempts {
+            assert!(scheduler.poll(WORKER, &Platform::default()).await.is_some());
+
+            tokio::time::advance(options.lease_duration).await;
+            scheduler.expire();
+        }
+        assert_eq!(status(&updates), Some(tonic::Code::Unavailable));
+        assert!(scheduler.state.lock().unwrap().queue.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn actions_no_worker_takes_time_out() {
+        let options = SchedulerOptions::default();
+        let scheduler = scheduler(options);
+        let updates = scheduler.schedule(spec(false));
+
+        tokio::time::advance(options.queue_timeout / 2).await;
+        scheduler.expire();
+        assert_eq!(status(&updates), None);
+
+        tokio::time::advance(options.queue_timeout / 2).await;
+        scheduler.expire();
+        assert_eq!(status(&updates), Some(tonic::Code::Unavailable));
+    }
+
+    #[tokio::test]
+    async fn uncached_actions_do_not_share_operations() {
+        let scheduler = scheduler(SchedulerOptions::default());
+
+        let cached = scheduler.schedule(spec(false)).borrow().name.clone();
+        let joined = scheduler.schedule(spec(false)).borrow().name.clone();
+        let uncached = scheduler.schedule(spec(true)).borrow().name.clone();
+
+        assert_eq!(joined, cached);
+        assert_ne!(uncached, cached);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn workers_that_lost_their_lease_cannot_complete() {
+        let options = SchedulerOptions::default();
+        let scheduler = scheduler(options);
+        let updates = scheduler.schedule(spec(false));
+
+        let assignment = scheduler.poll("first", &Platform::default()).await.unwrap();
+        tokio::time::advance(options.lease_duration).await;
+        scheduler.expire();
+        assert!(scheduler
+            .poll("second", &Platform::default())
+            .await
+            .is_some());
+
+        let result = scheduler
+            .complete(
+                "first",
+                &assignment.operation_name,
+                ActionResult::default(),
+                RpcStatus::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(Error::LeaseLost(_))));
+
+        scheduler
+            .complete(
+                "second",
+                &assignment.operation_name,
+                ActionResult::default(),
+                RpcStatus::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(status(&updates), Some(tonic::Code::Ok));
+    }
+}
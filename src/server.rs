@@ -1,13 +1,49 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use eyre::WrapErr;
+use tokio::sync::mpsc;
 use tonic::transport::Server;
 
 use crate::{
-    config::{Args, ServerConfig},
+    config::{Args, ServerConfig, StoreConfig},
+    domain::InstanceName,
+    drain::Drain,
+    infrastructure::{
+        coalescing::CoalescingStore,
+        dedup::{ChunkingOptions, DedupStore},
+        embedded::{self, EmbeddedStore},
+        existence_index::{BloomFilterOptions, ExistenceIndexStore},
+        grpc_upstream::{GrpcUpstreamOptions, GrpcUpstreamStore, RemoteApi},
+        memory::MemoryStore,
+        quota::{register_quota_metrics, QuotaStore, QuotaUsage},
+        redis::{RedisOptions, RedisStore},
+        reference_tracking::ReferenceTrackingStore,
+        replicated::ReplicatedStore,
+        sharded::{Shard, ShardedStore},
+        size_partitioned::SizePartitionedStore,
+        tiered::TieredStore,
+        StoreKind, StoreManager,
+    },
+    rate_limit::RateLimitLayer,
+    replication::ReplicationLayer,
+    scheduler::{Scheduler, SchedulerOptions},
+    services::{
+        action_cache::{ActionCacheService, ActionCacheWriters},
+        admin::AdminService,
+        bytestream::ByteStreamService,
+        capabilities::CapabilitiesService,
+        cas::ContentAddressableStorageService,
+        execution::ExecutionService,
+        worker::WorkerService,
+    },
     tracing,
+    uploads::PartialUploads,
 };
 
+/// Share of `cas_memory_max_bytes` the manifests of a deduplicating CAS get, the chunks getting
+/// the rest
+const DEDUP_MANIFEST_MEMORY_PERCENT: u64 = 10;
+
 fn create_socket_address(hostname: &str, port: u32) -> eyre::Result<SocketAddr> {
     format!("{hostname}:{port}").parse().wrap_err(
         "Failed to create socket address to bind to. Please ensure that the hostname and port are \
@@ -15,10 +51,593 @@ fn create_socket_address(hostname: &str, port: u32) -> eyre::Result<SocketAddr>
     )
 }
 
+/// Resolves on Ctrl-C, or on SIGTERM as sent by orchestrators
 async fn create_shutdown_signal_listener() {
-    tokio::signal::ctrl_c()
-        .await
-        .expect("Failed to create shutdown signal handler");
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to create SIGTERM signal handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.expect("Failed to create shutdown signal handler"),
+        _ = terminate => {}
+    }
+}
+
+/// Splits `max_bytes` of memory between the entries of a store and its prioritized entries
+fn split_memory(max_bytes: u64, store_config: &StoreConfig) -> (u64, u64) {
+    let retained_bytes = max_bytes / 100 * store_config.memory_retained_percent;
+
+    (max_bytes - retained_bytes, retained_bytes)
+}
+
+/// Splits `max_bytes` of memory between the manifests and the chunks of a deduplicating CAS, the
+/// manifests getting [`DEDUP_MANIFEST_MEMORY_PERCENT`] of it
+fn split_dedup_memory(max_bytes: u64) -> (u64, u64) {
+    let manifest_bytes = max_bytes / 100 * DEDUP_MANIFEST_MEMORY_PERCENT;
+
+    (manifest_bytes, max_bytes - manifest_bytes)
+}
+
+fn negative_cache_ttl(store_config: &StoreConfig) -> Duration {
+    Duration::from_millis(store_config.negative_cache_ttl_ms)
+}
+
+/// Snapshots `memory` to the configured snapshot directory, if there is one
+fn with_snapshot(
+    memory: MemoryStore,
+    instance_name: &str,
+    api: RemoteApi,
+    store_config: &StoreConfig,
+) -> MemoryStore {
+    let snapshot_dir = match &store_config.memory_snapshot_dir {
+        Some(snapshot_dir) => snapshot_dir,
+        None => return memory,
+    };
+
+    let api = match api {
+        RemoteApi::ContentAddressableStorage => "cas",
+        RemoteApi::ActionCache => "action_cache",
+    };
+    // instance names may span several path segments
+    let instance_name = instance_name.replace('%', "%25").replace('/', "%2F");
+
+    memory.with_snapshot(snapshot_dir.join(format!("{api}-{instance_name}.snapshot")))
+}
+
+/// Restores the snapshots of an instance in the background, the CAS first so that ActionCache
+/// entries find the blobs they reference, then snapshots them periodically if configured to.
+/// Each memory store is restored through the given store wrapping it
+fn spawn_snapshot_tasks(
+    cas: (MemoryStore, Arc<StoreKind>),
+    action_cache: (MemoryStore, Arc<StoreKind>),
+    store_config: &StoreConfig,
+) {
+    if store_config.memory_snapshot_dir.is_none() {
+        return;
+    }
+
+    let interval = store_config
+        .memory_snapshot_interval_seconds
+        .map(Duration::from_secs);
+
+    tokio::spawn(async move {
+        for (memory, store) in [&cas, &action_cache] {
+            if let Err(err) = memory.restore_snapshot(store).await {
+                ::tracing::error!(%err, "failed to restore memory store snapshot");
+            }
+        }
+
+        let mut interval = match interval {
+            Some(interval) => tokio::time::interval(interval),
+            None => return,
+        };
+        // the first tick completes right away
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            for (memory, _) in [&cas, &action_cache] {
+                if let Err(err) = memory.write_snapshot().await {
+                    ::tracing::error!(%err, "failed to write memory store snapshot");
+                }
+            }
+        }
+    });
+}
+
+/// Creates the scheduler of remote execution, along with the task expiring its leases
+fn spawn_scheduler(action_cache_stores: StoreManager, options: SchedulerOptions) -> Scheduler {
+    let scheduler = Scheduler::new(action_cache_stores, options);
+
+    let expiring = scheduler.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+        loop {
+            interval.tick().await;
+            expiring.expire();
+        }
+    });
+
+    scheduler
+}
+
+/// Creates the registry of partial ByteStream uploads, along with the task expiring the idle ones
+fn spawn_upload_reaper(
+    idle_timeout: Duration,
+    max_bytes: usize,
+    spill_dir: PathBuf,
+) -> PartialUploads {
+    let uploads = PartialUploads::new(idle_timeout, max_bytes).with_spill_dir(spill_dir);
+
+    let expiring = uploads.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+        loop {
+            interval.tick().await;
+            expiring.expire();
+        }
+    });
+
+    uploads
+}
+
+/// Splits the blobs of `memory` in content-defined chunks, if configured to
+fn with_dedup(memory: Arc<StoreKind>, store_config: &StoreConfig) -> eyre::Result<Arc<StoreKind>> {
+    let average_chunk_bytes = match store_config.dedup_average_chunk_bytes {
+        Some(average_chunk_bytes) => average_chunk_bytes,
+        None => return Ok(memory),
+    };
+
+    let options = ChunkingOptions::new(average_chunk_bytes)
+        .wrap_err("Invalid BACHE_DEDUP_AVERAGE_CHUNK_BYTES")?;
+    let (_, chunk_bytes) = split_dedup_memory(store_config.cas_memory_max_bytes);
+    let (max_bytes, retained_bytes) = split_memory(chunk_bytes, store_config);
+    let chunks = MemoryStore::new(max_bytes).with_retained_capacity(retained_bytes);
+
+    Ok(Arc::new(StoreKind::from(DedupStore::new(
+        memory,
+        Arc::new(StoreKind::from(chunks)),
+        options,
+    ))))
+}
+
+/// Puts `memory` in front of the configured Redis server, if there is one
+async fn with_redis(
+    memory: Arc<StoreKind>,
+    instance_name: &str,
+    api: RemoteApi,
+    store_config: &StoreConfig,
+) -> eyre::Result<Arc<StoreKind>> {
+    let url = match &store_config.redis_url {
+        Some(url) => url,
+        None => return Ok(memory),
+    };
+
+    let (api, content_addressed) = match api {
+        RemoteApi::ContentAddressableStorage => ("cas", true),
+        RemoteApi::ActionCache => ("action_cache", false),
+    };
+    let redis = RedisStore::connect(
+        url,
+        RedisOptions {
+            key_prefix: format!("bache:{api}:{instance_name}:"),
+            chunk_size: store_config.redis_chunk_bytes,
+            ttl: store_config.redis_ttl_seconds.map(Duration::from_secs),
+            content_addressed,
+        },
+    )
+    .await
+    .wrap_err_with(|| format!("Failed to connect to Redis at `{url}`"))?;
+
+    Ok(Arc::new(StoreKind::from(TieredStore::new(
+        memory,
+        Arc::new(StoreKind::from(redis)),
+    ))))
+}
+
+/// Keeps the small entries of `store` in the embedded store instead, if there is one
+fn with_embedded(
+    store: Arc<StoreKind>,
+    database: Option<&Arc<redb::Database>>,
+    instance_name: &str,
+    api: RemoteApi,
+    store_config: &StoreConfig,
+) -> eyre::Result<Arc<StoreKind>> {
+    let database = match database {
+        Some(database) => database,
+        None => return Ok(store),
+    };
+
+    let (table, content_addressed) = match api {
+        RemoteApi::ContentAddressableStorage => (format!("cas/{instance_name}"), true),
+        RemoteApi::ActionCache => (format!("action_cache/{instance_name}"), false),
+    };
+    let embedded = EmbeddedStore::new(
+        database.clone(),
+        &table,
+        store_config.embedded_store_max_bytes,
+    )
+    .wrap_err_with(|| format!("Failed to open embedded store table `{table}`"))?;
+
+    Ok(Arc::new(StoreKind::from(SizePartitionedStore::new(
+        Arc::new(StoreKind::from(embedded)),
+        store,
+        store_config.embedded_store_max_entry_bytes,
+        content_addressed,
+    ))))
+}
+
+/// Puts `store` in front of the configured upstream cache, if there is one
+fn with_upstream(
+    store: Arc<StoreKind>,
+    instance_name: &str,
+    api: RemoteApi,
+    store_config: &StoreConfig,
+) -> eyre::Result<Arc<StoreKind>> {
+    let endpoint = match &store_config.upstream_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(store),
+    };
+
+    let upstream_instance_name = store_config
+        .upstream_instance_name
+        .clone()
+        .unwrap_or_else(|| instance_name.to_string());
+
+    let upstream = GrpcUpstreamStore::with_options(
+        endpoint,
+        upstream_instance_name,
+        api,
+        GrpcUpstreamOptions {
+            connections: store_config.upstream_connections,
+            timeout: Some(Duration::from_secs(store_config.upstream_timeout_seconds)),
+            token: store_config.upstream_token.clone(),
+            ..Default::default()
+        },
+    )
+    .wrap_err("Failed to create upstream store")?;
+
+    // other clients write to the upstream cache too, so only missing blobs can be remembered
+    let upstream = Arc::new(StoreKind::from(upstream));
+    let upstream = match api {
+        RemoteApi::ContentAddressableStorage => Arc::new(StoreKind::from(
+            ExistenceIndexStore::new(upstream, negative_cache_ttl(store_config), None),
+        )),
+        RemoteApi::ActionCache => upstream,
+    };
+
+    Ok(Arc::new(StoreKind::from(TieredStore::new(store, upstream))))
+}
+
+/// Wraps `store` so that its entries are replicated to the configured peers, if there are any
+fn with_replication(
+    store: Arc<StoreKind>,
+    instance_name: &str,
+    api: RemoteApi,
+    store_config: &StoreConfig,
+) -> eyre::Result<Arc<StoreKind>> {
+    if store_config.replication_peers.is_empty() {
+        return Ok(store);
+    }
+
+    let nodes = store_config.replication_peers.len() + 1;
+    if !(1..=nodes).contains(&store_config.replication_factor) {
+        eyre::bail!(
+            "`--replication-factor` of {} must be between 1 and the {nodes} nodes replicating",
+            store_config.replication_factor
+        );
+    }
+
+    let options = GrpcUpstreamOptions {
+        timeout: Some(Duration::from_secs(
+            store_config.replication_timeout_seconds,
+        )),
+        replicated: true,
+        token: store_config.peer_token.clone(),
+        ..Default::default()
+    };
+    let peers = store_config
+        .replication_peers
+        .iter()
+        .map(|endpoint| {
+            GrpcUpstreamStore::with_options(
+                endpoint,
+                instance_name.to_string(),
+                api,
+                options.clone(),
+            )
+            .map(|peer| Arc::new(StoreKind::from(peer)))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err("Failed to create replication peers")?;
+
+    Ok(Arc::new(StoreKind::from(ReplicatedStore::new(
+        store,
+        peers,
+        store_config.replication_factor,
+        store_config.replication_acknowledgement,
+    ))))
+}
+
+/// Spreads the entries of `store` across the configured cluster, if there is one. `store` holds
+/// the shard of this node, the others are reached over gRPC
+fn with_sharding(
+    store: Arc<StoreKind>,
+    instance_name: &str,
+    api: RemoteApi,
+    store_config: &StoreConfig,
+) -> eyre::Result<Arc<StoreKind>> {
+    if store_config.shard_nodes.is_empty() {
+        return Ok(store);
+    }
+
+    let shard_self = store_config
+        .shard_self
+        .as_ref()
+        .filter(|shard_self| store_config.shard_nodes.contains(shard_self))
+        .ok_or_else(|| eyre::eyre!("`--shard-self` must be one of the `--shard-nodes`"))?;
+
+    let options = GrpcUpstreamOptions {
+        timeout: Some(Duration::from_secs(store_config.shard_timeout_seconds)),
+        token: store_config.peer_token.clone(),
+        ..Default::default()
+    };
+    let shards = store_config
+        .shard_nodes
+        .iter()
+        .map(|endpoint| {
+            let store = if endpoint == shard_self {
+                store.clone()
+            } else {
+                Arc::new(StoreKind::from(GrpcUpstreamStore::with_options(
+                    endpoint,
+                    instance_name.to_string(),
+                    api,
+                    options.clone(),
+                )?))
+            };
+
+            Ok(Shard::new(endpoint.clone(), store))
+        })
+        .collect::<Result<Vec<_>, crate::errors::Error>>()
+        .wrap_err("Failed to create shards")?;
+
+    Ok(Arc::new(StoreKind::from(ShardedStore::new(shards)?)))
+}
+
+/// Creates the CAS and ActionCache stores of every instance, the ActionCache entries keeping track
+/// of the CAS blobs they reference
+async fn create_store_managers(
+    store_config: &StoreConfig,
+) -> eyre::Result<(StoreManager, StoreManager)> {
+    let mut cas_stores = HashMap::new();
+    let mut action_cache_stores = HashMap::new();
+    let mut quotas = HashMap::new();
+    let mut action_cache_quotas = HashMap::new();
+
+    if store_config.memory_retained_percent > 100 {
+        eyre::bail!("BACHE_MEMORY_RETAINED_PERCENT cannot be over 100");
+    }
+
+    for spec in &store_config.instance_quotas {
+        if !store_config.instance_names.contains(&spec.instance_name) {
+            eyre::bail!("Quota given for unknown instance `{}`", spec.instance_name);
+        }
+
+        let instance_name = InstanceName::from(spec.instance_name.as_str());
+        quotas.insert(instance_name.clone(), Arc::new(QuotaUsage::new(spec.quota)));
+        action_cache_quotas.insert(instance_name, Arc::new(QuotaUsage::new(spec.quota)));
+    }
+
+    // snapshots of the CAS memory stores would only hold the manifests of the blobs
+    if store_config.memory_snapshot_dir.is_some()
+        && store_config.dedup_average_chunk_bytes.is_some()
+    {
+        eyre::bail!("Memory snapshots are not supported along with deduplication");
+    }
+
+    if let Some(snapshot_dir) = &store_config.memory_snapshot_dir {
+        std::fs::create_dir_all(snapshot_dir)
+            .wrap_err("Failed to create the memory snapshot directory")?;
+    }
+
+    let database = store_config
+        .embedded_store_path
+        .as_deref()
+        .map(embedded::open_database)
+        .transpose()
+        .wrap_err("Failed to open the embedded store")?;
+
+    for instance_name in &store_config.instance_names {
+        let (cas_evictions_sender, cas_evictions) = mpsc::unbounded_channel();
+        let (action_cache_evictions_sender, action_cache_evictions) = mpsc::unbounded_channel();
+
+        // with dedup, the CAS memory store only keeps the manifests of the blobs
+        let cas_memory_max_bytes = match store_config.dedup_average_chunk_bytes {
+            Some(_) => split_dedup_memory(store_config.cas_memory_max_bytes).0,
+            None => store_config.cas_memory_max_bytes,
+        };
+        let (max_bytes, retained_bytes) = split_memory(cas_memory_max_bytes, store_config);
+        let cas_memory = with_snapshot(
+            MemoryStore::with_eviction_listener(max_bytes, cas_evictions_sender)
+                .with_retained_capacity(retained_bytes),
+            instance_name,
+            RemoteApi::ContentAddressableStorage,
+            store_config,
+        );
+        let (max_bytes, retained_bytes) =
+            split_memory(store_config.action_cache_memory_max_bytes, store_config);
+        let action_cache_memory = with_snapshot(
+            MemoryStore::with_eviction_listener(max_bytes, action_cache_evictions_sender)
+                .with_retained_capacity(retained_bytes),
+            instance_name,
+            RemoteApi::ActionCache,
+            store_config,
+        );
+
+        let cas = with_dedup(Arc::new(StoreKind::from(cas_memory.clone())), store_config)?;
+        let cas = with_redis(
+            cas,
+            instance_name,
+            RemoteApi::ContentAddressableStorage,
+            store_config,
+        )
+        .await?;
+        let cas = with_embedded(
+            cas,
+            database.as_ref(),
+            instance_name,
+            RemoteApi::ContentAddressableStorage,
+            store_config,
+        )?;
+        let cas = match quotas.get(&InstanceName::from(instance_name.as_str())) {
+            Some(usage) => Arc::new(StoreKind::from(QuotaStore::new(
+                cas,
+                InstanceName::from(instance_name.as_str()),
+                usage.clone(),
+            ))),
+            None => cas,
+        };
+        // a Redis server shared with other nodes gets blobs the bloom filter never sees
+        let bloom_filter = store_config
+            .redis_url
+            .is_none()
+            .then_some(BloomFilterOptions {
+                expected_entries: store_config.bloom_filter_expected_entries,
+                false_positive_rate: store_config.bloom_filter_false_positive_rate,
+            });
+        let cas = ExistenceIndexStore::new(cas, negative_cache_ttl(store_config), bloom_filter);
+        cas.populate()
+            .await
+            .wrap_err("Failed to populate the existence index")?;
+        let cas = Arc::new(StoreKind::from(cas));
+        // snapshots are restored through the index and quota, which need to see every entry
+        let local_cas = cas.clone();
+        let action_cache = with_redis(
+            Arc::new(StoreKind::from(action_cache_memory.clone())),
+            instance_name,
+            RemoteApi::ActionCache,
+            store_config,
+        )
+        .await?;
+        let action_cache = with_embedded(
+            action_cache,
+            database.as_ref(),
+            instance_name,
+            RemoteApi::ActionCache,
+            store_config,
+        )?;
+        let action_cache =
+            match action_cache_quotas.get(&InstanceName::from(instance_name.as_str())) {
+                Some(usage) => Arc::new(StoreKind::from(QuotaStore::new(
+                    action_cache,
+                    InstanceName::from(instance_name.as_str()),
+                    usage.clone(),
+                ))),
+                None => action_cache,
+            };
+        let local_action_cache = action_cache.clone();
+
+        let cas = with_upstream(
+            cas,
+            instance_name,
+            RemoteApi::ContentAddressableStorage,
+            store_config,
+        )?;
+        let cas = with_replication(
+            cas,
+            instance_name,
+            RemoteApi::ContentAddressableStorage,
+            store_config,
+        )?;
+        let cas = with_sharding(
+            cas,
+            instance_name,
+            RemoteApi::ContentAddressableStorage,
+            store_config,
+        )?;
+        // only the CAS is content addressed, two writes of an action digest may differ
+        let cas = Arc::new(StoreKind::from(CoalescingStore::new(cas)));
+
+        let action_cache = with_upstream(
+            action_cache,
+            instance_name,
+            RemoteApi::ActionCache,
+            store_config,
+        )?;
+        // references are checked against the whole CAS, wherever in the cluster the blobs live,
+        // but evictions only remove entries from the memory stores that evicted them
+        let action_cache = ReferenceTrackingStore::new(action_cache, cas.clone())
+            .with_evicting_tiers(
+                Arc::new(StoreKind::from(action_cache_memory.clone())),
+                Arc::new(StoreKind::from(cas_memory.clone())),
+            );
+        action_cache.handle_evictions(cas_evictions, action_cache_evictions);
+        // restored entries still get their references tracked, but are not sent upstream
+        let local_action_cache = Arc::new(StoreKind::from(
+            action_cache.with_action_cache(local_action_cache),
+        ));
+        let action_cache = Arc::new(StoreKind::from(action_cache));
+
+        spawn_snapshot_tasks(
+            (cas_memory, local_cas),
+            (action_cache_memory, local_action_cache),
+            store_config,
+        );
+
+        let action_cache = with_replication(
+            action_cache,
+            instance_name,
+            RemoteApi::ActionCache,
+            store_config,
+        )?;
+        let action_cache = with_sharding(
+            action_cache,
+            instance_name,
+            RemoteApi::ActionCache,
+            store_config,
+        )?;
+
+        let instance_name = InstanceName::from(instance_name.as_str());
+        cas_stores.insert(instance_name.clone(), cas);
+        action_cache_stores.insert(instance_name, action_cache);
+    }
+
+    register_quota_metrics(
+        quotas
+            .iter()
+            .map(|(instance_name, usage)| (instance_name.clone(), "cas", usage.clone()))
+            .chain(action_cache_quotas.iter().map(|(instance_name, usage)| {
+                (instance_name.clone(), "action_cache", usage.clone())
+            }))
+            .collect(),
+    );
+
+    let default_instance_name = store_config
+        .default_instance_name
+        .as_deref()
+        .map(InstanceName::from);
+
+    Ok((
+        StoreManager::with_quotas(cas_stores, quotas)
+            .with_routing(
+                store_config.instance_routes.clone(),
+                default_instance_name.clone(),
+            )
+            .wrap_err("Failed to route instances")?,
+        StoreManager::with_quotas(action_cache_stores, action_cache_quotas)
+            .with_routing(store_config.instance_routes.clone(), default_instance_name)
+            .wrap_err("Failed to route instances")?,
+    ))
 }
 
 pub async fn start(args: Args) -> eyre::Result<()> {
@@ -28,12 +647,26 @@ pub async fn start(args: Args) -> eyre::Result<()> {
         grpc_hostname,
         grpc_port,
         disable_grpc_reflection,
+        admin_token,
+        find_missing_blobs_concurrency,
+        shutdown_grace_period_seconds,
+        max_batch_total_size_bytes,
+        max_splice_blob_size_bytes,
+        symlink_absolute_path_strategy,
+        action_cache_update_tokens,
+        worker_token,
+        worker_lease_seconds,
+        execution_max_attempts,
+        execution_queue_timeout_seconds,
+        upload_idle_timeout_seconds,
+        upload_max_buffered_bytes,
+        upload_spill_dir,
         ..
     } = args.server_config;
 
     let addr = create_socket_address(&grpc_hostname, grpc_port)?;
 
-    let (_health_reporter, health_service) = tonic_health::server::health_reporter();
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
 
     let reflection_service = if disable_grpc_reflection {
         None
@@ -46,11 +679,121 @@ pub async fn start(args: Args) -> eyre::Result<()> {
         )
     };
 
-    Server::builder()
+    let (cas_stores, action_cache_stores) = create_store_managers(&args.store_config).await?;
+    // blobs are split the way the dedup store splits them, so that they share their chunks
+    let chunking = args
+        .store_config
+        .dedup_average_chunk_bytes
+        .map(ChunkingOptions::new)
+        .transpose()
+        .wrap_err("Invalid BACHE_DEDUP_AVERAGE_CHUNK_BYTES")?
+        .unwrap_or_default();
+
+    // only the clients sending one of these get a rate limit of their own, as anyone may send a
+    // new made up token with every request
+    let rate_limit = RateLimitLayer::new(args.rate_limit_config)
+        .with_client_tokens(
+            action_cache_update_tokens
+                .iter()
+                .chain(&admin_token)
+                .chain(&worker_token)
+                .map(String::as_str),
+        )
+        .with_peer_token(args.store_config.peer_token.as_deref());
+
+    let admin_service = admin_token.map(|admin_token| {
+        AdminService::new(cas_stores.clone(), action_cache_stores.clone()).into_server(&admin_token)
+    });
+
+    let action_cache_writers = ActionCacheWriters::new(&action_cache_update_tokens)
+        .with_peer_token(args.store_config.peer_token.as_deref());
+
+    let scheduler = worker_token.as_ref().map(|_| {
+        spawn_scheduler(
+            action_cache_stores.clone(),
+            SchedulerOptions {
+                lease_duration: Duration::from_secs(worker_lease_seconds),
+                max_attempts: execution_max_attempts.max(1),
+                queue_timeout: Duration::from_secs(execution_queue_timeout_seconds),
+                ..Default::default()
+            },
+        )
+    });
+    let execution_service = scheduler.clone().map(|scheduler| {
+        ExecutionService::new(cas_stores.clone(), action_cache_stores.clone(), scheduler)
+            .into_server()
+    });
+    let worker_service = scheduler
+        .zip(worker_token)
+        .map(|(scheduler, worker_token)| WorkerService::new(scheduler).into_server(&worker_token));
+
+    let drain = Drain::new();
+    let grace_period = Duration::from_secs(shutdown_grace_period_seconds);
+
+    let server = Server::builder()
+        .layer(rate_limit)
+        .layer(ReplicationLayer)
+        .layer(drain.layer())
         .add_service(health_service)
         .add_optional_service(reflection_service)
-        .serve_with_shutdown(addr, create_shutdown_signal_listener())
-        .await?;
+        .add_service(
+            CapabilitiesService::new(cas_stores.clone())
+                .with_cache_priorities(args.store_config.memory_retained_percent > 0)
+                .with_execution(execution_service.is_some())
+                .with_max_batch_total_size_bytes(max_batch_total_size_bytes)
+                .with_symlink_policy(symlink_absolute_path_strategy)
+                .with_action_cache_writers(action_cache_writers.clone())
+                .into_server(),
+        )
+        .add_service(
+            ContentAddressableStorageService::new(
+                cas_stores.clone(),
+                find_missing_blobs_concurrency,
+            )
+            .with_chunking(chunking)
+            .with_max_batch_total_size_bytes(max_batch_total_size_bytes)
+            .with_max_splice_blob_size_bytes(max_splice_blob_size_bytes)
+            .into_server(),
+        )
+        .add_service(
+            ByteStreamService::new(
+                cas_stores.clone(),
+                spawn_upload_reaper(
+                    Duration::from_secs(upload_idle_timeout_seconds),
+                    upload_max_buffered_bytes,
+                    upload_spill_dir.unwrap_or_else(|| std::env::temp_dir().join("bache-uploads")),
+                ),
+            )
+            .into_server(),
+        )
+        .add_service(
+            ActionCacheService::new(action_cache_stores.clone())
+                .with_writers(action_cache_writers)
+                .into_server(),
+        )
+        .add_optional_service(execution_service)
+        .add_optional_service(worker_service)
+        .add_optional_service(admin_service)
+        .serve_with_shutdown(
+            addr,
+            drain
+                .clone()
+                .on_signal(create_shutdown_signal_listener(), health_reporter),
+        );
+
+    tokio::select! {
+        result = server => result?,
+        _ = drain.deadline(grace_period) => {
+            ::tracing::warn!(
+                in_flight = drain.in_flight(),
+                "shutdown grace period is over, dropping the requests still in flight"
+            );
+        }
+    }
+
+    // writes acknowledged before shutting down must not be lost
+    cas_stores.flush().await;
+    action_cache_stores.flush().await;
 
     Ok(())
 }
@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use prost::Message;
+use subtle::{Choice, ConstantTimeEq};
+use tonic::{metadata::MetadataMap, Request, Response, Status};
+use tracing::instrument;
+
+use crate::{
+    domain::{DigestInfo, InstanceName},
+    errors::Error,
+    infrastructure::{Store, StoreManager},
+    protos::build::bazel::remote::execution::v2::{
+        action_cache_server::{ActionCache, ActionCacheServer},
+        ActionResult, GetActionResultRequest, UpdateActionResultRequest,
+    },
+    services::capabilities::HIGH_CACHE_PRIORITY,
+};
+
+/// Clients allowed to update the ActionCache, by the bearer token they send. Every client is
+/// when no token is given
+#[derive(Clone, Default)]
+pub struct ActionCacheWriters {
+    expected_authorizations: Arc<Vec<String>>,
+}
+
+impl ActionCacheWriters {
+    pub fn new(tokens: &[String]) -> Self {
+        Self {
+            expected_authorizations: Arc::new(
+                tokens
+                    .iter()
+                    .map(|token| format!("Bearer {token}"))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Also lets the other nodes of the cluster, which send `peer_token`, update the ActionCache
+    /// when updates are restricted to some clients
+    pub fn with_peer_token(self, peer_token: Option<&str>) -> Self {
+        match peer_token {
+            Some(peer_token) if !self.expected_authorizations.is_empty() => {
+                let mut expected_authorizations = self.expected_authorizations.as_ref().clone();
+                expected_authorizations.push(format!("Bearer {peer_token}"));
+
+                Self {
+                    expected_authorizations: Arc::new(expected_authorizations),
+                }
+            }
+            _ => self,
+        }
+    }
+
+    /// Whether the request carrying `metadata` may update the ActionCache
+    pub fn allows(&self, metadata: &MetadataMap) -> bool {
+        if self.expected_authorizations.is_empty() {
+            return true;
+        }
+
+        let Some(authorization) = metadata.get("authorization") else {
+            return false;
+        };
+
+        // every token is compared, in constant time, not to tell which one came close
+        self.expected_authorizations
+            .iter()
+            .fold(Choice::from(0), |allowed, expected| {
+                allowed | authorization.as_bytes().ct_eq(expected.as_bytes())
+            })
+            .into()
+    }
+}
+
+pub struct ActionCacheService {
+    stores: StoreManager,
+    writers: ActionCacheWriters,
+}
+
+impl ActionCacheService {
+    pub fn new(stores: StoreManager) -> Self {
+        Self {
+            stores,
+            writers: ActionCacheWriters::default(),
+        }
+    }
+
+    /// Only lets `writers` update the ActionCache, the others get PERMISSION_DENIED
+    pub fn with_writers(mut self, writers: ActionCacheWriters) -> Self {
+        self.writers = writers;
+
+        self
+    }
+
+    pub fn into_server(self) -> ActionCacheServer<ActionCacheService> {
+        ActionCacheServer::new(self)
+    }
+}
+
+#[async_trait]
+impl ActionCache for ActionCacheService {
+    #[instrument(err, skip(self))]
+    async fn get_action_result(
+        &self,
+        request: Request<GetActionResultRequest>,
+    ) -> Result<Response<ActionResult>, Status> {
+        let GetActionResultRequest {
+            instance_name,
+            action_digest,
+            ..
+        } = request.into_inner();
+
+        let action_digest: DigestInfo = action_digest
+            .ok_or_else(|| Status::invalid_argument("`action_digest` is required"))?
+            .try_into()?;
+
+        let instance_name = InstanceName::new(instance_name);
+        let store = self.stores.get_store_by_instance_name(&instance_name)?;
+
+        let bytes = store.get(&action_digest).await?;
+        let action_result = ActionResult::decode(bytes).map_err(Error::from)?;
+
+        Ok(Response::new(action_result))
+    }
+
+    #[instrument(err, skip(self))]
+    async fn update_action_result(
+        &self,
+        request: Request<UpdateActionResultRequest>,
+    ) -> Result<Response<ActionResult>, Status> {
+        if !self.writers.allows(request.metadata()) {
+            return Err(Status::permission_denied(
+                "Not allowed to update the ActionCache",
+            ));
+        }
+
+        let UpdateActionResultRequest {
+            instance_name,
+            action_digest,
+            action_result,
+            results_cache_policy,
+            ..
+        } = request.into_inner();
+
+        let action_digest: DigestInfo = action_digest
+            .ok_or_else(|| Status::invalid_argument("`action_digest` is required"))?
+            .try_into()?;
+        let action_result =
+            action_result.ok_or_else(|| Status::invalid_argument("`action_result` is required"))?;
+
+        let instance_name = InstanceName::new(instance_name);
+        let store = self.stores.get_store_by_instance_name(&instance_name)?;
+
+        store
+            .put(
+                action_digest.clone(),
+                Bytes::from(action_result.encode_to_vec()),
+            )
+            .await?;
+
+        // lower values are more important, anything past the highest priority counts as it
+        let priority = results_cache_policy.map_or(0, |policy| policy.priority);
+        if priority <= HIGH_CACHE_PRIORITY {
+            store.prioritize(&action_digest).await;
+        }
+
+        Ok(Response::new(action_result))
+    }
+}
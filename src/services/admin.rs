@@ -0,0 +1,228 @@
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use tonic::{codegen::InterceptedService, Request, Response, Status};
+use tracing::instrument;
+
+use super::auth::BearerAuthInterceptor;
+use crate::{
+    domain::{DigestInfo, InstanceName},
+    errors::Error,
+    infrastructure::{quota::QuotaUsage, Store, StoreKind, StoreManager, StoreStats},
+    protos::{
+        bache::admin::v1::{
+            admin_server::{Admin, AdminServer},
+            CompactRequest, CompactResponse, DeleteActionResultRequest, DeleteActionResultResponse,
+            DeleteBlobRequest, DeleteBlobResponse, FlushInstanceRequest, FlushInstanceResponse,
+            InstanceStats, ListInstancesRequest, ListInstancesResponse, LookupDigestRequest,
+            LookupDigestResponse, QuotaUsage as QuotaUsageProto, StoreStats as StoreStatsProto,
+            TierPresence,
+        },
+        build::bazel::remote::execution::v2::Digest,
+    },
+};
+
+pub struct AdminService {
+    cas_stores: StoreManager,
+    action_cache_stores: StoreManager,
+}
+
+impl AdminService {
+    pub fn new(cas_stores: StoreManager, action_cache_stores: StoreManager) -> Self {
+        Self {
+            cas_stores,
+            action_cache_stores,
+        }
+    }
+
+    pub fn into_server(
+        self,
+        admin_token: &str,
+    ) -> InterceptedService<AdminServer<AdminService>, BearerAuthInterceptor> {
+        AdminServer::with_interceptor(self, BearerAuthInterceptor::new(admin_token, "admin"))
+    }
+}
+
+impl From<StoreStats> for StoreStatsProto {
+    fn from(stats: StoreStats) -> Self {
+        Self {
+            kind: stats.kind.to_string(),
+            entry_count: stats.entry_count,
+            size_bytes: stats.size_bytes,
+            tiers: stats.tiers.into_iter().map(Self::from).collect(),
+        }
+    }
+}
+
+impl From<&QuotaUsage> for QuotaUsageProto {
+    fn from(usage: &QuotaUsage) -> Self {
+        Self {
+            max_bytes: usage.quota.max_bytes,
+            max_entries: usage.quota.max_entries,
+            used_bytes: usage.used_bytes(),
+            used_entries: usage.used_entries(),
+        }
+    }
+}
+
+fn required_digest(digest: Option<Digest>, field: &'static str) -> Result<DigestInfo, Error> {
+    digest.ok_or(Error::MissingField(field))?.try_into()
+}
+
+/// Checks `store` and every store it is layered over for `key`, depth first
+fn lookup_tiers<'a>(
+    store: &'a StoreKind,
+    key: &'a DigestInfo,
+    depth: u32,
+) -> BoxFuture<'a, Vec<TierPresence>> {
+    Box::pin(async move {
+        let size_bytes = store.size_of(key).await.map(|size| size as u64);
+        let mut presences = vec![TierPresence {
+            kind: store.stats().kind.to_string(),
+            depth,
+            present: size_bytes.is_some(),
+            size_bytes,
+        }];
+
+        for tier in store.tiers() {
+            presences.extend(lookup_tiers(&tier, key, depth + 1).await);
+        }
+
+        presences
+    })
+}
+
+#[async_trait]
+impl Admin for AdminService {
+    #[instrument(err, skip(self))]
+    async fn list_instances(
+        &self,
+        _request: Request<ListInstancesRequest>,
+    ) -> Result<Response<ListInstancesResponse>, Status> {
+        let mut instances = Vec::new();
+
+        for instance_name in self.cas_stores.instance_names() {
+            let cas = self.cas_stores.get_store_by_instance_name(instance_name)?;
+            let action_cache = self
+                .action_cache_stores
+                .get_store_by_instance_name(instance_name)
+                .ok();
+
+            instances.push(InstanceStats {
+                instance_name: instance_name.to_string(),
+                cas: Some(cas.stats().into()),
+                action_cache: action_cache.map(|store| store.stats().into()),
+                quota: self
+                    .cas_stores
+                    .quota_usage(instance_name)
+                    .map(|usage| usage.as_ref().into()),
+            });
+        }
+
+        Ok(Response::new(ListInstancesResponse { instances }))
+    }
+
+    #[instrument(err, skip(self))]
+    async fn lookup_digest(
+        &self,
+        request: Request<LookupDigestRequest>,
+    ) -> Result<Response<LookupDigestResponse>, Status> {
+        let LookupDigestRequest {
+            instance_name,
+            digest,
+        } = request.into_inner();
+
+        let digest = required_digest(digest, "digest")?;
+        let instance_name = InstanceName::new(instance_name);
+
+        let cas = self.cas_stores.get_store_by_instance_name(&instance_name)?;
+        let action_cache = self
+            .action_cache_stores
+            .get_store_by_instance_name(&instance_name)?;
+
+        Ok(Response::new(LookupDigestResponse {
+            cas: lookup_tiers(&cas, &digest, 0).await,
+            action_cache: lookup_tiers(&action_cache, &digest, 0).await,
+        }))
+    }
+
+    #[instrument(err, skip(self))]
+    async fn delete_blob(
+        &self,
+        request: Request<DeleteBlobRequest>,
+    ) -> Result<Response<DeleteBlobResponse>, Status> {
+        let DeleteBlobRequest {
+            instance_name,
+            digest,
+        } = request.into_inner();
+
+        let digest = required_digest(digest, "digest")?;
+        let instance_name = InstanceName::new(instance_name);
+
+        self.cas_stores
+            .get_store_by_instance_name(&instance_name)?
+            .remove(&digest)
+            .await?;
+
+        Ok(Response::new(DeleteBlobResponse {}))
+    }
+
+    #[instrument(err, skip(self))]
+    async fn delete_action_result(
+        &self,
+        request: Request<DeleteActionResultRequest>,
+    ) -> Result<Response<DeleteActionResultResponse>, Status> {
+        let DeleteActionResultRequest {
+            instance_name,
+            action_digest,
+        } = request.into_inner();
+
+        let action_digest = required_digest(action_digest, "action_digest")?;
+        let instance_name = InstanceName::new(instance_name);
+
+        self.action_cache_stores
+            .get_store_by_instance_name(&instance_name)?
+            .remove(&action_digest)
+            .await?;
+
+        Ok(Response::new(DeleteActionResultResponse {}))
+    }
+
+    #[instrument(err, skip(self))]
+    async fn flush_instance(
+        &self,
+        request: Request<FlushInstanceRequest>,
+    ) -> Result<Response<FlushInstanceResponse>, Status> {
+        let instance_name = InstanceName::new(request.into_inner().instance_name);
+
+        let cas = self.cas_stores.get_store_by_instance_name(&instance_name)?;
+        let action_cache = self
+            .action_cache_stores
+            .get_store_by_instance_name(&instance_name)?;
+
+        // action results go first, so none of them is ever served without its blobs
+        action_cache.clear().await?;
+        cas.clear().await?;
+
+        tracing::info!(%instance_name, "flushed instance");
+
+        Ok(Response::new(FlushInstanceResponse {}))
+    }
+
+    #[instrument(err, skip(self))]
+    async fn compact(
+        &self,
+        request: Request<CompactRequest>,
+    ) -> Result<Response<CompactResponse>, Status> {
+        let instance_name = InstanceName::new(request.into_inner().instance_name);
+
+        let cas = self.cas_stores.get_store_by_instance_name(&instance_name)?;
+        let action_cache = self
+            .action_cache_stores
+            .get_store_by_instance_name(&instance_name)?;
+
+        action_cache.compact().await;
+        cas.compact().await;
+
+        Ok(Response::new(CompactResponse {}))
+    }
+}
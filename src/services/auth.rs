@@ -0,0 +1,41 @@
+use subtle::ConstantTimeEq;
+use tonic::{service::Interceptor, Request, Status};
+
+/// Rejects every request that does not carry the expected credential as a bearer token
+#[derive(Clone)]
+pub struct BearerAuthInterceptor {
+    expected_authorization: String,
+    /// What the credential is for, e.g. `admin`, as told to the clients missing it
+    credential: &'static str,
+}
+
+impl BearerAuthInterceptor {
+    pub fn new(token: &str, credential: &'static str) -> Self {
+        Self {
+            expected_authorization: format!("Bearer {token}"),
+            credential,
+        }
+    }
+}
+
+impl Interceptor for BearerAuthInterceptor {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let authorization = request.metadata().get("authorization").ok_or_else(|| {
+            Status::unauthenticated(format!("Missing {} credential", self.credential))
+        })?;
+
+        // compared in constant time, not to tell how much of a guessed token is right
+        if authorization
+            .as_bytes()
+            .ct_eq(self.expected_authorization.as_bytes())
+            .into()
+        {
+            Ok(request)
+        } else {
+            Err(Status::permission_denied(format!(
+                "Invalid {} credential",
+                self.credential
+            )))
+        }
+    }
+}
@@ -1,24 +1,29 @@
 use async_trait::async_trait;
+use bytes::BytesMut;
 use futures::stream::BoxStream;
 use tonic::{Request, Response, Status, Streaming};
 
 use crate::{
     domain::{DigestInfo, ResourceName},
+    errors::Error,
     infrastructure::{Store, StoreManager},
     protos::google::bytestream::{
         byte_stream_server::{ByteStream, ByteStreamServer},
         QueryWriteStatusRequest, QueryWriteStatusResponse, ReadRequest, ReadResponse, WriteRequest,
         WriteResponse,
     },
+    uploads::PartialUploads,
 };
 
 pub struct ByteStreamService {
     stores: StoreManager,
+    /// Writes to `uploads/{uuid}` resource names, which clients may resume
+    uploads: PartialUploads,
 }
 
 impl ByteStreamService {
-    pub fn new(stores: StoreManager) -> Self {
-        Self { stores }
+    pub fn new(stores: StoreManager, uploads: PartialUploads) -> Self {
+        Self { stores, uploads }
     }
 
     pub fn into_server(self) -> ByteStreamServer<Self> {
@@ -29,10 +34,8 @@ impl ByteStreamService {
     fn stream_one_read_response(
         &self,
         read_response: ReadResponse,
-    ) -> Result<Response<BoxStream<'static, Result<ReadResponse, Status>>>, Status> {
-        Ok(Response::new(Box::pin(tokio_stream::once(Ok(
-            read_response,
-        )))))
+    ) -> Response<BoxStream<'static, Result<ReadResponse, Status>>> {
+        Response::new(Box::pin(tokio_stream::once(Ok(read_response))))
     }
 }
 
@@ -50,19 +53,22 @@ impl ByteStream for ByteStreamService {
             read_limit,
         } = request.into_inner();
 
-        let read_limit: usize = read_limit.try_into().map_err(|_| {
-            Status::invalid_argument("`read_limit` could not be converted into a valid usize")
-        })?;
-
-        if read_limit == 0 {
-            return self.stream_one_read_response(ReadResponse::default());
-        }
+        // a `read_limit` of 0 means there is no limit
+        let read_limit: usize = match read_limit {
+            0 => usize::MAX,
+            read_limit => read_limit.try_into().map_err(|_| {
+                Status::invalid_argument("`read_limit` could not be converted into a valid usize")
+            })?,
+        };
 
         let read_offset: usize = read_offset.try_into().map_err(|_| {
             Status::invalid_argument("`read_limit` could not be converted into a valid usize")
         })?;
 
         let resource_name = ResourceName::try_from(resource_name)?;
+        if resource_name.is_compressed() {
+            return Err(Error::UnsupportedOperation("compressed blobs").into());
+        }
         let digest_info = DigestInfo::try_new(&resource_name.hash, resource_name.size)?;
 
         let store = self
@@ -73,26 +79,181 @@ impl ByteStream for ByteStreamService {
             .get_chunk(&digest_info, read_offset, read_limit)
             .await?;
 
-        if bytes_chunk.is_empty() {
-            self.stream_one_read_response(ReadResponse::default())
-        } else {
-            self.stream_one_read_response(ReadResponse {
-                data: bytes_chunk.to_vec(),
-            })
-        }
+        Ok(self.stream_one_read_response(ReadResponse {
+            data: bytes_chunk.to_vec(),
+        }))
     }
 
     async fn write(
         &self,
-        _request: Request<Streaming<WriteRequest>>,
+        request: Request<Streaming<WriteRequest>>,
     ) -> Result<Response<WriteResponse>, Status> {
-        todo!()
+        let mut requests = request.into_inner();
+
+        // only the first request of the stream has to carry the resource name
+        let mut write_request = requests.message().await?;
+        let resource_name = ResourceName::try_from(
+            write_request
+                .as_ref()
+                .map(|write_request| write_request.resource_name.as_str())
+                .unwrap_or_default(),
+        )?;
+        if resource_name.is_compressed() {
+            return Err(Error::UnsupportedOperation("compressed blobs").into());
+        }
+        let digest_info = DigestInfo::try_new(&resource_name.hash, resource_name.size)?;
+        let upload_id = resource_name
+            .uuid
+            .map(|uuid| (resource_name.instance_name.clone(), uuid));
+
+        let mut data = BytesMut::new();
+        let mut finished = false;
+
+        while let Some(WriteRequest {
+            write_offset,
+            finish_write,
+            data: chunk,
+            ..
+        }) = write_request
+        {
+            match &upload_id {
+                Some(upload_id) => {
+                    self.uploads
+                        .append(upload_id, resource_name.size, write_offset, &chunk)?;
+                }
+                None => {
+                    if write_offset != data.len() as i64 {
+                        return Err(Error::WriteOffsetMismatch(write_offset, data.len()).into());
+                    }
+                    // checked before buffering, a stream could otherwise grow without bound
+                    if data.len() + chunk.len() > resource_name.size {
+                        return Err(Error::WriteTooLarge(resource_name.size).into());
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+            }
+
+            if finish_write {
+                finished = true;
+                break;
+            }
+
+            write_request = requests.message().await?;
+        }
+
+        let data = match (&upload_id, finished) {
+            (Some(upload_id), true) => self.uploads.finish(upload_id)?,
+            (None, true) => data,
+            // the client resumes the upload later on, from where it left off
+            (Some(upload_id), false) => {
+                let committed_size = self.uploads.committed_size(upload_id).unwrap_or_default();
+
+                return Ok(Response::new(WriteResponse {
+                    committed_size: committed_size as i64,
+                }));
+            }
+            (None, false) => {
+                return Err(Status::invalid_argument(
+                    "Write stream ended without a request setting `finish_write`",
+                ))
+            }
+        };
+
+        if DigestInfo::compute(&data) != digest_info {
+            return Err(Error::DigestMismatch(digest_info).into());
+        }
+
+        let store = self
+            .stores
+            .get_store_by_instance_name(&resource_name.instance_name)?;
+
+        let committed_size = data.len() as i64;
+        store.put(digest_info, data.freeze()).await?;
+
+        Ok(Response::new(WriteResponse { committed_size }))
     }
 
     async fn query_write_status(
         &self,
-        _request: Request<QueryWriteStatusRequest>,
+        request: Request<QueryWriteStatusRequest>,
     ) -> Result<Response<QueryWriteStatusResponse>, Status> {
-        todo!()
+        let resource_name = ResourceName::try_from(request.into_inner().resource_name)?;
+        let digest_info = DigestInfo::try_new(&resource_name.hash, resource_name.size)?;
+        let upload_id = resource_name
+            .uuid
+            .map(|uuid| (resource_name.instance_name.clone(), uuid));
+
+        if let Some(committed_size) = upload_id
+            .as_ref()
+            .and_then(|upload_id| self.uploads.committed_size(upload_id))
+        {
+            return Ok(Response::new(QueryWriteStatusResponse {
+                committed_size: committed_size as i64,
+                complete: false,
+            }));
+        }
+
+        let store = self
+            .stores
+            .get_store_by_instance_name(&resource_name.instance_name)?;
+
+        // finished uploads are only known by the blob they wrote
+        if store.contains_key(&digest_info).await {
+            return Ok(Response::new(QueryWriteStatusResponse {
+                committed_size: digest_info.size_bytes,
+                complete: true,
+            }));
+        }
+
+        Err(match upload_id {
+            Some((_, uuid)) => Error::UploadNotFound(uuid),
+            None => Error::DigestInfoNotFound(digest_info.hash()),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        domain::InstanceName,
+        infrastructure::{memory::MemoryStore, StoreKind},
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_uploads_are_not_found() {
+        let idle_timeout = Duration::from_secs(60);
+        let uploads = PartialUploads::new(idle_timeout, 1024);
+        let stores = StoreManager::new(HashMap::from([(
+            InstanceName::from(""),
+            Arc::new(StoreKind::from(MemoryStore::new(1024))),
+        )]));
+        let service = ByteStreamService::new(stores, uploads.clone());
+
+        let data = b"partial";
+        let uuid = Uuid::new_v4();
+        let digest = DigestInfo::compute(b"partial upload");
+        let resource_name = format!("uploads/{uuid}/blobs/{}/{}", digest.hash(), 14);
+        uploads
+            .append(&(InstanceName::from(""), uuid), 14, 0, data)
+            .unwrap();
+
+        let query = || {
+            service.query_write_status(Request::new(QueryWriteStatusRequest {
+                resource_name: resource_name.clone(),
+            }))
+        };
+        let status = query().await.unwrap().into_inner();
+        assert_eq!(status.committed_size, data.len() as i64);
+        assert!(!status.complete);
+
+        tokio::time::advance(idle_timeout + Duration::from_secs(1)).await;
+        uploads.expire();
+        assert_eq!(query().await.unwrap_err().code(), tonic::Code::NotFound);
     }
 }
@@ -1,24 +1,108 @@
 use async_trait::async_trait;
+use clap::ArgEnum;
 use tonic::{Request, Response, Status};
 use tracing::instrument;
 
-use crate::protos::build::bazel::{
-    remote::execution::v2::{
-        capabilities_server::{Capabilities, CapabilitiesServer},
-        compressor::Value as Compressor,
-        digest_function::Value as DigestFunction,
-        symlink_absolute_path_strategy::Value as SymlinkAbsolutePathStrategy,
-        ActionCacheUpdateCapabilities, CacheCapabilities, GetCapabilitiesRequest,
-        ServerCapabilities,
+use super::action_cache::ActionCacheWriters;
+use crate::{
+    domain::InstanceName,
+    infrastructure::StoreManager,
+    protos::build::bazel::{
+        remote::execution::v2::{
+            capabilities_server::{Capabilities, CapabilitiesServer},
+            compressor::Value as Compressor,
+            digest_function::Value as DigestFunction,
+            priority_capabilities::PriorityRange,
+            symlink_absolute_path_strategy::Value as SymlinkAbsolutePathStrategy,
+            ActionCacheUpdateCapabilities, CacheCapabilities, ExecutionCapabilities,
+            GetCapabilitiesRequest, PriorityCapabilities, ServerCapabilities,
+        },
+        semver::SemVer,
     },
-    semver::SemVer,
 };
 
-pub struct CapabilitiesService;
+/// Highest cache priority, for entries kept for longer, see
+/// [`crate::infrastructure::Store::prioritize`]
+pub const HIGH_CACHE_PRIORITY: i32 = -1;
+
+/// Digest functions the CAS and ActionCache accept
+const DIGEST_FUNCTIONS: &[DigestFunction] = &[DigestFunction::Sha256];
+
+/// Compressors blobs may be sent and received with
+const COMPRESSORS: &[Compressor] = &[Compressor::Identity];
+
+/// Whether the action results may refer to files through symlinks to absolute paths
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    Disallowed,
+    Allowed,
+}
+
+impl From<SymlinkPolicy> for SymlinkAbsolutePathStrategy {
+    fn from(policy: SymlinkPolicy) -> Self {
+        match policy {
+            SymlinkPolicy::Disallowed => SymlinkAbsolutePathStrategy::Disallowed,
+            SymlinkPolicy::Allowed => SymlinkAbsolutePathStrategy::Allowed,
+        }
+    }
+}
+
+/// Reports what each instance supports, from the stores serving it and the configuration of the
+/// other services
+pub struct CapabilitiesService {
+    cas_stores: StoreManager,
+    cache_priorities: bool,
+    execution: bool,
+    max_batch_total_size_bytes: u64,
+    symlink_policy: SymlinkPolicy,
+    action_cache_writers: ActionCacheWriters,
+}
 
 impl CapabilitiesService {
-    pub fn new() -> Self {
-        Self
+    pub fn new(cas_stores: StoreManager) -> Self {
+        Self {
+            cas_stores,
+            cache_priorities: false,
+            execution: false,
+            max_batch_total_size_bytes: 0,
+            symlink_policy: SymlinkPolicy::Disallowed,
+            action_cache_writers: ActionCacheWriters::default(),
+        }
+    }
+
+    /// Advertises the cache priorities the stores honour, from [`HIGH_CACHE_PRIORITY`] to the
+    /// default of 0
+    pub fn with_cache_priorities(mut self, cache_priorities: bool) -> Self {
+        self.cache_priorities = cache_priorities;
+
+        self
+    }
+
+    /// Advertises that actions may be run remotely, with the Execution service
+    pub fn with_execution(mut self, execution: bool) -> Self {
+        self.execution = execution;
+
+        self
+    }
+
+    /// Advertises the largest batch the CAS accepts, 0 being no limit
+    pub fn with_max_batch_total_size_bytes(mut self, max_batch_total_size_bytes: u64) -> Self {
+        self.max_batch_total_size_bytes = max_batch_total_size_bytes;
+
+        self
+    }
+
+    pub fn with_symlink_policy(mut self, symlink_policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = symlink_policy;
+
+        self
+    }
+
+    /// Only lets the callers among `action_cache_writers` know they may update the ActionCache
+    pub fn with_action_cache_writers(mut self, action_cache_writers: ActionCacheWriters) -> Self {
+        self.action_cache_writers = action_cache_writers;
+
+        self
     }
 
     pub fn into_server(self) -> CapabilitiesServer<CapabilitiesService> {
@@ -31,21 +115,56 @@ impl Capabilities for CapabilitiesService {
     #[instrument(err, skip(self))]
     async fn get_capabilities(
         &self,
-        _request: Request<GetCapabilitiesRequest>,
+        request: Request<GetCapabilitiesRequest>,
     ) -> Result<Response<ServerCapabilities>, Status> {
+        let update_enabled = self.action_cache_writers.allows(request.metadata());
+
+        let instance_name = InstanceName::new(request.into_inner().instance_name);
+        self.cas_stores.resolve(&instance_name)?;
+        // a blob larger than the quota of its instance could never be kept
+        let max_cas_blob_size_bytes = self
+            .cas_stores
+            .quota_usage(&instance_name)
+            .and_then(|usage| usage.quota.max_bytes)
+            .unwrap_or(0);
+
+        let compressors: Vec<i32> = COMPRESSORS
+            .iter()
+            .map(|&compressor| compressor.into())
+            .collect();
+
         Ok(Response::new(ServerCapabilities {
             cache_capabilities: Some(CacheCapabilities {
-                digest_functions: vec![DigestFunction::Sha256.into()],
+                digest_functions: DIGEST_FUNCTIONS
+                    .iter()
+                    .map(|&digest_function| digest_function.into())
+                    .collect(),
                 action_cache_update_capabilities: Some(ActionCacheUpdateCapabilities {
-                    update_enabled: true,
+                    update_enabled,
+                }),
+                cache_priority_capabilities: self.cache_priorities.then(|| PriorityCapabilities {
+                    priorities: vec![PriorityRange {
+                        min_priority: HIGH_CACHE_PRIORITY,
+                        max_priority: 0,
+                    }],
                 }),
-                cache_priority_capabilities: None,
-                max_batch_total_size_bytes: 128 * 1024, // max 128MB batch sizes
-                symlink_absolute_path_strategy: SymlinkAbsolutePathStrategy::Disallowed.into(),
-                supported_compressors: vec![Compressor::Identity.into()],
-                supported_batch_update_compressors: vec![Compressor::Identity.into()],
+                max_batch_total_size_bytes: self.max_batch_total_size_bytes as i64,
+                symlink_absolute_path_strategy: SymlinkAbsolutePathStrategy::from(
+                    self.symlink_policy,
+                )
+                .into(),
+                supported_compressors: compressors.clone(),
+                supported_batch_update_compressors: compressors,
+                max_cas_blob_size_bytes: max_cas_blob_size_bytes as i64,
+                split_blob_support: true,
+                splice_blob_support: true,
+            }),
+            execution_capabilities: self.execution.then(|| ExecutionCapabilities {
+                digest_function: DIGEST_FUNCTIONS[0].into(),
+                exec_enabled: true,
+                execution_priority_capabilities: None,
+                supported_node_properties: Vec::new(),
             }),
-            execution_capabilities: None,
             deprecated_api_version: None,
             low_api_version: Some(SemVer {
                 major: 2,
@@ -1,31 +1,112 @@
-use std::pin::Pin;
+use std::collections::{HashSet, VecDeque};
 
 use async_trait::async_trait;
-use futures::stream::{BoxStream, FuturesUnordered, Stream};
+use bytes::{Bytes, BytesMut};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use prost::Message;
 use tonic::{Request, Response, Status};
 use tracing::instrument;
 
 use crate::{
     domain::{DigestInfo, InstanceName},
     errors::Error,
-    infrastructure::{Store, StoreManager},
-    protos::build::bazel::remote::execution::v2::{
-        content_addressable_storage_server::{
-            ContentAddressableStorage, ContentAddressableStorageServer,
+    infrastructure::{
+        dedup::{self, ChunkingOptions},
+        Store, StoreManager,
+    },
+    protos::{
+        build::bazel::remote::execution::v2::{
+            batch_read_blobs_response, batch_update_blobs_response,
+            compressor::Value as Compressor,
+            content_addressable_storage_server::{
+                ContentAddressableStorage, ContentAddressableStorageServer,
+            },
+            digest_function::Value as DigestFunction,
+            BatchReadBlobsRequest, BatchReadBlobsResponse, BatchUpdateBlobsRequest,
+            BatchUpdateBlobsResponse, Digest, Directory, FindMissingBlobsRequest,
+            FindMissingBlobsResponse, GetTreeRequest, GetTreeResponse, SpliceBlobRequest,
+            SpliceBlobResponse, SplitBlobRequest, SplitBlobResponse,
         },
-        BatchReadBlobsRequest, BatchReadBlobsResponse, BatchUpdateBlobsRequest,
-        BatchUpdateBlobsResponse, Digest, FindMissingBlobsRequest, FindMissingBlobsResponse,
-        GetTreeRequest, GetTreeResponse,
+        google::rpc::Status as RpcStatus,
     },
 };
 
+/// Number of directories sent per `GetTreeResponse` when the client does not ask for a page size
+const DEFAULT_TREE_PAGE_SIZE: usize = 1000;
+
+/// Most digests of a `FindMissingBlobs` request looked up in a single `contains_keys` call
+const FIND_MISSING_BLOBS_BATCH_SIZE: usize = 1000;
+
+/// Most bytes of a blob `SpliceBlob` assembles when not configured otherwise
+const DEFAULT_MAX_SPLICE_BLOB_SIZE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Only SHA256 digests are served, which clients may also leave implied
+fn validate_digest_function(digest_function: i32) -> Result<(), Error> {
+    match DigestFunction::from_i32(digest_function) {
+        Some(DigestFunction::Unknown | DigestFunction::Sha256) => Ok(()),
+        _ => Err(Error::UnsupportedDigestFunction),
+    }
+}
+
 pub struct ContentAddressableStorageService {
     stores: StoreManager,
+    /// Most `contains_keys` batches of a single `FindMissingBlobs` request in flight at once
+    lookup_concurrency: usize,
+    /// How `SplitBlob` splits blobs
+    chunking: ChunkingOptions,
+    /// Most bytes of blobs written or read by a single batch request, 0 being no limit
+    max_batch_total_size_bytes: u64,
+    /// Most bytes of a blob assembled by `SpliceBlob`
+    max_splice_blob_size_bytes: u64,
 }
 
 impl ContentAddressableStorageService {
-    pub fn new(stores: StoreManager) -> Self {
-        Self { stores }
+    pub fn new(stores: StoreManager, lookup_concurrency: usize) -> Self {
+        Self {
+            stores,
+            lookup_concurrency: lookup_concurrency.max(1),
+            chunking: ChunkingOptions::default(),
+            max_batch_total_size_bytes: 0,
+            max_splice_blob_size_bytes: DEFAULT_MAX_SPLICE_BLOB_SIZE_BYTES,
+        }
+    }
+
+    /// Turns away the batch requests for more than `max_batch_total_size_bytes` bytes of blobs
+    /// with INVALID_ARGUMENT, 0 being no limit
+    pub fn with_max_batch_total_size_bytes(mut self, max_batch_total_size_bytes: u64) -> Self {
+        self.max_batch_total_size_bytes = max_batch_total_size_bytes;
+
+        self
+    }
+
+    fn validate_batch_size(&self, total_size_bytes: u64) -> Result<(), Error> {
+        if self.max_batch_total_size_bytes > 0 && total_size_bytes > self.max_batch_total_size_bytes
+        {
+            return Err(Error::BatchTooLarge(
+                total_size_bytes,
+                self.max_batch_total_size_bytes,
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Turns away `SpliceBlob` requests for blobs of more than `max_splice_blob_size_bytes` bytes
+    /// with INVALID_ARGUMENT
+    pub fn with_max_splice_blob_size_bytes(mut self, max_splice_blob_size_bytes: u64) -> Self {
+        self.max_splice_blob_size_bytes = max_splice_blob_size_bytes;
+
+        self
+    }
+
+    /// Splits blobs in chunks of the given sizes on `SplitBlob`
+    pub fn with_chunking(mut self, chunking: ChunkingOptions) -> Self {
+        self.chunking = chunking;
+
+        self
     }
 
     pub fn into_server(self) -> ContentAddressableStorageServer<ContentAddressableStorageService> {
@@ -48,38 +129,95 @@ impl ContentAddressableStorage for ContentAddressableStorageService {
         let instance_name = InstanceName::new(instance_name);
         let store = self.stores.get_store_by_instance_name(&instance_name)?;
 
-        let join_handles = FuturesUnordered::new();
-        for digest in blob_digests {
-            let digest: DigestInfo = digest.try_into()?;
-            let store = store.clone();
+        let digests = blob_digests
+            .into_iter()
+            .map(DigestInfo::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
 
-            join_handles.push(tokio::spawn(async move {
-                if store.contains_key(&digest).await {
-                    None
-                } else {
-                    Some(digest)
-                }
-            }));
-        }
+        let lookups = digests
+            .chunks(FIND_MISSING_BLOBS_BATCH_SIZE)
+            .map(|batch| async {
+                let present = store.contains_keys(batch).await;
 
-        let missing_blob_digests = futures::future::try_join_all(join_handles)
-            .await
-            .map_err(Error::from)?
-            .into_iter()
-            .filter_map(|digest_info| digest_info.map(Digest::from))
-            .collect();
+                batch
+                    .iter()
+                    .zip(present)
+                    .filter(|(_, present)| !present)
+                    .map(|(digest, _)| Digest::from(digest.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let missing_blob_digests = stream::iter(lookups)
+            .buffered(self.lookup_concurrency)
+            .concat()
+            .await;
 
         Ok(Response::new(FindMissingBlobsResponse {
             missing_blob_digests,
         }))
     }
 
-    #[instrument(err, skip(self))]
+    #[instrument(err, skip(self, request))]
     async fn batch_update_blobs(
         &self,
-        _request: Request<BatchUpdateBlobsRequest>,
+        request: Request<BatchUpdateBlobsRequest>,
     ) -> Result<Response<BatchUpdateBlobsResponse>, Status> {
-        Ok(Response::new(BatchUpdateBlobsResponse::default()))
+        let BatchUpdateBlobsRequest {
+            instance_name,
+            requests,
+        } = request.into_inner();
+
+        self.validate_batch_size(requests.iter().map(|blob| blob.data.len() as u64).sum())?;
+
+        let instance_name = InstanceName::new(instance_name);
+        let store = self.stores.get_store_by_instance_name(&instance_name)?;
+
+        // blobs that fail validation get their error right away, the others are written together
+        let mut responses = Vec::with_capacity(requests.len());
+        let mut entries = Vec::new();
+        let mut entry_positions = Vec::new();
+
+        for blob in requests {
+            let digest = blob.digest.unwrap_or_default();
+            let validated = DigestInfo::try_from(digest.clone()).and_then(|digest_info| {
+                if blob.compressor != Compressor::Identity as i32 {
+                    Err(Error::UnsupportedOperation(
+                        "only uncompressed blobs are accepted",
+                    ))
+                } else if DigestInfo::compute(&blob.data) != digest_info {
+                    Err(Error::DigestMismatch(digest_info))
+                } else {
+                    Ok(digest_info)
+                }
+            });
+
+            let status = match validated {
+                Ok(digest_info) => {
+                    entry_positions.push(responses.len());
+                    entries.push((digest_info, Bytes::from(blob.data)));
+                    None
+                }
+                Err(err) => Some(err.into()),
+            };
+
+            responses.push(batch_update_blobs_response::Response {
+                digest: Some(digest),
+                status,
+            });
+        }
+
+        for (position, result) in entry_positions
+            .into_iter()
+            .zip(store.put_many(entries).await)
+        {
+            responses[position].status = Some(match result {
+                Ok(()) => RpcStatus::default(),
+                Err(err) => err.into(),
+            });
+        }
+
+        Ok(Response::new(BatchUpdateBlobsResponse { responses }))
     }
 
     #[instrument(err, skip(self))]
@@ -90,34 +228,45 @@ impl ContentAddressableStorage for ContentAddressableStorageService {
         let BatchReadBlobsRequest {
             instance_name,
             digests,
+            // every client has to accept uncompressed blobs, which is all we send
             acceptable_compressors: _,
         } = request.into_inner();
 
+        self.validate_batch_size(
+            digests
+                .iter()
+                .map(|digest| digest.size_bytes.max(0) as u64)
+                .sum(),
+        )?;
+
         let instance_name = InstanceName::new(instance_name);
         let store = self.stores.get_store_by_instance_name(&instance_name)?;
 
-        // let join_handles = FuturesUnordered::new();
-
-        for digest in digests {
-            let digest: DigestInfo = digest.try_into()?;
-            let store = store.clone();
+        let digest_infos = digests
+            .iter()
+            .cloned()
+            .map(DigestInfo::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
 
-            // join_handles.push(tokio::spawn(async move {
-            //     let size_bytes: usize = digest.size_bytes.try_into().map_err(|_| {
-            //         Status::invalid_argument(
-            //             "digest's `size_bytes` could not be converted into a valid usize",
-            //         )
-            //     })?;
-
-            //     store
-            //         .get_part_unchunked(digest, 0, None, Some(size_bytes))
-            //         .await
-            // }))
-        }
+        let responses = digests
+            .into_iter()
+            .zip(store.get_many(&digest_infos).await)
+            .map(|(digest, result)| {
+                let (data, status) = match result {
+                    Ok(bytes) => (bytes.to_vec(), RpcStatus::default()),
+                    Err(err) => (Vec::new(), err.into()),
+                };
 
-        // futures::future::try_join_all(join_handles);
+                batch_read_blobs_response::Response {
+                    digest: Some(digest),
+                    data,
+                    compressor: Compressor::Identity.into(),
+                    status: Some(status),
+                }
+            })
+            .collect();
 
-        Ok(Response::new(BatchReadBlobsResponse::default()))
+        Ok(Response::new(BatchReadBlobsResponse { responses }))
     }
 
     type GetTreeStream = BoxStream<'static, Result<GetTreeResponse, Status>>;
@@ -125,8 +274,193 @@ impl ContentAddressableStorage for ContentAddressableStorageService {
     #[instrument(err, skip(self))]
     async fn get_tree(
         &self,
-        _request: Request<GetTreeRequest>,
+        request: Request<GetTreeRequest>,
     ) -> Result<Response<Self::GetTreeStream>, Status> {
-        todo!();
+        let GetTreeRequest {
+            instance_name,
+            root_digest,
+            page_size,
+            page_token,
+        } = request.into_inner();
+
+        // no token is ever handed out, see below
+        if !page_token.is_empty() {
+            return Err(Status::invalid_argument(
+                "`page_token` is not a token of this server",
+            ));
+        }
+
+        let root_digest: DigestInfo = root_digest
+            .ok_or_else(|| Status::invalid_argument("`root_digest` is required"))?
+            .try_into()?;
+        let page_size = match page_size {
+            page_size if page_size > 0 => page_size as usize,
+            _ => DEFAULT_TREE_PAGE_SIZE,
+        };
+
+        let instance_name = InstanceName::new(instance_name);
+        let store = self.stores.get_store_by_instance_name(&instance_name)?;
+
+        // walk the tree breadth first. Parts of it that are missing are left out, as the spec asks
+        let mut directories = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::from([root_digest]);
+
+        while let Some(digest) = queue.pop_front() {
+            if !seen.insert(digest.clone()) {
+                continue;
+            }
+
+            let bytes = match store.get(&digest).await {
+                Ok(bytes) => bytes,
+                Err(_) => continue,
+            };
+            let directory = Directory::decode(bytes).map_err(Error::from)?;
+
+            for directory_node in &directory.directories {
+                if let Some(digest) = &directory_node.digest {
+                    queue.push_back(digest.clone().try_into()?);
+                }
+            }
+
+            directories.push(directory);
+        }
+
+        // every page is streamed right away, so there is never a page left for a token to point at
+        let responses: Vec<Result<GetTreeResponse, Status>> = directories
+            .chunks(page_size)
+            .map(|page| GetTreeResponse {
+                directories: page.to_vec(),
+                next_page_token: String::new(),
+            })
+            .map(Ok)
+            .collect();
+
+        Ok(Response::new(Box::pin(tokio_stream::iter(responses))))
+    }
+
+    /// Splits the blob in content-defined chunks, which are written to the CAS for the client to
+    /// fetch the ones it does not have yet
+    #[instrument(err, skip(self))]
+    async fn split_blob(
+        &self,
+        request: Request<SplitBlobRequest>,
+    ) -> Result<Response<SplitBlobResponse>, Status> {
+        let SplitBlobRequest {
+            instance_name,
+            blob_digest,
+            digest_function,
+        } = request.into_inner();
+
+        validate_digest_function(digest_function)?;
+        let blob_digest: DigestInfo = blob_digest
+            .ok_or_else(|| Status::invalid_argument("`blob_digest` is required"))?
+            .try_into()?;
+
+        let instance_name = InstanceName::new(instance_name);
+        let store = self.stores.get_store_by_instance_name(&instance_name)?;
+
+        let bytes = store.get(&blob_digest).await?;
+        let chunking = self.chunking;
+        // chunking and hashing a large blob would hold up the other requests
+        let chunks = tokio::task::spawn_blocking(move || dedup::split(&bytes, chunking))
+            .await
+            .map_err(Error::from)?;
+
+        let chunk_digests: Vec<_> = chunks.iter().map(|(digest, _)| digest.clone()).collect();
+
+        let mut seen = HashSet::new();
+        let missing = chunks
+            .into_iter()
+            .zip(store.contains_keys(&chunk_digests).await)
+            .filter(|((digest, _), present)| !present && seen.insert(digest.clone()))
+            .map(|(chunk, _)| chunk)
+            .collect();
+
+        for result in store.put_many(missing).await {
+            result?;
+        }
+
+        Ok(Response::new(SplitBlobResponse {
+            chunk_digests: chunk_digests.into_iter().map(Digest::from).collect(),
+            digest_function: DigestFunction::Sha256.into(),
+        }))
+    }
+
+    /// Concatenates chunks already in the CAS into the blob, checking it matches its digest
+    #[instrument(err, skip(self, request))]
+    async fn splice_blob(
+        &self,
+        request: Request<SpliceBlobRequest>,
+    ) -> Result<Response<SpliceBlobResponse>, Status> {
+        let SpliceBlobRequest {
+            instance_name,
+            blob_digest,
+            chunk_digests,
+            digest_function,
+        } = request.into_inner();
+
+        validate_digest_function(digest_function)?;
+        let blob_digest: DigestInfo = blob_digest
+            .ok_or_else(|| Status::invalid_argument("`blob_digest` is required"))?
+            .try_into()?;
+        let chunk_digests = chunk_digests
+            .into_iter()
+            .map(DigestInfo::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if std::iter::once(&blob_digest)
+            .chain(&chunk_digests)
+            .any(|digest| digest.size_bytes < 0)
+        {
+            return Err(
+                Error::InvalidDigestParts("`size_bytes` cannot be negative".to_string()).into(),
+            );
+        }
+
+        // chunks that cannot add up to the blob are turned away before reading any of them
+        let length = chunk_digests
+            .iter()
+            .try_fold(0i64, |length, digest| length.checked_add(digest.size_bytes));
+        if length != Some(blob_digest.size_bytes) {
+            return Err(Error::DigestMismatch(blob_digest).into());
+        }
+
+        let instance_name = InstanceName::new(instance_name);
+        let store = self.stores.get_store_by_instance_name(&instance_name)?;
+
+        // and so are blobs too large to assemble in memory, or that could never be kept, as
+        // advertised by `GetCapabilities`
+        let max_blob_size_bytes = self
+            .stores
+            .quota_usage(&instance_name)
+            .and_then(|usage| usage.quota.max_bytes)
+            .map_or(self.max_splice_blob_size_bytes, |max_bytes| {
+                max_bytes.min(self.max_splice_blob_size_bytes)
+            });
+        if blob_digest.size_bytes as u64 > max_blob_size_bytes {
+            return Err(Error::BlobTooLarge(blob_digest.size_bytes, max_blob_size_bytes).into());
+        }
+
+        // the declared size is not trusted until the chunks are read, so it is not preallocated
+        let mut bytes = BytesMut::new();
+        for chunk in store.get_many(&chunk_digests).await {
+            bytes.extend_from_slice(&chunk?);
+        }
+        let bytes = bytes.freeze();
+
+        let spliced = bytes.clone();
+        let computed = tokio::task::spawn_blocking(move || DigestInfo::compute(&spliced))
+            .await
+            .map_err(Error::from)?;
+        if computed != blob_digest {
+            return Err(Error::DigestMismatch(blob_digest).into());
+        }
+
+        store.put(blob_digest.clone(), bytes).await?;
+
+        Ok(Response::new(SpliceBlobResponse {
+            blob_digest: Some(blob_digest.into()),
+        }))
     }
 }
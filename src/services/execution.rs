@@ -0,0 +1,191 @@
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use prost::Message;
+use tokio::sync::watch;
+use tonic::{Request, Response, Status};
+use tracing::instrument;
+use uuid::Uuid;
+
+use super::capabilities::HIGH_CACHE_PRIORITY;
+use crate::{
+    domain::{DigestInfo, InstanceName},
+    errors::Error,
+    infrastructure::{Store, StoreKind, StoreManager},
+    protos::{
+        build::bazel::remote::execution::v2::{
+            execution_server::{Execution, ExecutionServer},
+            execution_stage::Value as ExecutionStage,
+            Action, ActionResult, Command, ExecuteRequest, ExecuteResponse, WaitExecutionRequest,
+        },
+        google::{longrunning::Operation, rpc::Status as RpcStatus},
+    },
+    scheduler::{self, ExecutionSpec, Scheduler},
+};
+
+/// Streams the updates of an operation, starting with its current state, until it is done
+fn operation_updates(
+    receiver: watch::Receiver<Operation>,
+) -> BoxStream<'static, Result<Operation, Status>> {
+    Box::pin(stream::unfold(Some((receiver, true)), |state| async move {
+        let (mut receiver, first) = state?;
+        // the operation is gone when its sender is, which only happens once it is done
+        if !first && receiver.changed().await.is_err() {
+            return None;
+        }
+
+        let operation = receiver.borrow_and_update().clone();
+        let next = (!operation.done).then_some((receiver, false));
+
+        Some((Ok(operation), next))
+    }))
+}
+
+/// Decodes the message stored in `store` under `digest`, which is reported as missing when not
+/// found
+async fn read_message<T: Message + Default>(
+    store: &StoreKind,
+    digest: &DigestInfo,
+) -> Result<T, Error> {
+    let bytes = store.get(digest).await.map_err(|err| match err {
+        Error::DigestInfoNotFound(_) => Error::MissingBlobs(vec![digest.clone()]),
+        err => err,
+    })?;
+
+    Ok(T::decode(bytes)?)
+}
+
+pub struct ExecutionService {
+    cas_stores: StoreManager,
+    action_cache_stores: StoreManager,
+    scheduler: Scheduler,
+}
+
+impl ExecutionService {
+    pub fn new(
+        cas_stores: StoreManager,
+        action_cache_stores: StoreManager,
+        scheduler: Scheduler,
+    ) -> Self {
+        Self {
+            cas_stores,
+            action_cache_stores,
+            scheduler,
+        }
+    }
+
+    pub fn into_server(self) -> ExecutionServer<ExecutionService> {
+        ExecutionServer::new(self)
+    }
+
+    /// Result of the action from the ActionCache, as the operation of an execution that is done
+    async fn cached_operation(
+        &self,
+        instance_name: &InstanceName,
+        action_digest: &DigestInfo,
+    ) -> Result<Option<Operation>, Error> {
+        let store = self
+            .action_cache_stores
+            .get_store_by_instance_name(instance_name)?;
+
+        let result = match store.get(action_digest).await {
+            Ok(bytes) => ActionResult::decode(bytes)?,
+            Err(Error::DigestInfoNotFound(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        Ok(Some(scheduler::operation(
+            &format!("operations/{}", Uuid::new_v4()),
+            action_digest,
+            ExecutionStage::Completed,
+            Some(ExecuteResponse {
+                result: Some(result),
+                cached_result: true,
+                status: Some(RpcStatus::default()),
+                ..Default::default()
+            }),
+        )))
+    }
+}
+
+#[async_trait]
+impl Execution for ExecutionService {
+    type ExecuteStream = BoxStream<'static, Result<Operation, Status>>;
+
+    #[instrument(err, skip(self))]
+    async fn execute(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> Result<Response<Self::ExecuteStream>, Status> {
+        let ExecuteRequest {
+            instance_name,
+            skip_cache_lookup,
+            action_digest,
+            execution_policy,
+            results_cache_policy,
+        } = request.into_inner();
+
+        let action_digest: DigestInfo = action_digest
+            .ok_or_else(|| Status::invalid_argument("`action_digest` is required"))?
+            .try_into()?;
+
+        let instance_name = InstanceName::new(instance_name);
+        let cas = self.cas_stores.get_store_by_instance_name(&instance_name)?;
+        let instance_name = self.cas_stores.resolve(&instance_name)?.clone();
+
+        let action: Action = read_message(&cas, &action_digest).await?;
+        let command_digest: DigestInfo = action
+            .command_digest
+            .ok_or_else(|| Status::invalid_argument("`command_digest` of the action is required"))?
+            .try_into()?;
+        let input_root_digest: DigestInfo = action
+            .input_root_digest
+            .ok_or_else(|| {
+                Status::invalid_argument("`input_root_digest` of the action is required")
+            })?
+            .try_into()?;
+
+        // the command also holds the platform of the actions of older clients
+        let command: Command = read_message(&cas, &command_digest).await?;
+        if !cas.contains_key(&input_root_digest).await {
+            return Err(Error::MissingBlobs(vec![input_root_digest]).into());
+        }
+
+        if !skip_cache_lookup {
+            if let Some(operation) = self
+                .cached_operation(&instance_name, &action_digest)
+                .await?
+            {
+                return Ok(Response::new(Box::pin(stream::once(async {
+                    Ok(operation)
+                }))));
+            }
+        }
+
+        let receiver = self.scheduler.schedule(ExecutionSpec {
+            instance_name,
+            action_digest,
+            platform: action.platform.or(command.platform).unwrap_or_default(),
+            priority: execution_policy.map_or(0, |policy| policy.priority),
+            do_not_cache: action.do_not_cache,
+            // lower values are more important, anything past the highest priority counts as it
+            prioritize_result: results_cache_policy.map_or(0, |policy| policy.priority)
+                <= HIGH_CACHE_PRIORITY,
+        });
+
+        Ok(Response::new(operation_updates(receiver)))
+    }
+
+    type WaitExecutionStream = BoxStream<'static, Result<Operation, Status>>;
+
+    #[instrument(err, skip(self))]
+    async fn wait_execution(
+        &self,
+        request: Request<WaitExecutionRequest>,
+    ) -> Result<Response<Self::WaitExecutionStream>, Status> {
+        let WaitExecutionRequest { name } = request.into_inner();
+
+        let receiver = self.scheduler.subscribe(&name)?;
+
+        Ok(Response::new(operation_updates(receiver)))
+    }
+}
@@ -1,3 +1,8 @@
+pub mod action_cache;
+pub mod admin;
+pub mod auth;
 pub mod bytestream;
 pub mod capabilities;
 pub mod cas;
+pub mod execution;
+pub mod worker;
@@ -0,0 +1,101 @@
+use async_trait::async_trait;
+use tonic::{codegen::InterceptedService, Request, Response, Status};
+use tracing::instrument;
+
+use super::auth::BearerAuthInterceptor;
+use crate::{
+    protos::bache::worker::v1::{
+        worker_server::{Worker, WorkerServer},
+        Assignment, CompleteWorkRequest, CompleteWorkResponse, KeepAliveRequest, KeepAliveResponse,
+        PollWorkRequest, PollWorkResponse,
+    },
+    scheduler::Scheduler,
+};
+
+/// Hands the actions of the [`Scheduler`] out to the workers, see `bache worker`
+pub struct WorkerService {
+    scheduler: Scheduler,
+}
+
+impl WorkerService {
+    pub fn new(scheduler: Scheduler) -> Self {
+        Self { scheduler }
+    }
+
+    pub fn into_server(
+        self,
+        worker_token: &str,
+    ) -> InterceptedService<WorkerServer<WorkerService>, BearerAuthInterceptor> {
+        WorkerServer::with_interceptor(self, BearerAuthInterceptor::new(worker_token, "worker"))
+    }
+}
+
+#[async_trait]
+impl Worker for WorkerService {
+    #[instrument(err, skip(self))]
+    async fn poll_work(
+        &self,
+        request: Request<PollWorkRequest>,
+    ) -> Result<Response<PollWorkResponse>, Status> {
+        let PollWorkRequest {
+            worker_id,
+            platform,
+        } = request.into_inner();
+
+        if worker_id.is_empty() {
+            return Err(Status::invalid_argument("`worker_id` is required"));
+        }
+
+        let assignment = self
+            .scheduler
+            .poll(&worker_id, &platform.unwrap_or_default())
+            .await
+            .map(|assignment| Assignment {
+                operation_name: assignment.operation_name,
+                instance_name: assignment.instance_name.to_string(),
+                action_digest: Some(assignment.action_digest.into()),
+                lease_duration: Some(assignment.lease_duration.into()),
+            });
+
+        Ok(Response::new(PollWorkResponse { assignment }))
+    }
+
+    #[instrument(err, skip(self))]
+    async fn keep_alive(
+        &self,
+        request: Request<KeepAliveRequest>,
+    ) -> Result<Response<KeepAliveResponse>, Status> {
+        let KeepAliveRequest {
+            worker_id,
+            operation_name,
+        } = request.into_inner();
+
+        self.scheduler.keep_alive(&worker_id, &operation_name)?;
+
+        Ok(Response::new(KeepAliveResponse {}))
+    }
+
+    #[instrument(err, skip(self, request))]
+    async fn complete_work(
+        &self,
+        request: Request<CompleteWorkRequest>,
+    ) -> Result<Response<CompleteWorkResponse>, Status> {
+        let CompleteWorkRequest {
+            worker_id,
+            operation_name,
+            result,
+            status,
+        } = request.into_inner();
+
+        self.scheduler
+            .complete(
+                &worker_id,
+                &operation_name,
+                result.unwrap_or_default(),
+                status.unwrap_or_default(),
+            )
+            .await?;
+
+        Ok(Response::new(CompleteWorkResponse {}))
+    }
+}
@@ -5,9 +5,11 @@ use eyre::WrapErr;
 use opentelemetry::{
     global,
     sdk::{
+        metrics::PushController,
         trace::{self, IdGenerator, Sampler, Tracer},
         Resource,
     },
+    util::tokio_interval_stream,
     KeyValue,
 };
 use opentelemetry_otlp::{Protocol, WithExportConfig};
@@ -53,7 +55,7 @@ pub struct LoggingConfig {
 #[derive(Parser, Debug, Clone)]
 #[clap(rename_all = "kebab-case", next_help_heading = "OPENTELEMETRY OPTIONS")]
 pub struct OpenTelemetryConfig {
-    /// Enable OpenTelemetry tracing
+    /// Enable OpenTelemetry tracing and metrics
     #[clap(long = "telemetry_enable_otpl", env = "BACHE_TELEMETRY_ENABLE_OTPL")]
     pub enable: bool,
 
@@ -105,8 +107,26 @@ fn create_opentelemetry_layer(otel_config: &OpenTelemetryConfig) -> eyre::Result
         .wrap_err("Failed to create OpenTelemetry tracing layer")
 }
 
-/// Unit struct, used to shutdown the global tracing-subscriber when it is dropped
-pub struct ShutdownGuard;
+/// Installs the global meter provider, pushing metrics to the same collector as traces
+fn create_opentelemetry_metrics(otel_config: &OpenTelemetryConfig) -> eyre::Result<PushController> {
+    opentelemetry_otlp::new_pipeline()
+        .metrics(tokio::spawn, tokio_interval_stream)
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&otel_config.endpoint)
+                .with_timeout(Duration::from_secs(otel_config.timeout))
+                .with_protocol(Protocol::Grpc),
+        )
+        .with_resource(vec![KeyValue::new("service.name", "bache")])
+        .build()
+        .wrap_err("Failed to create OpenTelemetry metrics pipeline")
+}
+
+/// Used to shutdown the global tracing-subscriber and metrics pipeline when it is dropped
+pub struct ShutdownGuard {
+    _metrics: Option<PushController>,
+}
 
 impl Drop for ShutdownGuard {
     fn drop(&mut self) {
@@ -131,6 +151,12 @@ pub fn init(config: &TracingConfig) -> eyre::Result<ShutdownGuard> {
         None
     };
 
+    let metrics = if config.open_telemetry_config.enable {
+        Some(create_opentelemetry_metrics(&config.open_telemetry_config)?)
+    } else {
+        None
+    };
+
     let subscriber = Registry::default()
         .with(EnvFilter::new(config.logging_config.level.to_string()))
         .with(opentelemetry_layer);
@@ -164,5 +190,5 @@ pub fn init(config: &TracingConfig) -> eyre::Result<ShutdownGuard> {
         tracing::debug!("Logging successfully initialized");
     }
 
-    Ok(ShutdownGuard)
+    Ok(ShutdownGuard { _metrics: metrics })
 }
@@ -0,0 +1,335 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::{self, File},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::BytesMut;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::{domain::InstanceName, errors::Error};
+
+/// Upload of an `{instance_name}/uploads/{uuid}/...` ByteStream resource name
+pub type UploadId = (InstanceName, Uuid);
+
+/// File an upload was spilled to, removed along with it
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    len: usize,
+}
+
+impl SpillFile {
+    fn create(dir: &Path, data: &[u8]) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(Uuid::new_v4().to_string());
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        file.write_all(data)?;
+
+        Ok(Self {
+            path,
+            file,
+            len: data.len(),
+        })
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.file.write_all(data)?;
+        self.len += data.len();
+
+        Ok(())
+    }
+
+    fn read(mut self) -> Result<BytesMut, Error> {
+        let mut data = vec![0; self.len];
+        self.file.rewind()?;
+        self.file.read_exact(&mut data)?;
+
+        Ok(BytesMut::from(data.as_slice()))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            ::tracing::warn!(path = %self.path.display(), %err, "failed to remove spilled upload");
+        }
+    }
+}
+
+enum UploadData {
+    Memory(BytesMut),
+    Spilled(SpillFile),
+}
+
+impl UploadData {
+    fn len(&self) -> usize {
+        match self {
+            UploadData::Memory(data) => data.len(),
+            UploadData::Spilled(file) => file.len,
+        }
+    }
+
+    /// Bytes of the upload held in memory
+    fn buffered_len(&self) -> usize {
+        match self {
+            UploadData::Memory(data) => data.len(),
+            UploadData::Spilled(_) => 0,
+        }
+    }
+}
+
+struct PartialUpload {
+    data: UploadData,
+    last_write: Instant,
+}
+
+#[derive(Default)]
+struct Uploads {
+    entries: HashMap<UploadId, PartialUpload>,
+    /// Bytes of all of the uploads held in memory
+    bytes: usize,
+}
+
+/// ByteStream uploads that were written to without being finished, kept for their clients to
+/// resume them where `QueryWriteStatus` tells them they left off. Uploads nobody writes to for
+/// longer than the idle timeout are dropped by [`PartialUploads::expire`]. The uploads hold at most
+/// a given number of bytes in memory together, the ones that would go past it being spilled to
+/// files of the spill directory, or turned away when there is none
+#[derive(Clone)]
+pub struct PartialUploads {
+    uploads: Arc<Mutex<Uploads>>,
+    idle_timeout: Duration,
+    max_bytes: usize,
+    spill_dir: Option<PathBuf>,
+    expired_uploads: Counter<u64>,
+    reclaimed_bytes: Counter<u64>,
+}
+
+impl PartialUploads {
+    pub fn new(idle_timeout: Duration, max_bytes: usize) -> Self {
+        let meter = global::meter("bache");
+        let expired_uploads = meter
+            .u64_counter("bache.bytestream.expired_uploads")
+            .with_description("Partial ByteStream uploads dropped for being idle")
+            .init();
+        let reclaimed_bytes = meter
+            .u64_counter("bache.bytestream.reclaimed_bytes")
+            .with_description("Bytes of the partial ByteStream uploads dropped for being idle")
+            .init();
+
+        Self {
+            uploads: Arc::default(),
+            idle_timeout,
+            max_bytes,
+            spill_dir: None,
+            expired_uploads,
+            reclaimed_bytes,
+        }
+    }
+
+    /// Spills the uploads that no longer fit in memory to files of `spill_dir`, created when
+    /// missing
+    pub fn with_spill_dir(mut self, spill_dir: PathBuf) -> Self {
+        self.spill_dir = Some(spill_dir);
+        self
+    }
+
+    /// Bytes written to the upload of `id` so far, `None` when it is not in progress, having
+    /// never been started, been finished or expired
+    pub fn committed_size(&self, id: &UploadId) -> Option<usize> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .entries
+            .get(id)
+            .map(|upload| upload.data.len())
+    }
+
+    /// Appends `data` to the upload of `id`, a blob of `size` bytes which must have had
+    /// `write_offset` bytes written to it so far, returning the bytes written to it now. Writing at
+    /// offset 0 starts the upload over
+    pub fn append(
+        &self,
+        id: &UploadId,
+        size: usize,
+        write_offset: i64,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let Uploads { entries, bytes } = &mut *uploads;
+        let upload = match entries.entry(id.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if write_offset == 0 => entry.insert(PartialUpload {
+                data: UploadData::Memory(BytesMut::new()),
+                last_write: Instant::now(),
+            }),
+            Entry::Vacant(_) => return Err(Error::UploadNotFound(id.1)),
+        };
+
+        if write_offset == 0 {
+            *bytes -= upload.data.buffered_len();
+            upload.data = UploadData::Memory(BytesMut::new());
+        }
+        if write_offset != upload.data.len() as i64 {
+            return Err(Error::WriteOffsetMismatch(write_offset, upload.data.len()));
+        }
+        // checked before buffering, an upload could otherwise grow without bound
+        if upload.data.len() + data.len() > size {
+            return Err(Error::WriteTooLarge(size));
+        }
+
+        match &mut upload.data {
+            UploadData::Memory(buffered) if *bytes + data.len() <= self.max_bytes => {
+                *bytes += data.len();
+                buffered.extend_from_slice(data);
+            }
+            UploadData::Memory(buffered) => {
+                let spill_dir = self
+                    .spill_dir
+                    .as_ref()
+                    .ok_or(Error::UploadsExhausted(self.max_bytes))?;
+                let mut file = SpillFile::create(spill_dir, buffered)?;
+                file.append(data)?;
+
+                *bytes -= buffered.len();
+                upload.data = UploadData::Spilled(file);
+            }
+            UploadData::Spilled(file) => file.append(data)?,
+        }
+        upload.last_write = Instant::now();
+
+        Ok(upload.data.len())
+    }
+
+    /// Removes the upload of `id`, returning the bytes written to it
+    pub fn finish(&self, id: &UploadId) -> Result<BytesMut, Error> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = uploads
+            .entries
+            .remove(id)
+            .ok_or(Error::UploadNotFound(id.1))?;
+        uploads.bytes -= upload.data.buffered_len();
+        drop(uploads);
+
+        match upload.data {
+            UploadData::Memory(data) => Ok(data),
+            UploadData::Spilled(file) => file.read(),
+        }
+    }
+
+    /// Drops the uploads nobody wrote to for longer than the idle timeout
+    pub fn expire(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.entries.retain(|id, upload| {
+            let is_idle = now.duration_since(upload.last_write) > self.idle_timeout;
+            if is_idle {
+                expired.push((id.clone(), upload.data.len(), upload.data.buffered_len()));
+            }
+
+            !is_idle
+        });
+        uploads.bytes -= expired
+            .iter()
+            .map(|(_, _, buffered)| buffered)
+            .sum::<usize>();
+        drop(uploads);
+
+        for ((instance_name, uuid), bytes, _) in expired {
+            ::tracing::info!(%instance_name, %uuid, bytes, "expired idle partial upload");
+
+            let attributes = [KeyValue::new("instance_name", instance_name.to_string())];
+            self.expired_uploads.add(1, &attributes);
+            self.reclaimed_bytes.add(bytes as u64, &attributes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn upload_id() -> UploadId {
+        (InstanceName::from(""), Uuid::new_v4())
+    }
+
+    fn buffered_bytes(uploads: &PartialUploads) -> usize {
+        uploads.uploads.lock().unwrap().bytes
+    }
+
+    #[test]
+    fn writes_past_the_memory_budget_are_turned_away_without_a_spill_dir() {
+        let uploads = PartialUploads::new(IDLE_TIMEOUT, 10);
+        let (first, second) = (upload_id(), upload_id());
+
+        uploads.append(&first, 100, 0, &[0; 6]).unwrap();
+        assert!(matches!(
+            uploads.append(&second, 100, 0, &[0; 6]),
+            Err(Error::UploadsExhausted(10))
+        ));
+        assert_eq!(buffered_bytes(&uploads), 6);
+
+        // starting over gives back what was written before
+        uploads.append(&first, 100, 0, &[0; 2]).unwrap();
+        uploads.append(&second, 100, 0, &[0; 6]).unwrap();
+        assert_eq!(buffered_bytes(&uploads), 8);
+
+        assert_eq!(uploads.finish(&first).unwrap().len(), 2);
+        assert_eq!(buffered_bytes(&uploads), 6);
+    }
+
+    #[test]
+    fn uploads_past_the_memory_budget_are_spilled() {
+        let spill_dir = std::env::temp_dir().join(format!("bache-uploads-{}", Uuid::new_v4()));
+        let uploads = PartialUploads::new(IDLE_TIMEOUT, 10).with_spill_dir(spill_dir.clone());
+        let (small, large) = (upload_id(), upload_id());
+
+        uploads.append(&small, 100, 0, b"small").unwrap();
+        uploads.append(&large, 100, 0, b"larger").unwrap();
+        assert_eq!(uploads.append(&large, 100, 6, b" than").unwrap(), 11);
+        assert_eq!(buffered_bytes(&uploads), 5);
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 1);
+
+        assert_eq!(&uploads.finish(&large).unwrap()[..], b"larger than");
+        assert_eq!(&uploads.finish(&small).unwrap()[..], b"small");
+        assert_eq!(buffered_bytes(&uploads), 0);
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 0);
+
+        fs::remove_dir(spill_dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expiring_idle_uploads_reclaims_their_bytes() {
+        let spill_dir = std::env::temp_dir().join(format!("bache-uploads-{}", Uuid::new_v4()));
+        let uploads = PartialUploads::new(IDLE_TIMEOUT, 10).with_spill_dir(spill_dir.clone());
+        let (buffered, spilled, active) = (upload_id(), upload_id(), upload_id());
+
+        uploads.append(&buffered, 100, 0, &[0; 4]).unwrap();
+        uploads.append(&spilled, 100, 0, &[0; 20]).unwrap();
+        tokio::time::advance(IDLE_TIMEOUT).await;
+        uploads.append(&active, 100, 0, &[0; 2]).unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        uploads.expire();
+        assert_eq!(uploads.committed_size(&buffered), None);
+        assert_eq!(uploads.committed_size(&spilled), None);
+        assert_eq!(uploads.committed_size(&active), Some(2));
+        assert_eq!(buffered_bytes(&uploads), 2);
+        assert_eq!(fs::read_dir(&spill_dir).unwrap().count(), 0);
+
+        fs::remove_dir(spill_dir).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use moka::sync::{Cache, ConcurrentCacheExt};
+use uuid::Uuid;
+
+use super::inputs;
+use crate::{
+    domain::DigestInfo,
+    errors::Error,
+    infrastructure::{Store, StoreKind},
+};
+
+/// Most bytes of blobs read from the CAS at once, so that the missing inputs of an action are not
+/// all held in memory together
+const FETCH_BATCH_BYTES: i64 = 64 * 1024 * 1024;
+
+/// Blob of a cached file and whether it is executable. The hardlinks of a file share its mode, so
+/// the executable and non executable copies of a blob are cached apart
+type Key = (DigestInfo, bool);
+
+/// Name of the file of `key` in the cache, `{hash}-{size}`, suffixed by `-x` when executable
+fn file_name((digest, is_executable): &Key) -> String {
+    let suffix = if *is_executable { "-x" } else { "" };
+    format!("{}-{}{suffix}", digest.hash(), digest.size_bytes)
+}
+
+fn parse_file_name(name: &str) -> Option<Key> {
+    let (name, is_executable) = match name.strip_suffix("-x") {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+    let (hash, size) = name.split_once('-')?;
+    let digest = DigestInfo::try_new(hash, size.parse::<i64>().ok()?).ok()?;
+
+    Some((digest, is_executable))
+}
+
+/// Content-addressed cache of the input files of actions, which are hardlinked into the input
+/// roots of the actions using them rather than downloaded for each of them. Holds at most a given
+/// number of bytes of files, evicting the least recently used ones first.
+///
+/// Cached files are read-only, an action run by a user allowed to write to them anyway could
+/// still corrupt the cache
+pub struct FileCache {
+    path: PathBuf,
+    files: Cache<Key, ()>,
+}
+
+impl FileCache {
+    /// Opens the cache in `path`, keeping the files a previous worker left there. The sandboxes
+    /// of the actions must be on the same filesystem, for the files to be hardlinked into them
+    pub fn open(path: &Path, max_bytes: u64) -> Result<Self, Error> {
+        std::fs::create_dir_all(path)?;
+
+        let cache_path = path.to_path_buf();
+        // weighed in KiB, as weights only go up to 4GiB
+        let files = Cache::builder()
+            .max_capacity(max_bytes.div_ceil(1024))
+            .weigher(|(digest, _): &Key, _| {
+                u32::try_from((digest.size_bytes.max(0) as u64).div_ceil(1024)).unwrap_or(u32::MAX)
+            })
+            .eviction_listener(move |key: Arc<Key>, _, cause| {
+                if !cause.was_evicted() {
+                    return;
+                }
+
+                // the sandboxes the file is linked into keep it around until they are removed
+                let file_path = cache_path.join(file_name(&key));
+                match std::fs::remove_file(&file_path) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == ErrorKind::NotFound => {}
+                    Err(err) => ::tracing::warn!(
+                        %err,
+                        path = %file_path.display(),
+                        "failed to remove evicted file"
+                    ),
+                }
+            })
+            .build();
+
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            match parse_file_name(&entry.file_name().to_string_lossy()) {
+                Some(key) => files.insert(key, ()),
+                // partial download of a worker that was interrupted
+                None => std::fs::remove_file(entry.path())?,
+            }
+        }
+        files.sync();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            files,
+        })
+    }
+
+    /// Hardlinks the blob of each of `files`, `(digest, path, is_executable)`, to its path, first
+    /// reading the blobs not cached yet from `cas`, at most [`FETCH_BATCH_BYTES`] at once
+    pub async fn link(
+        &self,
+        cas: &StoreKind,
+        files: Vec<(DigestInfo, PathBuf, bool)>,
+    ) -> Result<(), Error> {
+        let mut missing: HashMap<Key, Vec<PathBuf>> = HashMap::new();
+        for (digest, path, is_executable) in files {
+            let key = (digest, is_executable);
+
+            if self.files.get(&key).is_some() {
+                match tokio::fs::hard_link(self.path.join(file_name(&key)), &path).await {
+                    Ok(()) => continue,
+                    // evicted since, the entry is replaced once downloaded again
+                    Err(err) if err.kind() == ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+
+            missing.entry(key).or_default().push(path);
+        }
+
+        let mut missing = missing.into_iter().peekable();
+        while missing.peek().is_some() {
+            let mut batch_bytes = 0;
+            let mut batch = Vec::new();
+            // a blob larger than a batch gets one of its own
+            while let Some(((digest, _), _)) = missing.peek() {
+                if !batch.is_empty() && batch_bytes + digest.size_bytes > FETCH_BATCH_BYTES {
+                    break;
+                }
+
+                batch_bytes += digest.size_bytes;
+                batch.push(missing.next().unwrap());
+            }
+
+            self.fetch(cas, batch).await?;
+        }
+        // evicts right away rather than eventually, to keep to the size of the cache
+        self.files.sync();
+
+        Ok(())
+    }
+
+    /// Reads the blob of each of `files` from `cas` into the cache, then hardlinks it to its paths
+    async fn fetch(&self, cas: &StoreKind, files: Vec<(Key, Vec<PathBuf>)>) -> Result<(), Error> {
+        let (keys, paths): (Vec<_>, Vec<_>) = files.into_iter().unzip();
+        let digests: Vec<_> = keys.iter().map(|(digest, _)| digest.clone()).collect();
+        let blobs = cas.get_many(&digests).await;
+
+        for ((key, paths), bytes) in keys.into_iter().zip(paths).zip(blobs) {
+            // a corrupt blob would be linked into every action using it for as long as it is cached
+            let bytes = bytes.map_err(inputs::missing_input(&key.0))?;
+            let verified = bytes.clone();
+            let computed =
+                tokio::task::spawn_blocking(move || DigestInfo::compute(&verified)).await?;
+            if computed != key.0 {
+                return Err(Error::DigestMismatch(key.0));
+            }
+
+            let temporary_path = self.path.join(format!("tmp-{}", Uuid::new_v4()));
+            tokio::fs::write(&temporary_path, bytes).await?;
+
+            let mode = if key.1 { 0o555 } else { 0o444 };
+            tokio::fs::set_permissions(&temporary_path, std::fs::Permissions::from_mode(mode))
+                .await?;
+
+            // linked before being moved into the cache, where it may be evicted at any time
+            for path in paths {
+                tokio::fs::hard_link(&temporary_path, path).await?;
+            }
+
+            // a file downloaded at the same time for another action is replaced by the same bytes
+            tokio::fs::rename(&temporary_path, self.path.join(file_name(&key))).await?;
+            self.files.insert(key, ());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::infrastructure::memory::MemoryStore;
+
+    /// Directory of its own in the temporary directory, for the test to remove once done
+    fn temporary_directory() -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bache-file-cache-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+
+        path
+    }
+
+    fn cached_files(cache: &FileCache) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(&cache.path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+
+        names
+    }
+
+    #[test]
+    fn open_indexes_previous_files_and_removes_partial_downloads() {
+        let path = temporary_directory();
+        let key = (DigestInfo::compute(b"data"), true);
+        std::fs::write(path.join(file_name(&key)), b"data").unwrap();
+        std::fs::write(path.join(format!("tmp-{}", Uuid::new_v4())), b"da").unwrap();
+
+        let cache = FileCache::open(&path, 1024 * 1024).unwrap();
+
+        assert!(cache.files.contains_key(&key));
+        assert_eq!(cached_files(&cache), vec![file_name(&key)]);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn evicted_files_are_removed() {
+        let path = temporary_directory();
+        let cache = FileCache::open(&path.join("cache"), 2 * 1024).unwrap();
+        let cas = StoreKind::from(MemoryStore::new(1024 * 1024));
+
+        for (index, data) in [[1; 1024], [2; 1024], [3; 1024]].iter().enumerate() {
+            let digest = DigestInfo::compute(data);
+            cas.put(digest.clone(), Bytes::copy_from_slice(data))
+                .await
+                .unwrap();
+
+            let link = path.join(index.to_string());
+            cache.link(&cas, vec![(digest, link, false)]).await.unwrap();
+        }
+
+        let mut indexed: Vec<_> = cache.files.iter().map(|(key, _)| file_name(&key)).collect();
+        indexed.sort();
+        assert_eq!(indexed.len(), 2);
+        assert_eq!(cached_files(&cache), indexed);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
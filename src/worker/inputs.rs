@@ -0,0 +1,83 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use prost::Message;
+
+use super::file_cache::FileCache;
+use crate::{
+    domain::DigestInfo,
+    errors::Error,
+    infrastructure::{Store, StoreKind},
+    protos::build::bazel::remote::execution::v2::{Digest, Directory},
+};
+
+/// Name of a file, directory or symlink of a `Directory`, which must be a single path segment
+fn validate_name(name: &str) -> Result<&str, Error> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("`{name}` is not a valid name for an input"),
+        )
+        .into());
+    }
+
+    Ok(name)
+}
+
+fn digest_info(digest: Option<Digest>) -> Result<DigestInfo, Error> {
+    digest.unwrap_or_default().try_into()
+}
+
+/// Reports a blob an action needs but the CAS does not have as a missing input, which the REAPI
+/// asks for along with its digest so that clients can upload it again
+pub fn missing_input(digest: &DigestInfo) -> impl FnOnce(Error) -> Error + '_ {
+    move |err| match err {
+        Error::DigestInfoNotFound(_) => Error::MissingBlobs(vec![digest.clone()]),
+        err => err,
+    }
+}
+
+/// Writes the tree of `Directory`s rooted at `root_digest` to `path`, which must not exist yet,
+/// reading its directories from `cas` and linking its files from `file_cache`. The directories of
+/// every level of the tree are read together, as are the files missing from the cache
+pub async fn materialize(
+    cas: &StoreKind,
+    file_cache: &FileCache,
+    root_digest: &DigestInfo,
+    path: &Path,
+) -> Result<(), Error> {
+    let mut level: Vec<(DigestInfo, PathBuf)> = vec![(root_digest.clone(), path.to_path_buf())];
+
+    while !level.is_empty() {
+        let digests: Vec<_> = level.iter().map(|(digest, _)| digest.clone()).collect();
+        let directories = cas.get_many(&digests).await;
+
+        let mut next_level = Vec::new();
+        let mut files = Vec::new();
+        for ((digest, path), directory) in level.into_iter().zip(directories) {
+            let directory = Directory::decode(directory.map_err(missing_input(&digest))?)?;
+            tokio::fs::create_dir(&path).await?;
+
+            for file in directory.files {
+                let file_path = path.join(validate_name(&file.name)?);
+                files.push((digest_info(file.digest)?, file_path, file.is_executable));
+            }
+            for symlink in directory.symlinks {
+                let symlink_path = path.join(validate_name(&symlink.name)?);
+                tokio::fs::symlink(&symlink.target, symlink_path).await?;
+            }
+            for child in directory.directories {
+                let child_path = path.join(validate_name(&child.name)?);
+                next_level.push((digest_info(child.digest)?, child_path));
+            }
+        }
+
+        file_cache.link(cas, files).await?;
+
+        level = next_level;
+    }
+
+    Ok(())
+}
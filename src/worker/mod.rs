@@ -0,0 +1,557 @@
+use std::{
+    collections::HashMap,
+    os::unix::process::ExitStatusExt,
+    path::{Component, Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use clap::Parser;
+use eyre::WrapErr;
+use prost::Message;
+use tonic::{metadata::MetadataValue, transport::Channel, Code, Request};
+use uuid::Uuid;
+
+use crate::{
+    domain::DigestInfo,
+    errors::Error,
+    infrastructure::{
+        grpc_upstream::{GrpcUpstreamStore, RemoteApi},
+        Store, StoreKind,
+    },
+    protos::{
+        bache::worker::v1::{
+            worker_client::WorkerClient, Assignment, CompleteWorkRequest, KeepAliveRequest,
+            PollWorkRequest,
+        },
+        build::bazel::remote::execution::v2::{
+            platform::Property, Action, ActionResult, Command, Digest, ExecutedActionMetadata,
+            Platform,
+        },
+        google::rpc::Status as RpcStatus,
+    },
+    tracing::{self, TracingConfig},
+};
+
+mod file_cache;
+mod inputs;
+mod outputs;
+
+use file_cache::FileCache;
+use outputs::Blobs;
+
+/// Property of the platform of a worker, as given on the command line, `{name}={value}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlatformProperty {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for PlatformProperty {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, property_value) = value
+            .split_once('=')
+            .ok_or_else(|| format!("`{value}` is not of the form `name=value`"))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            value: property_value.to_string(),
+        })
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(rename_all = "kebab-case", next_help_heading = "WORKER CONFIGS")]
+pub struct WorkerConfig {
+    /// Address of the server scheduling the actions, whose CAS holds their inputs and is given
+    /// their outputs
+    #[clap(
+        long = "endpoint",
+        env = "BACHE_ENDPOINT",
+        default_value = "http://localhost:50051"
+    )]
+    pub server_endpoint: String,
+
+    /// Credential of the workers, the `--worker-token` the server was started with
+    #[clap(long, env = "BACHE_WORKER_TOKEN")]
+    pub worker_token: String,
+
+    /// Name of this worker, unique among the workers of the server. Random when unset
+    #[clap(long, env = "BACHE_WORKER_ID")]
+    pub worker_id: Option<String>,
+
+    /// Comma separated list of the properties of the platform actions are run on, as
+    /// `name=value`, e.g. `OSFamily=linux`. Only the actions whose platform has no other
+    /// properties are run
+    #[clap(long, env = "BACHE_WORKER_PLATFORM", use_value_delimiter = true)]
+    pub platform: Vec<PlatformProperty>,
+
+    /// Directory the sandboxes of the actions are created in, along with the cache of their
+    /// input files
+    #[clap(long, env = "BACHE_WORKER_DIR", default_value = "/tmp/bache-worker")]
+    pub work_dir: PathBuf,
+
+    /// Maximum size in bytes of the cache of input files, the least recently used ones being
+    /// evicted first
+    #[clap(
+        long,
+        env = "BACHE_WORKER_CACHE_MAX_BYTES",
+        default_value_t = 10 * 1024 * 1024 * 1024
+    )]
+    pub cache_max_bytes: u64,
+
+    /// Number of actions run at once
+    #[clap(long, env = "BACHE_WORKER_CONCURRENCY", default_value_t = 1)]
+    pub concurrency: usize,
+
+    /// Seconds an action runs for at most, whether it sets a shorter timeout or none
+    #[clap(
+        long,
+        env = "BACHE_WORKER_MAX_ACTION_TIMEOUT_SECONDS",
+        default_value_t = 3600
+    )]
+    pub max_action_timeout_seconds: u64,
+}
+
+/// Outcome of an action that could not run to completion
+struct Failure {
+    status: RpcStatus,
+    /// What the action output before failing, if it ran at all. Boxed, since failures are
+    /// returned far more often than they hold one
+    result: Box<ActionResult>,
+}
+
+impl Failure {
+    fn new(code: Code, message: String) -> Self {
+        Self {
+            status: RpcStatus {
+                code: code as i32,
+                message,
+                details: Vec::new(),
+            },
+            result: Box::default(),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        Self {
+            status: err.into(),
+            result: Box::default(),
+        }
+    }
+}
+
+impl From<std::io::Error> for Failure {
+    fn from(err: std::io::Error) -> Self {
+        Error::from(err).into()
+    }
+}
+
+fn digest_info(digest: Option<Digest>) -> Result<DigestInfo, Error> {
+    digest.unwrap_or_default().try_into()
+}
+
+/// Whether the relative `path` stays below the directory it is joined to
+fn is_below(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Joins the working directory of an action to its input root, which it may not escape
+fn working_directory(input_root: &Path, working_directory: &str) -> Result<PathBuf, Failure> {
+    let relative = Path::new(working_directory);
+    if !is_below(relative) {
+        return Err(Failure::new(
+            Code::InvalidArgument,
+            format!("`{working_directory}` is not a valid working directory"),
+        ));
+    }
+
+    Ok(input_root.join(relative))
+}
+
+/// Runs the actions the server hands out to it, each in a sandbox directory of its own
+#[derive(Clone)]
+struct Worker {
+    client: WorkerClient<Channel>,
+    authorization: MetadataValue<tonic::metadata::Ascii>,
+    worker_id: String,
+    platform: Platform,
+    endpoint: String,
+    work_dir: PathBuf,
+    max_action_timeout: Duration,
+    /// CAS of every instance actions were run for
+    cas_stores: Arc<Mutex<HashMap<String, Arc<StoreKind>>>>,
+    /// Input files of the actions, shared by the instances since blobs are content-addressed
+    file_cache: Arc<FileCache>,
+}
+
+impl Worker {
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request
+            .metadata_mut()
+            .insert("authorization", self.authorization.clone());
+
+        request
+    }
+
+    fn cas(&self, instance_name: &str) -> Result<Arc<StoreKind>, Error> {
+        let mut cas_stores = self.cas_stores.lock().unwrap();
+        if let Some(cas) = cas_stores.get(instance_name) {
+            return Ok(cas.clone());
+        }
+
+        let cas = Arc::new(StoreKind::from(GrpcUpstreamStore::new(
+            &self.endpoint,
+            instance_name.to_string(),
+            RemoteApi::ContentAddressableStorage,
+        )?));
+        cas_stores.insert(instance_name.to_string(), cas.clone());
+
+        Ok(cas)
+    }
+
+    /// Polls for actions and runs them, one at a time
+    async fn run(self) {
+        loop {
+            let polled = self
+                .client
+                .clone()
+                .poll_work(self.request(PollWorkRequest {
+                    worker_id: self.worker_id.clone(),
+                    platform: Some(self.platform.clone()),
+                }))
+                .await;
+
+            match polled {
+                Ok(response) => {
+                    if let Some(assignment) = response.into_inner().assignment {
+                        self.run_assignment(assignment).await;
+                    }
+                }
+                Err(status) => {
+                    ::tracing::warn!(%status, "failed to poll for work");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    /// Keeps extending the lease on the action of `operation_name`, until it is lost
+    async fn keep_alive(&self, operation_name: &str, lease_duration: Duration) {
+        let mut interval = tokio::time::interval(lease_duration / 3);
+        // the first tick completes right away
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let kept = self
+                .client
+                .clone()
+                .keep_alive(self.request(KeepAliveRequest {
+                    worker_id: self.worker_id.clone(),
+                    operation_name: operation_name.to_string(),
+                }))
+                .await;
+
+            match kept {
+                Ok(_) => {}
+                Err(status) if status.code() == Code::Aborted => return,
+                // the lease may still be renewed before it runs out
+                Err(status) => ::tracing::warn!(%status, "failed to extend lease"),
+            }
+        }
+    }
+
+    async fn run_assignment(&self, assignment: Assignment) {
+        let Assignment {
+            operation_name,
+            instance_name,
+            action_digest,
+            lease_duration,
+        } = assignment;
+        let lease_duration = lease_duration
+            .and_then(|duration| duration.try_into().ok())
+            .unwrap_or(Duration::from_secs(30));
+
+        ::tracing::info!(%operation_name, %instance_name, "running action");
+
+        let sandbox = self.work_dir.join(Uuid::new_v4().to_string());
+        let outcome = tokio::select! {
+            outcome = self.execute(&instance_name, action_digest, &sandbox) => outcome,
+            _ = self.keep_alive(&operation_name, lease_duration) => {
+                ::tracing::warn!(%operation_name, "lost the lease on the action, abandoning it");
+                self.remove_sandbox(&sandbox).await;
+                return;
+            }
+        };
+        self.remove_sandbox(&sandbox).await;
+
+        let (result, status) = match outcome {
+            Ok(result) => (result, RpcStatus::default()),
+            Err(Failure { status, result }) => {
+                ::tracing::warn!(
+                    %operation_name,
+                    code = status.code,
+                    message = %status.message,
+                    "action failed to run"
+                );
+                (*result, status)
+            }
+        };
+
+        let completed = self
+            .client
+            .clone()
+            .complete_work(self.request(CompleteWorkRequest {
+                worker_id: self.worker_id.clone(),
+                operation_name: operation_name.clone(),
+                result: Some(result),
+                status: Some(status),
+            }))
+            .await;
+
+        if let Err(status) = completed {
+            ::tracing::warn!(
+                %operation_name,
+                %status,
+                "failed to report the result of the action"
+            );
+        }
+    }
+
+    async fn remove_sandbox(&self, sandbox: &Path) {
+        if let Err(err) = tokio::fs::remove_dir_all(sandbox).await {
+            ::tracing::warn!(%err, sandbox = %sandbox.display(), "failed to remove sandbox");
+        }
+    }
+
+    /// Runs the action in `sandbox`, its input root being materialized in `sandbox/root`
+    async fn execute(
+        &self,
+        instance_name: &str,
+        action_digest: Option<Digest>,
+        sandbox: &Path,
+    ) -> Result<ActionResult, Failure> {
+        let mut metadata = ExecutedActionMetadata {
+            worker: self.worker_id.clone(),
+            worker_start_timestamp: Some(SystemTime::now().into()),
+            ..Default::default()
+        };
+
+        let cas = self.cas(instance_name)?;
+        let action_digest = digest_info(action_digest)?;
+        let action = cas
+            .get(&action_digest)
+            .await
+            .map_err(inputs::missing_input(&action_digest))?;
+        let action = Action::decode(action).map_err(Error::from)?;
+        let command_digest = digest_info(action.command_digest)?;
+        let command = cas
+            .get(&command_digest)
+            .await
+            .map_err(inputs::missing_input(&command_digest))?;
+        let command = Command::decode(command).map_err(Error::from)?;
+
+        if command.arguments.is_empty() {
+            return Err(Failure::new(
+                Code::InvalidArgument,
+                "the command has no arguments".to_string(),
+            ));
+        }
+        // outputs are created and read from below the working directory, which they may not escape
+        if let Some(path) = outputs::output_paths(&command)
+            .into_iter()
+            .find(|path| !is_below(Path::new(path)))
+        {
+            return Err(Failure::new(
+                Code::InvalidArgument,
+                format!("`{path}` is not a valid output path"),
+            ));
+        }
+
+        metadata.input_fetch_start_timestamp = Some(SystemTime::now().into());
+        tokio::fs::create_dir_all(sandbox).await?;
+        let input_root = sandbox.join("root");
+        inputs::materialize(
+            &cas,
+            &self.file_cache,
+            &digest_info(action.input_root_digest)?,
+            &input_root,
+        )
+        .await?;
+        let working_directory = working_directory(&input_root, &command.working_directory)?;
+        outputs::create_output_parents(&command, &working_directory)?;
+        metadata.input_fetch_completed_timestamp = Some(SystemTime::now().into());
+
+        let timeout = action
+            .timeout
+            .and_then(|timeout| Duration::try_from(timeout).ok())
+            .filter(|timeout| !timeout.is_zero())
+            .map_or(self.max_action_timeout, |timeout| {
+                timeout.min(self.max_action_timeout)
+            });
+
+        let stdout_path = sandbox.join("stdout");
+        let stderr_path = sandbox.join("stderr");
+
+        metadata.execution_start_timestamp = Some(SystemTime::now().into());
+        let mut child = tokio::process::Command::new(&command.arguments[0])
+            .args(&command.arguments[1..])
+            .current_dir(&working_directory)
+            .env_clear()
+            .envs(
+                command
+                    .environment_variables
+                    .iter()
+                    .map(|variable| (&variable.name, &variable.value)),
+            )
+            .stdin(Stdio::null())
+            .stdout(std::fs::File::create(&stdout_path)?)
+            .stderr(std::fs::File::create(&stderr_path)?)
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| {
+                Failure::new(
+                    Code::InvalidArgument,
+                    format!("failed to start `{}`, {err}", command.arguments[0]),
+                )
+            })?;
+
+        let exit_status = match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(exit_status) => Some(exit_status?),
+            Err(_) => {
+                child.kill().await?;
+                None
+            }
+        };
+        metadata.execution_completed_timestamp = Some(SystemTime::now().into());
+
+        let mut result = ActionResult {
+            // killed by a signal, as shells report it
+            exit_code: exit_status.map_or(-1, |exit_status| {
+                exit_status
+                    .code()
+                    .or_else(|| exit_status.signal().map(|signal| 128 + signal))
+                    .unwrap_or(-1)
+            }),
+            ..Default::default()
+        };
+
+        metadata.output_upload_start_timestamp = Some(SystemTime::now().into());
+        // hashing the outputs would hold up the other requests
+        let (mut result, mut blobs) = tokio::task::spawn_blocking(move || {
+            let mut blobs = Blobs::default();
+            outputs::collect(&command, &working_directory, &mut result, &mut blobs)
+                .map(|()| (result, blobs))
+        })
+        .await
+        .map_err(Error::from)??;
+
+        let stdout = Bytes::from(tokio::fs::read(&stdout_path).await?);
+        if !stdout.is_empty() {
+            result.stdout_digest = Some(blobs.add(stdout).into());
+        }
+        let stderr = Bytes::from(tokio::fs::read(&stderr_path).await?);
+        if !stderr.is_empty() {
+            result.stderr_digest = Some(blobs.add(stderr).into());
+        }
+
+        upload(&cas, blobs.entries).await?;
+        metadata.output_upload_completed_timestamp = Some(SystemTime::now().into());
+        metadata.worker_completed_timestamp = Some(SystemTime::now().into());
+        result.execution_metadata = Some(metadata);
+
+        match exit_status {
+            Some(_) => Ok(result),
+            None => Err(Failure {
+                status: RpcStatus {
+                    code: Code::DeadlineExceeded as i32,
+                    message: format!("action timed out after {}s", timeout.as_secs_f64()),
+                    details: Vec::new(),
+                },
+                result: Box::new(result),
+            }),
+        }
+    }
+}
+
+/// Writes the blobs the CAS is missing
+async fn upload(cas: &StoreKind, blobs: Vec<(DigestInfo, Bytes)>) -> Result<(), Error> {
+    let digests: Vec<_> = blobs.iter().map(|(digest, _)| digest.clone()).collect();
+    let missing: Vec<_> = blobs
+        .into_iter()
+        .zip(cas.contains_keys(&digests).await)
+        .filter(|(_, present)| !present)
+        .map(|(blob, _)| blob)
+        .collect();
+
+    for result in cas.put_many(missing).await {
+        result?;
+    }
+
+    Ok(())
+}
+
+/// Runs the actions of the server at `endpoint`, until interrupted
+pub async fn run(config: WorkerConfig, tracing_config: &TracingConfig) -> eyre::Result<()> {
+    let _tracing = tracing::init(tracing_config)?;
+
+    let channel = Channel::from_shared(config.server_endpoint.clone())
+        .wrap_err("Invalid endpoint")?
+        .connect_lazy();
+
+    tokio::fs::create_dir_all(&config.work_dir)
+        .await
+        .wrap_err_with(|| format!("Failed to create {}", config.work_dir.display()))?;
+
+    let cache_path = config.work_dir.join("cache");
+    let file_cache = FileCache::open(&cache_path, config.cache_max_bytes)
+        .wrap_err_with(|| format!("Failed to open the file cache in {}", cache_path.display()))?;
+
+    let worker = Worker {
+        client: WorkerClient::new(channel),
+        authorization: format!("Bearer {}", config.worker_token)
+            .parse()
+            .wrap_err("Invalid worker token")?,
+        worker_id: config
+            .worker_id
+            .unwrap_or_else(|| format!("worker-{}", Uuid::new_v4())),
+        platform: Platform {
+            properties: config
+                .platform
+                .into_iter()
+                .map(|property| Property {
+                    name: property.name,
+                    value: property.value,
+                })
+                .collect(),
+        },
+        endpoint: config.server_endpoint,
+        work_dir: config.work_dir,
+        max_action_timeout: Duration::from_secs(config.max_action_timeout_seconds),
+        cas_stores: Arc::default(),
+        file_cache: Arc::new(file_cache),
+    };
+
+    ::tracing::info!(worker_id = %worker.worker_id, "polling for actions");
+
+    let slots = (0..config.concurrency.max(1)).map(|_| tokio::spawn(worker.clone().run()));
+
+    // actions still running are killed, their leases running out has them run again elsewhere
+    tokio::select! {
+        _ = futures::future::join_all(slots) => {}
+        _ = tokio::signal::ctrl_c() => ::tracing::info!("interrupted, stopping"),
+    }
+
+    Ok(())
+}
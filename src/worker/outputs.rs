@@ -0,0 +1,164 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use prost::Message;
+
+use crate::{
+    domain::DigestInfo,
+    errors::Error,
+    protos::build::bazel::remote::execution::v2::{
+        ActionResult, Command, Directory, DirectoryNode, FileNode, OutputDirectory, OutputFile,
+        OutputSymlink, SymlinkNode, Tree,
+    },
+};
+
+/// Output paths of `command`, relative to its working directory
+pub fn output_paths(command: &Command) -> Vec<&String> {
+    if command.output_paths.is_empty() {
+        command
+            .output_files
+            .iter()
+            .chain(&command.output_directories)
+            .collect()
+    } else {
+        command.output_paths.iter().collect()
+    }
+}
+
+/// Creates the parent directories of the outputs of `command`, which actions expect to exist
+pub fn create_output_parents(command: &Command, working_directory: &Path) -> Result<(), Error> {
+    for path in output_paths(command) {
+        if let Some(parent) = working_directory.join(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+/// Blobs of the outputs of an action, to upload to the CAS
+#[derive(Default)]
+pub struct Blobs {
+    seen: HashSet<DigestInfo>,
+    pub entries: Vec<(DigestInfo, Bytes)>,
+}
+
+impl Blobs {
+    pub fn add(&mut self, bytes: Bytes) -> DigestInfo {
+        let digest = DigestInfo::compute(&bytes);
+        if self.seen.insert(digest.clone()) {
+            self.entries.push((digest.clone(), bytes));
+        }
+
+        digest
+    }
+
+    fn add_file(&mut self, path: &Path) -> Result<DigestInfo, Error> {
+        Ok(self.add(Bytes::from(fs::read(path)?)))
+    }
+
+    /// Adds the files of the directory at `path`, returning it along with every directory below
+    /// it
+    fn add_directory(&mut self, path: &Path) -> Result<(Directory, Vec<Directory>), Error> {
+        let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        // the REAPI asks for the nodes of a `Directory` to be sorted by name
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut directory = Directory::default();
+        let mut descendants = Vec::new();
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = fs::symlink_metadata(entry.path())?;
+
+            if metadata.file_type().is_symlink() {
+                directory.symlinks.push(SymlinkNode {
+                    name,
+                    target: fs::read_link(entry.path())?.to_string_lossy().into_owned(),
+                    ..Default::default()
+                });
+            } else if metadata.is_dir() {
+                let (child, child_descendants) = self.add_directory(&entry.path())?;
+                directory.directories.push(DirectoryNode {
+                    name,
+                    digest: Some(DigestInfo::compute(&child.encode_to_vec()).into()),
+                });
+                descendants.push(child);
+                descendants.extend(child_descendants);
+            } else {
+                directory.files.push(FileNode {
+                    name,
+                    digest: Some(self.add_file(&entry.path())?.into()),
+                    is_executable: is_executable(&metadata),
+                    ..Default::default()
+                });
+            }
+        }
+
+        Ok((directory, descendants))
+    }
+}
+
+/// Adds the outputs `command` left in `working_directory` to `result`, along with their blobs.
+/// Outputs the action did not create are left out
+pub fn collect(
+    command: &Command,
+    working_directory: &Path,
+    result: &mut ActionResult,
+    blobs: &mut Blobs,
+) -> Result<(), Error> {
+    for output_path in output_paths(command) {
+        let path: PathBuf = working_directory.join(output_path);
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        if metadata.file_type().is_symlink() {
+            let symlink = OutputSymlink {
+                path: output_path.clone(),
+                target: fs::read_link(&path)?.to_string_lossy().into_owned(),
+                ..Default::default()
+            };
+
+            // older clients tell file and directory symlinks apart by the outputs they declared
+            if !command.output_paths.is_empty() {
+                result.output_symlinks.push(symlink);
+            } else if command.output_files.contains(output_path) {
+                result.output_file_symlinks.push(symlink);
+            } else {
+                result.output_directory_symlinks.push(symlink);
+            }
+        } else if metadata.is_dir() {
+            let (root, children) = blobs.add_directory(&path)?;
+            let tree = Tree {
+                root: Some(root),
+                children,
+            };
+
+            result.output_directories.push(OutputDirectory {
+                path: output_path.clone(),
+                tree_digest: Some(blobs.add(Bytes::from(tree.encode_to_vec())).into()),
+            });
+        } else {
+            result.output_files.push(OutputFile {
+                path: output_path.clone(),
+                digest: Some(blobs.add_file(&path)?.into()),
+                is_executable: is_executable(&metadata),
+                ..Default::default()
+            });
+        }
+    }
+
+    Ok(())
+}